@@ -0,0 +1,417 @@
+//! Sign-in of users whose domain is federated with an on-premises ADFS.
+//!
+//! Office 365 cannot check the password of these users, so `auth::login` asks `GetUserRealm.srf`
+//! where the user signs in, gets a SAML assertion for the password from the ADFS `usernamemixed`
+//! endpoint and exchanges the assertion at `extSTS.srf` for the usual security token.
+extern crate futures;
+extern crate serde_xml_rs;
+extern crate xml;
+
+use std::time::{Duration, SystemTime};
+
+use hyper::Method;
+
+use self::futures::{future, Future};
+use self::serde_xml_rs::from_reader;
+use self::xml::common::{Position, TextPosition};
+use self::xml::name::OwnedName;
+use self::xml::reader::{EventReader, XmlEvent};
+
+#[allow(unused_imports)]
+use super::*;
+
+use auth::{fill_xml_template, host, parse_soap_fault, security_token_from_response, LoginConfig};
+use client::{block_on, Client, SharePointFuture};
+use data::status_error;
+use error::{Error, Result, SoapFault};
+use oauth::form_urlencoded;
+use timestamp::utc_timestamp;
+use transport::{HeaderItem, Timeouts, TransportRequest, TransportResponse};
+
+/// Issuer Office 365 accepts assertions for, unless the realm names another one.
+static MICROSOFT_ONLINE_ISSUER: &'static str = "urn:federation:MicrosoftOnline";
+
+/// WS-Trust namespaces of `RequestedSecurityToken`, of the ADFS `trust/2005` and `trust/13` endpoints.
+static WS_TRUST_NAMESPACES: [&'static str; 2] = [
+    "http://schemas.xmlsoap.org/ws/2005/02/trust",
+    "http://docs.oasis-open.org/ws-sx/ws-trust/200512",
+];
+
+/// How long the ADFS may accept the timestamp of the password request.
+const TIMESTAMP_LIFETIME_SECONDS: u64 = 600;
+
+static ADFS_TOKEN_BODY_PAR: &'static str = r##"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"
+      xmlns:a="http://www.w3.org/2005/08/addressing"
+      xmlns:u="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd">
+  <s:Header>
+    <a:Action s:mustUnderstand="1">http://schemas.xmlsoap.org/ws/2005/02/trust/RST/Issue</a:Action>
+    <a:ReplyTo>
+      <a:Address>http://www.w3.org/2005/08/addressing/anonymous</a:Address>
+    </a:ReplyTo>
+    <a:To s:mustUnderstand="1">{sts_auth_url}</a:To>
+    <o:Security s:mustUnderstand="1"
+       xmlns:o="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd">
+      <u:Timestamp u:Id="_0">
+        <u:Created>{created}</u:Created>
+        <u:Expires>{expires}</u:Expires>
+      </u:Timestamp>
+      <o:UsernameToken u:Id="user">
+        <o:Username>{user_name}</o:Username>
+        <o:Password>{password}</o:Password>
+      </o:UsernameToken>
+    </o:Security>
+  </s:Header>
+  <s:Body>
+    <t:RequestSecurityToken xmlns:t="http://schemas.xmlsoap.org/ws/2005/02/trust">
+      <wsp:AppliesTo xmlns:wsp="http://schemas.xmlsoap.org/ws/2004/09/policy">
+        <a:EndpointReference>
+          <a:Address>{issuer}</a:Address>
+        </a:EndpointReference>
+      </wsp:AppliesTo>
+      <t:KeyType>http://schemas.xmlsoap.org/ws/2005/05/identity/NoProofKey</t:KeyType>
+      <t:RequestType>http://schemas.xmlsoap.org/ws/2005/02/trust/Issue</t:RequestType>
+    </t:RequestSecurityToken>
+  </s:Body>
+</s:Envelope>
+        "##;
+
+static ASSERTION_TOKEN_BODY_PAR: &'static str = r##"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"
+      xmlns:a="http://www.w3.org/2005/08/addressing"
+      xmlns:u="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd">
+  <s:Header>
+    <a:Action s:mustUnderstand="1">http://schemas.xmlsoap.org/ws/2005/02/trust/RST/Issue</a:Action>
+    <a:ReplyTo>
+      <a:Address>http://www.w3.org/2005/08/addressing/anonymous</a:Address>
+    </a:ReplyTo>
+    <a:To s:mustUnderstand="1">{security_token_url}</a:To>
+    <o:Security s:mustUnderstand="1"
+       xmlns:o="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd">{assertion}</o:Security>
+  </s:Header>
+  <s:Body>
+    <t:RequestSecurityToken xmlns:t="http://schemas.xmlsoap.org/ws/2005/02/trust">
+      <wsp:AppliesTo xmlns:wsp="http://schemas.xmlsoap.org/ws/2004/09/policy">
+        <a:EndpointReference>
+          <a:Address>{host}</a:Address>
+        </a:EndpointReference>
+      </wsp:AppliesTo>
+      <t:KeyType>http://schemas.xmlsoap.org/ws/2005/05/identity/NoProofKey</t:KeyType>
+      <t:RequestType>http://schemas.xmlsoap.org/ws/2005/02/trust/Issue</t:RequestType>
+      <t:TokenType>urn:oasis:names:tc:SAML:1.0:assertion</t:TokenType>
+    </t:RequestSecurityToken>
+  </s:Body>
+</s:Envelope>
+        "##;
+
+/// Where a user signs in, as answered by `GetUserRealm.srf`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Default)]
+pub struct UserRealm {
+    /// `Managed` when Azure AD checks the password itself, `Federated` when an ADFS does.
+    #[serde(rename = "NameSpaceType", default)]
+    pub name_space_type: String,
+    /// ADFS endpoint checking the password of federated users.
+    #[serde(rename = "STSAuthURL", default)]
+    pub sts_auth_url: String,
+    /// Audience of the assertion, `urn:federation:MicrosoftOnline` for Office 365 worldwide.
+    #[serde(rename = "CloudInstanceIssuerUri", default)]
+    pub cloud_instance_issuer_uri: String,
+}
+
+impl UserRealm {
+    /// Whether the password has to be sent to the ADFS at `sts_auth_url`.
+    pub fn is_federated(&self) -> bool {
+        self.name_space_type == "Federated" && !self.sts_auth_url.is_empty()
+    }
+}
+
+fn parse_user_realm(res: TransportResponse) -> Result<UserRealm> {
+    if !res.status.is_success() {
+        return Err(status_error(res.status, res.body));
+    }
+    from_reader(res.body.as_bytes()).map_err(|e| Error::Xml(e.to_string()))
+}
+
+/// The `saml:Assertion` inside `RequestedSecurityToken`, kept as sent since it is signed.
+fn assertion_from_response(res: TransportResponse) -> Result<String> {
+    if let Some(fault) = parse_soap_fault(&res.body) {
+        return Err(Error::SoapFault(fault));
+    }
+    if !res.status.is_success() {
+        return Err(status_error(res.status, res.body));
+    }
+    match requested_security_token(&res.body)? {
+        Some(assertion) if !assertion.is_empty() => Ok(assertion.to_string()),
+        _ => Err(Error::SoapFault(SoapFault {
+            reason: "no assertion in the ADFS response".to_string(),
+            ..Default::default()
+        })),
+    }
+}
+
+fn is_requested_security_token(name: &OwnedName) -> bool {
+    name.local_name == "RequestedSecurityToken"
+        && name.namespace.as_ref().map_or(false, |namespace| WS_TRUST_NAMESPACES.contains(&namespace.as_str()))
+}
+
+/// The byte offset in `text` of the row and character column `position`.
+fn byte_offset(text: &str, position: TextPosition) -> Option<usize> {
+    let line_start = match position.row {
+        0 => 0,
+        row => text.match_indices('\n').nth(row as usize - 1)?.0 + 1,
+    };
+    text[line_start..]
+        .char_indices()
+        .map(|(i, _)| line_start + i)
+        .chain(Some(text.len()))
+        .nth(position.column as usize)
+}
+
+/// The elements inside `RequestedSecurityToken`, as they are in `body`.
+fn requested_security_token(body: &str) -> Result<Option<&str>> {
+    let mut reader = EventReader::new(body.as_bytes());
+    // depth below `RequestedSecurityToken` and where its first child starts, once inside
+    let mut inside: Option<(usize, Option<usize>)> = None;
+    loop {
+        let event = reader.next().map_err(|e| Error::Xml(e.to_string()))?;
+        match (event, inside.as_mut()) {
+            (XmlEvent::StartElement { ref name, .. }, None) if is_requested_security_token(name) => inside = Some((0, None)),
+            (XmlEvent::StartElement { .. }, Some(&mut (ref mut depth, ref mut start))) => {
+                if start.is_none() {
+                    *start = byte_offset(body, reader.position());
+                }
+                *depth += 1;
+            }
+            (XmlEvent::EndElement { .. }, Some(&mut (0, start))) => {
+                let end = byte_offset(body, reader.position());
+                return Ok(match (start, end) {
+                    (Some(start), Some(end)) if start <= end => Some(body[start..end].trim()),
+                    _ => None,
+                });
+            }
+            (XmlEvent::EndElement { .. }, Some(&mut (ref mut depth, _))) => *depth -= 1,
+            (XmlEvent::EndDocument, _) => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+fn soap_request(url: String, body: String) -> TransportRequest {
+    TransportRequest {
+        method: Method::Post,
+        url: url,
+        headers: vec![HeaderItem {
+            name: "Content-Type".to_string(),
+            value: "application/soap+xml; charset=utf-8".to_string(),
+        }],
+        body: body,
+        timeouts: Timeouts::default(),
+    }
+}
+
+/// Asks Office 365 whether `user_name` signs in with Azure AD or with an ADFS.
+pub fn get_user_realm(user_name: String) -> Result<UserRealm> {
+    block_on(|client| client.get_user_realm(user_name))
+}
+
+impl Client {
+    /// Asynchronous `adfs::get_user_realm`.
+    pub fn get_user_realm(&self, user_name: String) -> SharePointFuture<UserRealm> {
+        self.request_user_realm(&LoginConfig::default(), user_name)
+    }
+
+    pub(crate) fn request_user_realm(&self, config: &LoginConfig, user_name: String) -> SharePointFuture<UserRealm> {
+        let request = TransportRequest {
+            method: Method::Post,
+            url: config.user_realm_url.to_owned(),
+            headers: vec![HeaderItem {
+                name: "Content-Type".to_string(),
+                value: "application/x-www-form-urlencoded".to_string(),
+            }],
+            body: form_urlencoded(&[("login", &user_name), ("xml", "1")]),
+            timeouts: Timeouts::default(),
+        };
+        Box::new(self.send(request, true).and_then(parse_user_realm))
+    }
+
+    /// Gets a SAML assertion for the password from the ADFS of `realm` and exchanges it
+    /// for a security token at the STS of `config`.
+    pub(crate) fn request_federated_security_token(
+        &self,
+        config: &LoginConfig,
+        realm: UserRealm,
+        site: Site,
+        user_name: String,
+        password: String,
+    ) -> SharePointFuture<String> {
+        let host = match host(site) {
+            Ok(host) => host,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let issuer = if realm.cloud_instance_issuer_uri.is_empty() {
+            MICROSOFT_ONLINE_ISSUER.to_string()
+        } else {
+            realm.cloud_instance_issuer_uri
+        };
+        let now = SystemTime::now();
+        let body = fill_xml_template(
+            ADFS_TOKEN_BODY_PAR,
+            &[
+                ("sts_auth_url", &realm.sts_auth_url),
+                ("created", &utc_timestamp(now)),
+                ("expires", &utc_timestamp(now + Duration::from_secs(TIMESTAMP_LIFETIME_SECONDS))),
+                ("user_name", &user_name),
+                ("password", &password),
+                ("issuer", &issuer),
+            ],
+        );
+        let client = self.clone();
+        let security_token_url = config.security_token_url.to_owned();
+        Box::new(
+            self.send(soap_request(realm.sts_auth_url, body), true)
+                .and_then(assertion_from_response)
+                .and_then(move |assertion| {
+                    // the assertion is signed XML and goes in as it is, only the other values are escaped
+                    let mut halves = ASSERTION_TOKEN_BODY_PAR.splitn(2, "{assertion}");
+                    let values = [("security_token_url", security_token_url.as_str()), ("host", host.as_str())];
+                    let body = format!(
+                        "{}{}{}",
+                        fill_xml_template(halves.next().unwrap_or(""), &values),
+                        assertion,
+                        fill_xml_template(halves.next().unwrap_or(""), &values)
+                    );
+                    client
+                        .send(soap_request(security_token_url, body), true)
+                        .then(security_token_from_response)
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use auth::tests::{CONTEXT_INFO_RESPONSE, SECURITY_TOKEN_RESPONSE};
+    use client::SharePointClient;
+    use error::LoginErrorKind;
+    use hyper::StatusCode;
+    use transport::tests::FakeTransport;
+
+    pub static MANAGED_REALM_RESPONSE: &'static str = r##"<RealmInfo Success="true"><State>4</State><UserState>1</UserState><Login>user@company.onmicrosoft.com</Login><NameSpaceType>Managed</NameSpaceType><DomainName>company.onmicrosoft.com</DomainName><IsFederatedNS>false</IsFederatedNS><FederationBrandName>Company</FederationBrandName><CloudInstanceName>microsoftonline.com</CloudInstanceName></RealmInfo>"##;
+
+    static FEDERATED_REALM_RESPONSE: &'static str = r##"<RealmInfo Success="true"><State>3</State><UserState>2</UserState><Login>user@company.com</Login><NameSpaceType>Federated</NameSpaceType><DomainName>company.com</DomainName><FederationGlobalVersion>-1</FederationGlobalVersion><AuthURL>https://adfs.company.com/adfs/ls/?username=user%40company.com&amp;wa=wsignin1.0</AuthURL><IsFederatedNS>true</IsFederatedNS><STSAuthURL>https://adfs.company.com/adfs/services/trust/2005/usernamemixed</STSAuthURL><FederationTier>0</FederationTier><FederationBrandName>Company</FederationBrandName><AllowFedUsersWLIDSignIn>false</AllowFedUsersWLIDSignIn><MEXURL>https://adfs.company.com/adfs/services/trust/mex</MEXURL><PreferredProtocol>1</PreferredProtocol><EDUDomainFlags>0</EDUDomainFlags><CloudInstanceName>microsoftonline.com</CloudInstanceName><CloudInstanceIssuerUri>urn:federation:MicrosoftOnline</CloudInstanceIssuerUri></RealmInfo>"##;
+
+    static ADFS_RESPONSE: &'static str = r##"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:a="http://www.w3.org/2005/08/addressing"><s:Header><a:Action s:mustUnderstand="1">http://schemas.xmlsoap.org/ws/2005/02/trust/RSTR/Issue</a:Action></s:Header><s:Body><t:RequestSecurityTokenResponse xmlns:t="http://schemas.xmlsoap.org/ws/2005/02/trust"><t:RequestedSecurityToken><saml:Assertion MajorVersion="1" MinorVersion="1" AssertionID="_adfs" Issuer="http://adfs.company.com/adfs/services/trust" xmlns:saml="urn:oasis:names:tc:SAML:1.0:assertion"><saml:AttributeStatement><saml:Subject><saml:NameIdentifier>user&amp;co</saml:NameIdentifier></saml:Subject></saml:AttributeStatement></saml:Assertion></t:RequestedSecurityToken></t:RequestSecurityTokenResponse></s:Body></s:Envelope>"##;
+
+    static ADFS_FAULT: &'static str = r##"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:a="http://www.w3.org/2005/08/addressing"><s:Body><s:Fault><s:Code><s:Value>s:Sender</s:Value><s:Subcode><s:Value xmlns:a="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd">a:FailedAuthentication</s:Value></s:Subcode></s:Code><s:Reason><s:Text xml:lang="en-US">ID3242: The security token could not be authenticated or authorized.</s:Text></s:Reason></s:Fault></s:Body></s:Envelope>"##;
+
+    #[test]
+    fn parses_user_realm() {
+        let realm: UserRealm = from_reader(FEDERATED_REALM_RESPONSE.as_bytes()).unwrap();
+        assert!(realm.is_federated());
+        assert_eq!(realm.sts_auth_url, "https://adfs.company.com/adfs/services/trust/2005/usernamemixed");
+        assert_eq!(realm.cloud_instance_issuer_uri, "urn:federation:MicrosoftOnline");
+
+        let realm: UserRealm = from_reader(MANAGED_REALM_RESPONSE.as_bytes()).unwrap();
+        assert!(!realm.is_federated());
+    }
+
+    #[test]
+    fn assertion_is_found_by_namespace_and_kept_as_sent() {
+        let assertion = "<saml:Assertion xmlns:saml=\"urn:oasis:names:tc:SAML:1.0:assertion\" AssertionID=\"_adfs\">\r\n\
+            <saml:Subject><saml:NameIdentifier>Zoë &amp; co</saml:NameIdentifier></saml:Subject>\r\n\
+            </saml:Assertion>";
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\r\n\
+            <env:Envelope xmlns:env=\"http://www.w3.org/2003/05/soap-envelope\">\r\n\
+              <env:Body>\r\n\
+                <other:RequestedSecurityToken xmlns:other=\"urn:not-ws-trust\">ignored</other:RequestedSecurityToken>\r\n\
+                <trust:RequestSecurityTokenResponseCollection xmlns:trust=\"http://docs.oasis-open.org/ws-sx/ws-trust/200512\">\r\n\
+                  <trust:RequestSecurityTokenResponse><trust:Lifetime>Žluťoučký kůň</trust:Lifetime>\r\n\
+                    <trust:RequestedSecurityToken>\r\n      {}\r\n    </trust:RequestedSecurityToken>\r\n\
+                  </trust:RequestSecurityTokenResponse>\r\n\
+                </trust:RequestSecurityTokenResponseCollection>\r\n\
+              </env:Body>\r\n\
+            </env:Envelope>",
+            assertion
+        );
+        let response = TransportResponse {
+            status: StatusCode::Ok,
+            headers: Vec::new(),
+            cookies: Vec::new(),
+            body: body,
+        };
+        assert_eq!(assertion_from_response(response).unwrap(), assertion);
+    }
+
+    #[test]
+    fn federated_login_forwards_the_adfs_assertion() {
+        let transport = FakeTransport::default();
+        transport
+            .respond(StatusCode::Ok, FEDERATED_REALM_RESPONSE)
+            .respond(StatusCode::Ok, ADFS_RESPONSE)
+            .respond(StatusCode::Ok, SECURITY_TOKEN_RESPONSE)
+            .respond_with_cookies(StatusCode::Found, "", vec!["rtFa=rt-fa-cookie", "FedAuth=fed-auth-cookie"])
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        let login = client
+            .login("https://company.sharepoint.com/sites/test/".to_string(), "user@company.com".to_string(), "p<ss".to_string())
+            .unwrap();
+        assert_eq!(login.access_token().unwrap().fed_auth, Some("fed-auth-cookie".to_string()));
+
+        let realm = transport.request(0);
+        assert_eq!(realm.url, "https://login.microsoftonline.com/GetUserRealm.srf");
+        assert_eq!(realm.body, "login=user%40company.com&xml=1");
+
+        let adfs = transport.request(1);
+        assert_eq!(adfs.url, "https://adfs.company.com/adfs/services/trust/2005/usernamemixed");
+        assert_eq!(adfs.header("Content-Type"), Some("application/soap+xml; charset=utf-8"));
+        assert!(adfs.body.contains("<o:Username>user@company.com</o:Username>"));
+        assert!(adfs.body.contains("<o:Password>p&lt;ss</o:Password>"));
+        assert!(adfs.body.contains("<a:Address>urn:federation:MicrosoftOnline</a:Address>"));
+
+        let sts = transport.request(2);
+        assert_eq!(sts.url, "https://login.microsoftonline.com/extSTS.srf");
+        assert!(sts.body.contains("<saml:Assertion MajorVersion=\"1\""));
+        assert!(sts.body.contains("<saml:NameIdentifier>user&amp;co</saml:NameIdentifier>"));
+        assert!(sts.body.contains("<a:Address>company.sharepoint.com</a:Address>"));
+        assert!(!sts.body.contains("<o:Password>"));
+
+        assert_eq!(transport.request(3).body, "t=security-token");
+    }
+
+    #[test]
+    fn reports_adfs_fault() {
+        let transport = FakeTransport::default();
+        transport
+            .respond(StatusCode::Ok, FEDERATED_REALM_RESPONSE)
+            .respond(StatusCode::InternalServerError, ADFS_FAULT);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        match client.login("https://company.sharepoint.com/sites/test/".to_string(), "user@company.com".to_string(), "wrong".to_string()) {
+            Err(Error::SoapFault(fault)) => {
+                assert_eq!(fault.code, "a:FailedAuthentication");
+                assert_eq!(fault.kind(), LoginErrorKind::InvalidCredentials);
+            }
+            res => panic!("expected a SOAP fault, got {:?}", res.map(|_| ())),
+        }
+        assert_eq!(transport.requests.borrow().len(), 2);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[test]
+    fn federated_login_against_mock_server() {
+        use auth::login_with_config;
+        use mock::MockServer;
+
+        let server = MockServer::start();
+        server.create_list("Tasks");
+        let login = login_with_config(
+            server.site_url(),
+            MockServer::FEDERATED_USER_NAME.to_string(),
+            MockServer::PASSWORD.to_string(),
+            server.login_config(),
+        ).unwrap();
+        assert!(login.access_token().unwrap().fed_auth.is_some());
+
+        let realm = block_on(|client| client.request_user_realm(&server.login_config(), MockServer::USER_NAME.to_string())).unwrap();
+        assert_eq!(realm.name_space_type, "Managed");
+    }
+}
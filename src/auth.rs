@@ -1,15 +1,78 @@
+extern crate serde;
 extern crate serde_xml_rs;
+extern crate futures;
 
 #[allow(unused_imports)]
 use super::*;
 
+use client::{block_on, Client, SharePointClient, SharePointFuture};
+use credentials::{CredentialProvider, UserCredentials};
 use data::*;
+use error::{Error, ODataErrorKind, Result, SoapFault};
+use on_premises::WindowsCredentials;
+use transport::TransportResponse;
+
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use self::futures::{future, Future};
 
 use hyper::Method;
+use hyper::header::HttpDate;
 
-static GET_SECURITY_TOKEN_URL: &'static str = "https://login.microsoftonline.com/extSTS.srf";
-static GET_ACCESS_TOKEN_URL: &'static str = "https://{host}/_forms/default.aspx?wa=wsignin1.0";
-static GET_REQUEST_DIGEST_URL: &'static str = "https://{host}/{path}/_api/contextinfo";
+use self::serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Login endpoints of one Office 365 cloud.
+struct CloudEndpoints {
+    user_realm_url: &'static str,
+    security_token_url: &'static str,
+    token_url: &'static str,
+    acs_token_url: &'static str,
+}
+
+static OFFICE365: CloudEndpoints = CloudEndpoints {
+    user_realm_url: "https://login.microsoftonline.com/GetUserRealm.srf",
+    security_token_url: "https://login.microsoftonline.com/extSTS.srf",
+    token_url: "https://login.microsoftonline.com/{tenant}/oauth2/v2.0/token",
+    acs_token_url: "https://accounts.accesscontrol.windows.net/{realm}/tokens/OAuth/2",
+};
+static US_GOVERNMENT: CloudEndpoints = CloudEndpoints {
+    user_realm_url: "https://login.microsoftonline.us/GetUserRealm.srf",
+    security_token_url: "https://login.microsoftonline.us/extSTS.srf",
+    token_url: "https://login.microsoftonline.us/{tenant}/oauth2/v2.0/token",
+    acs_token_url: "https://login.microsoftonline.us/{realm}/tokens/OAuth/2",
+};
+static CHINA: CloudEndpoints = CloudEndpoints {
+    user_realm_url: "https://login.chinacloudapi.cn/GetUserRealm.srf",
+    security_token_url: "https://login.chinacloudapi.cn/extSTS.srf",
+    token_url: "https://login.chinacloudapi.cn/{tenant}/oauth2/v2.0/token",
+    acs_token_url: "https://accounts.accesscontrol.chinacloudapi.cn/{realm}/tokens/OAuth/2",
+};
+static GERMANY: CloudEndpoints = CloudEndpoints {
+    user_realm_url: "https://login.microsoftonline.de/GetUserRealm.srf",
+    security_token_url: "https://login.microsoftonline.de/extSTS.srf",
+    token_url: "https://login.microsoftonline.de/{tenant}/oauth2/v2.0/token",
+    acs_token_url: "https://login.microsoftonline.de/{realm}/tokens/OAuth/2",
+};
+/// Whether `host` signs users in to one of the clouds, where SharePoint Online redirects
+/// requests whose session has expired.
+pub(crate) fn is_login_host(host: &str) -> bool {
+    [&OFFICE365, &US_GOVERNMENT, &CHINA, &GERMANY].iter().any(|cloud| {
+        cloud
+            .user_realm_url
+            .parse::<hyper::Uri>()
+            .ok()
+            .and_then(|url| url.host().map(|h| h.eq_ignore_ascii_case(host)))
+            .unwrap_or(false)
+    })
+}
+
+static GET_ACCESS_TOKEN_PATH: &'static str = "/_forms/default.aspx?wa=wsignin1.0";
+static GET_REQUEST_DIGEST_URL: &'static str = "{origin}{path}/_api/contextinfo";
+
+/// Request digests and bearer tokens are fetched again this many seconds before they expire.
+const REFRESH_MARGIN_SECONDS: u64 = 60;
 
 static GET_SECURITY_TOKEN_BODY_PAR: &'static str = r##"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"
       xmlns:a="http://www.w3.org/2005/08/addressing"
@@ -19,7 +82,7 @@ static GET_SECURITY_TOKEN_BODY_PAR: &'static str = r##"<s:Envelope xmlns:s="http
     <a:ReplyTo>
       <a:Address>http://www.w3.org/2005/08/addressing/anonymous</a:Address>
     </a:ReplyTo>
-    <a:To s:mustUnderstand="1">https://login.microsoftonline.com/extSTS.srf</a:To>
+    <a:To s:mustUnderstand="1">{security_token_url}</a:To>
     <o:Security s:mustUnderstand="1"
        xmlns:o="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd">
       <o:UsernameToken>
@@ -44,25 +107,307 @@ static GET_SECURITY_TOKEN_BODY_PAR: &'static str = r##"<s:Envelope xmlns:s="http
         "##;
 
 
-#[derive(Clone)]
+/// Endpoints used by `login_with_config`, built like
+/// `LoginConfig::china().scheme("https")` or `LoginConfig::default().security_token_url(..)`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoginConfig {
+    /// Tells whether a user signs in with Azure AD or with the ADFS of a federated domain,
+    /// `https://login.microsoftonline.com/GetUserRealm.srf` for Office 365.
+    pub user_realm_url: String,
+    /// Security token service, `https://login.microsoftonline.com/extSTS.srf` for Office 365.
+    pub security_token_url: String,
+    /// Sign-in form on the SharePoint host exchanging the security token for cookies.
+    pub sign_in_path: String,
+    /// Scheme of the sign-in form and `contextinfo` requests; the site's own scheme if `None`.
+    pub scheme: Option<String>,
+    /// Azure AD token endpoint for app-only logins, `{tenant}` being replaced by the tenant.
+    pub token_url: String,
+    /// Access Control Service token endpoint for SharePoint add-ins, `{realm}` being replaced
+    /// by the realm the site announces.
+    pub acs_token_url: String,
+}
+
+impl Default for LoginConfig {
+    fn default() -> LoginConfig {
+        LoginConfig::with_endpoints(&OFFICE365)
+    }
+}
+
+impl LoginConfig {
+    fn with_endpoints(cloud: &CloudEndpoints) -> LoginConfig {
+        LoginConfig {
+            user_realm_url: cloud.user_realm_url.to_string(),
+            security_token_url: cloud.security_token_url.to_string(),
+            sign_in_path: GET_ACCESS_TOKEN_PATH.to_string(),
+            scheme: None,
+            token_url: cloud.token_url.to_string(),
+            acs_token_url: cloud.acs_token_url.to_string(),
+        }
+    }
+
+    /// Office 365 worldwide, the default.
+    pub fn office365() -> LoginConfig {
+        LoginConfig::default()
+    }
+
+    /// Office 365 US Government GCC High and DoD.
+    pub fn us_government() -> LoginConfig {
+        LoginConfig::with_endpoints(&US_GOVERNMENT)
+    }
+
+    /// Office 365 operated by 21Vianet in China.
+    pub fn china() -> LoginConfig {
+        LoginConfig::with_endpoints(&CHINA)
+    }
+
+    /// Office 365 Germany.
+    pub fn germany() -> LoginConfig {
+        LoginConfig::with_endpoints(&GERMANY)
+    }
+
+    pub fn user_realm_url<S: Into<String>>(mut self, url: S) -> LoginConfig {
+        self.user_realm_url = url.into();
+        self
+    }
+
+    pub fn security_token_url<S: Into<String>>(mut self, url: S) -> LoginConfig {
+        self.security_token_url = url.into();
+        self
+    }
+
+    pub fn sign_in_path<S: Into<String>>(mut self, path: S) -> LoginConfig {
+        self.sign_in_path = path.into();
+        self
+    }
+
+    pub fn scheme<S: Into<String>>(mut self, scheme: S) -> LoginConfig {
+        self.scheme = Some(scheme.into());
+        self
+    }
+
+    pub fn token_url<S: Into<String>>(mut self, url: S) -> LoginConfig {
+        self.token_url = url.into();
+        self
+    }
+
+    pub fn acs_token_url<S: Into<String>>(mut self, url: S) -> LoginConfig {
+        self.acs_token_url = url.into();
+        self
+    }
+
+    /// Scheme and authority of the site, e.g. `https://company.sharepoint.com`, and its path
+    /// without trailing slash, e.g. `/sites/test`.
+    pub(crate) fn origin_and_path(&self, site: Site) -> Result<(String, String)> {
+        let site_parsed: hyper::Uri = site.parent.parse().map_err(|_| Error::Uri(site.parent.to_owned()))?;
+        let scheme = self.scheme.as_ref().map(|s| s.as_str()).or(site_parsed.scheme());
+        match (scheme, site_parsed.authority()) {
+            (Some(scheme), Some(authority)) => Ok((format!("{}://{}", scheme, authority), site_path(site_parsed.path()).to_string())),
+            _ => Err(Error::Uri(site.parent.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AccessTokenCookies {
     pub rt_fa: Option<String>,
     pub fed_auth: Option<String>,
+    /// When the first of the cookies expires, if the sign-in response said so.
+    #[serde(default)]
+    pub valid_until: Option<SystemTime>,
 }
 
-#[derive(Clone)]
+/// OAuth2 access token sent as `Authorization: Bearer`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BearerToken {
+    pub access_token: String,
+    /// When the token expires, if the token endpoint said so.
+    pub valid_until: Option<SystemTime>,
+}
+
+/// How requests to SharePoint are authenticated.
+///
+/// Windows accounts are never serialized, as that would write out their password.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Authorization {
+    /// `rtFa` and `FedAuth` cookies of a user signed in with a password.
+    Cookies(AccessTokenCookies),
+    /// App-only access token from Azure AD.
+    Bearer(BearerToken),
+    /// Windows account of an on-premises farm, sent with the NTLM handshake.
+    #[serde(skip)]
+    Ntlm(WindowsCredentials),
+    /// Windows account of an on-premises farm, sent as `Authorization: Basic` over https only.
+    #[serde(skip)]
+    Basic(WindowsCredentials),
+}
+
+impl Authorization {
+    /// Whether the credential has expired or is about to; cookies do not always say when
+    /// they expire and Windows accounts do not expire.
+    pub fn is_expiring(&self) -> bool {
+        match *self {
+            Authorization::Cookies(ref cookies) => expiring(cookies.valid_until),
+            Authorization::Bearer(ref token) => expiring(token.valid_until),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct RequestDigest {
     pub content: String,
+    /// When SharePoint stops accepting the digest, if `/_api/contextinfo` said so.
+    pub valid_until: Option<SystemTime>,
+}
+
+impl RequestDigest {
+    /// Whether the digest has expired or is about to.
+    pub fn is_expiring(&self) -> bool {
+        expiring(self.valid_until)
+    }
 }
 
+fn expiring(valid_until: Option<SystemTime>) -> bool {
+    match valid_until {
+        Some(valid_until) => SystemTime::now() + Duration::from_secs(REFRESH_MARGIN_SECONDS) >= valid_until,
+        None => false,
+    }
+}
+
+/// What a `LoginContext` keeps to sign in again when the session expires.
+#[derive(Clone)]
+pub(crate) enum Credentials {
+    Password(Arc<dyn CredentialProvider>),
+    ClientSecret { tenant: String, client_id: String, client_secret: String },
+    AddIn { client_id: String, client_secret: String },
+    #[cfg(feature = "certificate")]
+    Certificate { tenant: String, client_id: String, certificate: ::certificate::Certificate },
+}
+
+#[derive(Clone)]
+struct Session {
+    authorization: Authorization,
+    request_digest: RequestDigest,
+}
+
+/// Authentication state for one site.
+///
+/// Clones share the session and the request digest, so a session renewed by one call is
+/// used by all of them.
+///
+/// A context can be serialized to be restored by a later run, see `cache::TokenCache`;
+/// the credentials kept to sign in again are left out and have to be given again with
+/// `with_credentials`.
 #[derive(Clone)]
 pub struct LoginContext {
-    pub access_token : AccessTokenCookies,
-    pub request_digest : RequestDigest,
     pub site : Site,
+    pub config : LoginConfig,
+    session : Arc<Mutex<Session>>,
+    credentials : Option<Credentials>,
+    user_name : Option<String>,
 }
 
-use self::serde_xml_rs::deserialize;
+impl LoginContext {
+    pub fn new(site: Site, authorization: Authorization, request_digest: RequestDigest, config: LoginConfig) -> LoginContext {
+        LoginContext {
+            site: site,
+            config: config,
+            session: Arc::new(Mutex::new(Session {
+                authorization: authorization,
+                request_digest: request_digest,
+            })),
+            credentials: None,
+            user_name: None,
+        }
+    }
+
+    /// Keeps `user_name` and `password` to sign in again once SharePoint no longer accepts the cookies.
+    pub fn with_credentials(mut self, user_name: String, password: String) -> LoginContext {
+        self.user_name = Some(user_name.clone());
+        self.with_credential_provider(UserCredentials::new(user_name, password))
+    }
+
+    /// The account given to `with_credentials`, kept with the context when it is cached.
+    pub(crate) fn user_name(&self) -> Option<&str> {
+        self.user_name.as_ref().map(|u| u.as_str())
+    }
+
+    /// Asks `provider` for the credentials every time SharePoint no longer accepts the cookies.
+    pub fn with_credential_provider<P: CredentialProvider + 'static>(self, provider: P) -> LoginContext {
+        self.with_renewal(Credentials::Password(Arc::new(provider)))
+    }
+
+    pub(crate) fn with_renewal(mut self, credentials: Credentials) -> LoginContext {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// The cookies or bearer token of the current session.
+    pub fn authorization(&self) -> Authorization {
+        self.session.lock().unwrap().authorization.clone()
+    }
+
+    /// The `rtFa` and `FedAuth` cookies of the current session, if it was started with a password.
+    pub fn access_token(&self) -> Option<AccessTokenCookies> {
+        match self.authorization() {
+            Authorization::Cookies(cookies) => Some(cookies),
+            _ => None,
+        }
+    }
+
+    /// The request digest last fetched for this context.
+    pub fn request_digest(&self) -> RequestDigest {
+        self.session.lock().unwrap().request_digest.clone()
+    }
+
+    pub(crate) fn set_request_digest(&self, digest: RequestDigest) {
+        self.session.lock().unwrap().request_digest = digest;
+    }
+
+    pub(crate) fn set_session(&self, authorization: Authorization, request_digest: RequestDigest) {
+        *self.session.lock().unwrap() = Session {
+            authorization: authorization,
+            request_digest: request_digest,
+        };
+    }
+}
+
+/// The serialized form of a `LoginContext`.
+#[derive(Serialize, Deserialize)]
+struct SavedLoginContext {
+    site: Site,
+    #[serde(default)]
+    config: LoginConfig,
+    #[serde(default)]
+    user_name: Option<String>,
+    authorization: Authorization,
+    request_digest: RequestDigest,
+}
+
+impl Serialize for LoginContext {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        let session = self.session.lock().unwrap().clone();
+        SavedLoginContext {
+            site: self.site.clone(),
+            config: self.config.clone(),
+            user_name: self.user_name.clone(),
+            authorization: session.authorization,
+            request_digest: session.request_digest,
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LoginContext {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<LoginContext, D::Error> {
+        let saved = SavedLoginContext::deserialize(deserializer)?;
+        let mut login = LoginContext::new(saved.site, saved.authorization, saved.request_digest, saved.config);
+        login.user_name = saved.user_name;
+        Ok(login)
+    }
+}
+
+use self::serde_xml_rs::from_reader;
 
 #[derive(Debug, Deserialize, Default)]
 struct Header {}
@@ -85,10 +430,78 @@ struct RequestSecurityTokenResponse {
     requested_security_token: RequestedSecurityToken,
 }
 
+#[derive(Debug, Deserialize, Default)]
+struct FaultValue {
+    #[serde(rename = "Value", default)]
+    value: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct FaultCode {
+    #[serde(rename = "Value", default)]
+    value: String,
+    #[serde(rename = "Subcode", default)]
+    subcode: FaultValue,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct FaultReason {
+    #[serde(rename = "Text", default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct InternalError {
+    #[serde(rename = "text", default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct PassportError {
+    #[serde(rename = "value", default)]
+    value: String,
+    #[serde(rename = "internalerror", default)]
+    internal_error: InternalError,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct FaultDetail {
+    #[serde(rename = "error", default)]
+    error: PassportError,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct Fault {
+    #[serde(rename = "Code", default)]
+    code: FaultCode,
+    #[serde(rename = "Reason", default)]
+    reason: FaultReason,
+    #[serde(rename = "Detail", default)]
+    detail: FaultDetail,
+}
+
+impl From<Fault> for SoapFault {
+    fn from(fault: Fault) -> SoapFault {
+        let code = if fault.code.subcode.value.is_empty() {
+            fault.code.value
+        } else {
+            fault.code.subcode.value
+        };
+        SoapFault {
+            code: code,
+            reason: fault.reason.text,
+            value: fault.detail.error.value,
+            text: fault.detail.error.internal_error.text,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Default)]
 struct Body {
     #[serde(rename = "RequestSecurityTokenResponse", default)]
     request_security_token_response: RequestSecurityTokenResponse,
+    #[serde(rename = "Fault", default)]
+    fault: Option<Fault>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -105,185 +518,851 @@ struct FormDigestValue {
     content: String,
 }
 
+#[derive(Debug, Deserialize, Default)]
+struct FormDigestTimeoutSeconds {
+    #[serde(rename = "$value", default)]
+    content: String,
+}
+
 #[derive(Debug, Deserialize, Default)]
 struct GetContextWebInformation {
     #[serde(rename = "FormDigestValue", default)]
     pub form_digest_value: FormDigestValue,
+    #[serde(rename = "FormDigestTimeoutSeconds", default)]
+    pub form_digest_timeout_seconds: FormDigestTimeoutSeconds,
 }
 
-fn parse_xml_envelope(body: String, _: Vec<HeaderItem>, _: Vec<String>) -> Option<Envelope> {
+impl GetContextWebInformation {
+    fn into_request_digest(self) -> RequestDigest {
+        let valid_until = match self.form_digest_timeout_seconds.content.trim().parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Some(SystemTime::now() + Duration::from_secs(seconds)),
+            _ => None,
+        };
+        RequestDigest {
+            content: self.form_digest_value.content,
+            valid_until: valid_until,
+        }
+    }
+}
+
+fn parse_xml_envelope(body: String, _: Vec<HeaderItem>, _: Vec<String>) -> Result<Envelope> {
     //println!("XML Parsing '{:?}'", body);
-    let v: Envelope = deserialize(body.as_bytes()).unwrap();
-    Some(v)
+    let v: Envelope = from_reader(body.as_bytes()).map_err(|e| Error::Xml(e.to_string()))?;
+    Ok(v)
 }
 
-pub fn login( site : String, user_name : String, password : String ) -> LoginContext {
-    let site_url: &str = &site;
-    let site_parsed: hyper::Uri = site_url.parse().unwrap();
-    let site = Site { parent: site_parsed.to_string() };
-    let security_token =
-        get_security_token(site.clone(), user_name, password);
-    let access_token_cookies = get_access_token_cookies(site.clone(), security_token);
-    let digest = get_the_request_digest(site.clone(), access_token_cookies.clone());    
-    LoginContext{  
-        access_token : access_token_cookies,
-        request_digest : digest,
-        site : site,
+/// Escapes `&`, `<`, `>`, `"` and `'` so `value` can be put into XML text or attributes.
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
     }
+    escaped
+}
+
+/// Replaces the `{name}` placeholders of `template` with XML-escaped values in a single pass,
+/// so a value containing another placeholder is never expanded.
+pub(crate) fn fill_xml_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            values.iter().find(|v| v.0 == name).map(|v| (v.1, end))
+        });
+        match value {
+            Some((value, end)) => {
+                filled.push_str(&xml_escape(value));
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+pub(crate) fn parse_site(site: &str) -> Result<Site> {
+    let site_parsed: hyper::Uri = site.parse().map_err(|_| Error::Uri(site.to_owned()))?;
+    Ok(Site { parent: site_parsed.to_string() })
+}
+
+pub fn login( site : String, user_name : String, password : String ) -> Result<LoginContext> {
+    block_on(|client| client.login(site, user_name, password))
+}
+
+/// `login` against the endpoints in `config` instead of Office 365.
+pub fn login_with_config(site: String, user_name: String, password: String, config: LoginConfig) -> Result<LoginContext> {
+    block_on(|client| client.login_with_config(site, user_name, password, config))
+}
+
+/// `login_with_config` with the credentials of `provider`, which is asked again whenever the
+/// session has to be renewed.
+pub fn login_with_provider<P: CredentialProvider + 'static>(site: String, provider: P, config: LoginConfig) -> Result<LoginContext> {
+    block_on(|client| client.login_with_provider(site, provider, config))
 }
 
-fn host(site: Site) -> String {
-    println!("Parsing '{:?}'", site.parent);
-    let site_parsed: hyper::Uri = site.parent.parse().unwrap();
-    let result = site_parsed.host().unwrap().to_string();
-    println!("Returning '{:?}'", result);
-    result
+pub(crate) fn host(site: Site) -> Result<String> {
+    let (result, _) = host_and_path(site)?;
+    Ok(result)
 }
 
-fn host_and_path(site: Site) -> (String, String) {
-    println!("Parsing '{:?}'", site.parent);
-    let site_parsed: hyper::Uri = site.parent.parse().unwrap();
-    let result = site_parsed.host().unwrap().to_string();
+fn host_and_path(site: Site) -> Result<(String, String)> {
+    let site_parsed: hyper::Uri = site.parent.parse().map_err(|_| Error::Uri(site.parent.to_owned()))?;
+    let result = site_parsed.host().ok_or_else(|| Error::Uri(site.parent.to_owned()))?.to_string();
     let path = site_parsed.path().to_string();
-    println!("Returning '{:?}' & '{:?}'", result, path);
-    (result, path)
-}
-
-pub fn get_security_token(site: Site, user_name: String, password: String) -> String {
-    let host = host(site);
-    let s = GET_SECURITY_TOKEN_BODY_PAR
-        .replace("{user_name}", &user_name)
-        .replace("{password}", &password)
-        .replace("{host}", &host);
-    let res: Envelope = process(
-        GET_SECURITY_TOKEN_URL.to_string(),
-        s.to_string(),
-        None,
-        parse_xml_envelope,
-        false,
-        None,
-        Method::Post,
-        false
-    ).unwrap();
-    res.body
+    Ok((result, path))
+}
+
+pub fn get_security_token(site: Site, user_name: String, password: String) -> Result<String> {
+    block_on(|client| client.get_security_token(site, user_name, password))
+}
+
+/// Extracts the token from the STS answer, which carries a SOAP fault instead when the login fails.
+fn security_token_from_envelope(res: Result<Envelope>) -> Result<String> {
+    let res = match res {
+        // the STS sends faults with a 500 status
+        Err(Error::Status { status, body }) => {
+            match parse_xml_envelope(body.to_owned(), Vec::new(), Vec::new()) {
+                Ok(envelope) => if envelope.body.fault.is_some() {
+                    Ok(envelope)
+                } else {
+                    Err(Error::Status { status: status, body: body })
+                },
+                Err(_) => Err(Error::Status { status: status, body: body }),
+            }
+        }
+        res => res,
+    }?;
+    if let Some(fault) = res.body.fault {
+        return Err(Error::SoapFault(fault.into()));
+    }
+    let token = res.body
         .request_security_token_response
         .requested_security_token
         .binary_security_token
-        .content
-}
-
-fn parse_cookies(_: String, _: Vec<HeaderItem>, cookies: Vec<String>) -> Option<(Vec<String>)> {
-    let res: Vec<String> = cookies
-        .iter()
-        .map(|x| x.to_owned().split(";").next().unwrap().to_string())
-        .filter(|x| x.starts_with("rtFa=") || x.starts_with("FedAuth="))
-        .collect();
-    Some(res)
-}
-
-pub fn get_access_token_cookies(site: Site, security_token: String) -> AccessTokenCookies {
-    let host = host(site);
-    let data = process(
-        GET_ACCESS_TOKEN_URL.replace("{host}", &host),
-        security_token,
-        None,
-        parse_cookies,
-        false,
-        None,
-        Method::Post,
-        false
-    ).unwrap();
+        .content;
+    if token.is_empty() {
+        return Err(Error::SoapFault(SoapFault {
+            reason: "no security token in the response".to_string(),
+            ..Default::default()
+        }));
+    }
+    Ok(token)
+}
+
+/// `security_token_from_envelope` for an STS answer not parsed yet.
+pub(crate) fn security_token_from_response(res: Result<TransportResponse>) -> Result<String> {
+    security_token_from_envelope(res.and_then(|res| {
+        if res.status.is_success() {
+            parse_xml_envelope(res.body, res.headers, res.cookies)
+        } else {
+            Err(status_error(res.status, res.body))
+        }
+    }))
+}
+
+/// The SOAP fault in `body`, if it is one.
+pub(crate) fn parse_soap_fault(body: &str) -> Option<SoapFault> {
+    parse_xml_envelope(body.to_owned(), Vec::new(), Vec::new())
+        .ok()
+        .and_then(|envelope| envelope.body.fault)
+        .map(SoapFault::from)
+}
+
+/// When the cookie of the `Set-Cookie` value `cookie` expires, from its `Max-Age` or `Expires` attribute.
+fn cookie_expiry(cookie: &str) -> Option<SystemTime> {
+    let mut expires = None;
+    for attribute in cookie.split(';').skip(1) {
+        let mut parts = attribute.trim().splitn(2, '=');
+        let name = parts.next().unwrap_or("").to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        if name == "max-age" {
+            if let Ok(seconds) = value.parse::<i64>() {
+                // Max-Age wins over Expires
+                return Some(if seconds > 0 { SystemTime::now() + Duration::from_secs(seconds as u64) } else { UNIX_EPOCH });
+            }
+        } else if name == "expires" {
+            expires = value.parse::<HttpDate>().ok().map(SystemTime::from);
+        }
+    }
+    expires
+}
+
+fn parse_cookies(_: String, _: Vec<HeaderItem>, cookies: Vec<String>) -> Result<AccessTokenCookies> {
     let mut res = AccessTokenCookies {
         rt_fa: None,
         fed_auth: None,
+        valid_until: None,
     };
-    for i in data.clone() {
+    for cookie in cookies.iter() {
+        let i = cookie.split(';').next().unwrap_or("");
         //println!("Cookie:{}", i);
         if i.starts_with("rtFa=") {
-            let right = i.split("rtFa=").nth(1).unwrap().to_string();
-            res = AccessTokenCookies {
-                rt_fa: Some(right),
-                fed_auth: res.fed_auth,
-            };
+            res.rt_fa = Some(i["rtFa=".len()..].to_string());
+        } else if i.starts_with("FedAuth=") {
+            res.fed_auth = Some(i["FedAuth=".len()..].to_string());
+        } else {
+            continue;
         }
-        if i.starts_with("FedAuth=") {
-            let right = i.split("FedAuth=").nth(1).unwrap().to_string();
-            res = AccessTokenCookies {
-                rt_fa: res.rt_fa,
-                fed_auth: Some(right),
-            };
+        if let Some(expiry) = cookie_expiry(cookie) {
+            res.valid_until = Some(res.valid_until.map_or(expiry, |v| ::std::cmp::min(v, expiry)));
         }
     }
-    res
+    if res.rt_fa.is_none() || res.fed_auth.is_none() {
+        return Err(Error::MissingCookies);
+    }
+    Ok(res)
+}
+
+pub fn get_access_token_cookies(site: Site, security_token: String) -> Result<AccessTokenCookies> {
+    block_on(|client| client.get_access_token_cookies(site, security_token))
 }
 
 fn parse_digest(
     body: String,
     _: Vec<HeaderItem>,
     _: Vec<String>,
-) -> Option<GetContextWebInformation> {
+) -> Result<GetContextWebInformation> {
     //println!("Parsing '{:?}'", body);
-    let v: GetContextWebInformation = deserialize(body.as_bytes()).unwrap();
-    Some(v)
+    let v: GetContextWebInformation = from_reader(body.as_bytes()).map_err(|e| Error::Xml(e.to_string()))?;
+    Ok(v)
 }
 
 pub fn get_the_request_digest(
     site: Site,
     access_token_cookies: AccessTokenCookies,
-) -> RequestDigest {
-    let (host, path) = host_and_path(site);
-    let res: GetContextWebInformation = process(
-        GET_REQUEST_DIGEST_URL.replace("{host}", &host).replace(
-            "{path}",
-            &path,
-        ),
-        "".to_string(),
-        Some(access_token_cookies),
-        parse_digest,
-        false,
-        None,
-        Method::Post,
-        false,
-    ).unwrap();
-    RequestDigest { content: res.form_digest_value.content }
+) -> Result<RequestDigest> {
+    block_on(|client| client.get_the_request_digest(site, access_token_cookies))
 }
 
+impl SharePointClient {
+    /// `auth::login` reusing this client's connections.
+    pub fn login(&mut self, site: String, user_name: String, password: String) -> Result<LoginContext> {
+        let work = self.client().login(site, user_name, password);
+        self.run(work)
+    }
+
+    /// `auth::login_with_config` reusing this client's connections.
+    pub fn login_with_config(&mut self, site: String, user_name: String, password: String, config: LoginConfig) -> Result<LoginContext> {
+        let work = self.client().login_with_config(site, user_name, password, config);
+        self.run(work)
+    }
+
+    /// `auth::login_with_provider` reusing this client's connections.
+    pub fn login_with_provider<P: CredentialProvider + 'static>(&mut self, site: String, provider: P, config: LoginConfig) -> Result<LoginContext> {
+        let work = self.client().login_with_provider(site, provider, config);
+        self.run(work)
+    }
+}
+
+impl Client {
+    /// Asynchronous `auth::login`.
+    pub fn login(&self, site: String, user_name: String, password: String) -> SharePointFuture<LoginContext> {
+        self.login_with_config(site, user_name, password, LoginConfig::default())
+    }
+
+    /// Asynchronous `auth::login_with_config`.
+    pub fn login_with_config(
+        &self,
+        site: String,
+        user_name: String,
+        password: String,
+        config: LoginConfig,
+    ) -> SharePointFuture<LoginContext> {
+        let site = match parse_site(&site) {
+            Ok(site) => site,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        Box::new(
+            self.sign_in_with_password(&config, site.clone(), user_name.to_owned(), password.to_owned())
+                .map(move |(authorization, digest)| {
+                    LoginContext::new(site, authorization, digest, config).with_credentials(user_name, password)
+                }),
+        )
+    }
+
+    /// Asynchronous `auth::login_with_provider`.
+    pub fn login_with_provider<P: CredentialProvider + 'static>(&self, site: String, provider: P, config: LoginConfig) -> SharePointFuture<LoginContext> {
+        let site = match parse_site(&site) {
+            Ok(site) => site,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let provider: Arc<dyn CredentialProvider> = Arc::new(provider);
+        Box::new(
+            self.sign_in_with_provider(&config, site.clone(), provider.clone())
+                .map(move |(authorization, digest)| {
+                    LoginContext::new(site, authorization, digest, config).with_renewal(Credentials::Password(provider))
+                }),
+        )
+    }
+
+    fn sign_in_with_provider(
+        &self,
+        config: &LoginConfig,
+        site: Site,
+        provider: Arc<dyn CredentialProvider>,
+    ) -> SharePointFuture<(Authorization, RequestDigest)> {
+        let client = self.clone();
+        let config = config.clone();
+        Box::new(provider.credentials().and_then(move |credentials| {
+            client.sign_in_with_password(&config, site, credentials.user_name, credentials.password)
+        }))
+    }
+
+    /// Runs the whole user realm, security token, cookie and request digest flow.
+    fn sign_in_with_password(
+        &self,
+        config: &LoginConfig,
+        site: Site,
+        user_name: String,
+        password: String,
+    ) -> SharePointFuture<(Authorization, RequestDigest)> {
+        let client = self.clone();
+        let config = config.clone();
+        Box::new(
+            self.request_user_realm(&config, user_name.to_owned())
+                .and_then({
+                    let client = client.clone();
+                    let config = config.clone();
+                    let site = site.clone();
+                    move |realm| {
+                        if realm.is_federated() {
+                            client.request_federated_security_token(&config, realm, site, user_name, password)
+                        } else {
+                            client.request_security_token(config.security_token_url.to_owned(), site, user_name, password)
+                        }
+                    }
+                })
+                .and_then({
+                    let client = client.clone();
+                    let config = config.clone();
+                    let site = site.clone();
+                    move |security_token| client.sign_in(&config, site, security_token)
+                })
+                .and_then(move |access_token_cookies| {
+                    let authorization = Authorization::Cookies(access_token_cookies);
+                    client
+                        .request_digest(&config, site, authorization.clone())
+                        .map(move |digest| (authorization, digest))
+                }),
+        )
+    }
+
+    /// Signs in again with the credentials kept by `login` and stores the new session in it.
+    pub fn renew_session(&self, login: &LoginContext) -> SharePointFuture<()> {
+        let credentials = match login.credentials {
+            Some(ref credentials) => credentials.clone(),
+            None => return Box::new(future::err(Error::SessionExpired)),
+        };
+        debug!("session of {} expired, signing in again", login.site.parent);
+        let signed_in = match credentials {
+            Credentials::Password(provider) => {
+                self.sign_in_with_provider(&login.config, login.site.clone(), provider)
+            }
+            Credentials::ClientSecret { tenant, client_id, client_secret } => {
+                self.sign_in_with_client_secret(&login.config, login.site.clone(), tenant, client_id, client_secret)
+            }
+            Credentials::AddIn { client_id, client_secret } => {
+                self.sign_in_with_add_in(&login.config, login.site.clone(), client_id, client_secret)
+            }
+            #[cfg(feature = "certificate")]
+            Credentials::Certificate { tenant, client_id, certificate } => {
+                self.sign_in_with_certificate(&login.config, login.site.clone(), tenant, client_id, certificate)
+            }
+        };
+        let login = login.clone();
+        Box::new(signed_in.map(move |(authorization, digest)| login.set_session(authorization, digest)))
+    }
+
+    /// Runs `call` with the current session, renewed first if its bearer token is about to expire;
+    /// if SharePoint asks to sign in again, renews the session with the kept credentials and
+    /// runs `call` once more.
+    pub(crate) fn with_session<T, F>(&self, login: &LoginContext, call: F) -> SharePointFuture<T>
+    where
+        T: 'static,
+        F: Fn(Authorization) -> SharePointFuture<T> + 'static,
+    {
+        let call = Rc::new(call);
+        let client = self.clone();
+        let login = login.clone();
+        let session: SharePointFuture<()> = if login.credentials.is_some() && login.authorization().is_expiring() {
+            self.renew_session(&login)
+        } else {
+            Box::new(future::ok(()))
+        };
+        Box::new(session.and_then(move |_| {
+            call(login.authorization()).or_else(move |e| -> SharePointFuture<T> {
+                match e {
+                    Error::SessionExpired if login.credentials.is_some() => {
+                        Box::new(client.renew_session(&login).and_then(move |_| call(login.authorization())))
+                    }
+                    e => Box::new(future::err(e)),
+                }
+            })
+        }))
+    }
+
+    /// Asynchronous `auth::get_security_token`.
+    pub fn get_security_token(&self, site: Site, user_name: String, password: String) -> SharePointFuture<String> {
+        self.request_security_token(OFFICE365.security_token_url.to_string(), site, user_name, password)
+    }
+
+    fn request_security_token(
+        &self,
+        security_token_url: String,
+        site: Site,
+        user_name: String,
+        password: String,
+    ) -> SharePointFuture<String> {
+        let host = match host(site) {
+            Ok(host) => host,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let s = fill_xml_template(
+            GET_SECURITY_TOKEN_BODY_PAR,
+            &[
+                ("security_token_url", &security_token_url),
+                ("user_name", &user_name),
+                ("password", &password),
+                ("host", &host),
+            ],
+        );
+        Box::new(request(
+            self,
+            security_token_url,
+            s.to_string(),
+            None,
+            parse_xml_envelope,
+            false,
+            None,
+            Method::Post,
+            false,
+            true,
+        ).then(security_token_from_envelope))
+    }
+
+    /// Asynchronous `auth::get_access_token_cookies`.
+    pub fn get_access_token_cookies(&self, site: Site, security_token: String) -> SharePointFuture<AccessTokenCookies> {
+        self.sign_in(&LoginConfig::default(), site, security_token)
+    }
+
+    fn sign_in(&self, config: &LoginConfig, site: Site, security_token: String) -> SharePointFuture<AccessTokenCookies> {
+        let (origin, _) = match config.origin_and_path(site) {
+            Ok(origin_and_path) => origin_and_path,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        request(
+            self,
+            format!("{}{}", origin, config.sign_in_path),
+            security_token,
+            None,
+            parse_cookies,
+            false,
+            None,
+            Method::Post,
+            false,
+            true,
+        )
+    }
+
+    /// Asynchronous `auth::get_the_request_digest`.
+    pub fn get_the_request_digest(
+        &self,
+        site: Site,
+        access_token_cookies: AccessTokenCookies,
+    ) -> SharePointFuture<RequestDigest> {
+        self.request_digest(&LoginConfig::default(), site, Authorization::Cookies(access_token_cookies))
+    }
+
+    pub(crate) fn request_digest(
+        &self,
+        config: &LoginConfig,
+        site: Site,
+        authorization: Authorization,
+    ) -> SharePointFuture<RequestDigest> {
+        let (origin, path) = match config.origin_and_path(site) {
+            Ok(origin_and_path) => origin_and_path,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        Box::new(request(
+            self,
+            GET_REQUEST_DIGEST_URL.replace("{origin}", &origin).replace(
+                "{path}",
+                &path,
+            ),
+            "".to_string(),
+            Some(authorization),
+            parse_digest,
+            false,
+            None,
+            Method::Post,
+            false,
+            true,
+        ).map(GetContextWebInformation::into_request_digest))
+    }
+
+    /// The request digest of `login`, fetched again first if it is about to expire.
+    pub fn valid_request_digest(&self, login: &LoginContext) -> SharePointFuture<RequestDigest> {
+        let digest = login.request_digest();
+        if digest.is_expiring() {
+            self.refresh_request_digest(login)
+        } else {
+            Box::new(future::ok(digest))
+        }
+    }
+
+    /// Fetches a new request digest from `/_api/contextinfo` and stores it in `login`.
+    pub fn refresh_request_digest(&self, login: &LoginContext) -> SharePointFuture<RequestDigest> {
+        debug!("fetching a new request digest for {}", login.site.parent);
+        let login = login.clone();
+        Box::new(
+            self.request_digest(&login.config, login.site.clone(), login.authorization())
+                .map(move |digest| {
+                    login.set_request_digest(digest.clone());
+                    digest
+                }),
+        )
+    }
+
+    /// Runs `write` with a valid request digest; if SharePoint still rejects the digest,
+    /// fetches a new one and runs `write` once more.
+    pub(crate) fn with_request_digest<T, F>(&self, login: &LoginContext, write: F) -> SharePointFuture<T>
+    where
+        T: 'static,
+        F: Fn(RequestDigest) -> SharePointFuture<T> + 'static,
+    {
+        let write = Rc::new(write);
+        let client = self.clone();
+        let login = login.clone();
+        Box::new(self.valid_request_digest(&login).and_then(move |digest| {
+            write(digest).or_else(move |e| -> SharePointFuture<T> {
+                match e {
+                    Error::OData(ref e) if e.kind() == ODataErrorKind::InvalidRequestDigest => {
+                        Box::new(client.refresh_request_digest(&login).and_then(move |digest| write(digest)))
+                    }
+                    e => Box::new(future::err(e)),
+                }
+            })
+        }))
+    }
+}
 
 #[cfg(test)]
 pub mod tests {
+    extern crate tokio_core;
+
     use super::*;
+    use self::futures::sync::oneshot;
+    use self::tokio_core::reactor::Core;
+    use adfs::tests::MANAGED_REALM_RESPONSE;
+    use credentials::EnvCredentials;
+    use error::LoginErrorKind;
+    use hyper::StatusCode;
     use std::env;
+    use transport::tests::FakeTransport;
 
     pub fn login_params() -> (String, String, Site) {
-        let login = env::var("RUST_USERNAME").unwrap();
-        let password = env::var("RUST_PASSWORD").unwrap();
+        let credentials = EnvCredentials::default().credentials().wait().unwrap();
         let config_site = env::var("RUST_SITE").unwrap();
         let site_url: &str = &config_site;
         let site_parsed: hyper::Uri = site_url.parse().unwrap();
         let site = Site { parent: site_parsed.to_string() };
-        (login, password, site)
+        (credentials.user_name, credentials.password, site)
     }
 
     use self::serde_json::Value;
 
-    fn parse_json(body: String, _: Vec<HeaderItem>, _: Vec<String>) -> Option<Value> {
+    fn parse_json(body: String, _: Vec<HeaderItem>, _: Vec<String>) -> Result<Value> {
         println!("JSON Parsing '{:?}'", body);
-        let v: Value = serde_json::from_str(&body).unwrap();
-        Some(v)
+        let v: Value = serde_json::from_str(&body)?;
+        Ok(v)
+    }
+
+    static INVALID_CREDENTIALS_FAULT: &'static str = r##"<?xml version="1.0" encoding="utf-8"?>
+<S:Envelope xmlns:S="http://www.w3.org/2003/05/soap-envelope" xmlns:wst="http://schemas.xmlsoap.org/ws/2005/02/trust" xmlns:psf="http://schemas.microsoft.com/Passport/SoapServices/SOAPFault">
+  <S:Body>
+    <S:Fault>
+      <S:Code>
+        <S:Value>S:Sender</S:Value>
+        <S:Subcode><S:Value>wst:FailedAuthentication</S:Value></S:Subcode>
+      </S:Code>
+      <S:Reason><S:Text xml:lang="en-US">Authentication Failure</S:Text></S:Reason>
+      <S:Detail>
+        <psf:error>
+          <psf:value>0x80048821</psf:value>
+          <psf:internalerror>
+            <psf:code>0x80041012</psf:code>
+            <psf:text>AADSTS50126: Invalid username or password.</psf:text>
+          </psf:internalerror>
+        </psf:error>
+      </S:Detail>
+    </S:Fault>
+  </S:Body>
+</S:Envelope>"##;
+
+    #[test]
+    fn reports_sts_fault() {
+        let envelope = parse_xml_envelope(INVALID_CREDENTIALS_FAULT.to_string(), Vec::new(), Vec::new());
+        match security_token_from_envelope(envelope) {
+            Err(Error::SoapFault(fault)) => {
+                assert_eq!(fault.code, "wst:FailedAuthentication");
+                assert_eq!(fault.reason, "Authentication Failure");
+                assert_eq!(fault.value, "0x80048821");
+                assert_eq!(fault.aadsts_code(), Some("AADSTS50126"));
+                assert_eq!(fault.kind(), LoginErrorKind::InvalidCredentials);
+            }
+            res => panic!("expected a SOAP fault, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn provider_answering_later_does_not_hold_up_other_requests() {
+        struct Vault(Mutex<Option<oneshot::Receiver<UserCredentials>>>);
+
+        impl CredentialProvider for Vault {
+            fn credentials(&self) -> SharePointFuture<UserCredentials> {
+                let answer = self.0.lock().unwrap().take().expect("asked once");
+                Box::new(answer.map_err(|_| Error::Credentials("the vault went away".to_string())))
+            }
+        }
+
+        let transport = FakeTransport::default();
+        let mut core = Core::new().unwrap();
+        let client = Client::with_transport(transport.clone());
+        let (vault, answer) = oneshot::channel();
+        let login = client.login_with_provider(
+            "https://company.sharepoint.com/sites/test/".to_string(),
+            Vault(Mutex::new(Some(answer))),
+            LoginConfig::default(),
+        );
+        transport.respond(StatusCode::Ok, MANAGED_REALM_RESPONSE);
+        let answered = transport.clone();
+        let other = client
+            .request_user_realm(&LoginConfig::default(), "other@company.onmicrosoft.com".to_string())
+            .map(move |_| {
+                assert_eq!(answered.requests.borrow().len(), 1);
+                respond_to_login(&answered);
+                vault.send(UserCredentials::new("user@company.onmicrosoft.com", "secret")).unwrap();
+            });
+
+        let (login, _) = core.run(login.join(other)).unwrap();
+        assert_eq!(login.request_digest().content, "0xDIGEST,01 Jan 2018 00:00:00 -0000");
+        assert_eq!(transport.request(0).body, "login=other%40company.onmicrosoft.com&xml=1");
+        assert_eq!(transport.request(1).body, "login=user%40company.onmicrosoft.com&xml=1");
+        assert!(transport.request(2).body.contains("<o:Password>secret</o:Password>"));
+    }
+
+    #[test]
+    fn reports_sts_fault_sent_with_error_status() {
+        let res = Err(Error::Status {
+            status: hyper::StatusCode::InternalServerError,
+            body: INVALID_CREDENTIALS_FAULT.to_string(),
+        });
+        match security_token_from_envelope(res) {
+            Err(Error::SoapFault(fault)) => assert_eq!(fault.kind(), LoginErrorKind::InvalidCredentials),
+            res => panic!("expected a SOAP fault, got {:?}", res),
+        }
+    }
+
+    pub static SECURITY_TOKEN_RESPONSE: &'static str = r##"<S:Envelope xmlns:S="http://www.w3.org/2003/05/soap-envelope" xmlns:wst="http://schemas.xmlsoap.org/ws/2005/02/trust" xmlns:wsse="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd">
+  <S:Body>
+    <wst:RequestSecurityTokenResponse>
+      <wst:RequestedSecurityToken>
+        <wsse:BinarySecurityToken Id="Compact0">t=security-token</wsse:BinarySecurityToken>
+      </wst:RequestedSecurityToken>
+    </wst:RequestSecurityTokenResponse>
+  </S:Body>
+</S:Envelope>"##;
+
+    pub static CONTEXT_INFO_RESPONSE: &'static str = r##"<d:GetContextWebInformation xmlns:d="http://schemas.microsoft.com/ado/2007/08/dataservices" xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata">
+  <d:FormDigestTimeoutSeconds m:type="Edm.Int32">1800</d:FormDigestTimeoutSeconds>
+  <d:FormDigestValue>0xDIGEST,01 Jan 2018 00:00:00 -0000</d:FormDigestValue>
+</d:GetContextWebInformation>"##;
+
+    /// Queues the answers to a password login on `transport`.
+    pub fn respond_to_login(transport: &FakeTransport) {
+        transport
+            .respond(StatusCode::Ok, MANAGED_REALM_RESPONSE)
+            .respond(StatusCode::Ok, SECURITY_TOKEN_RESPONSE)
+            .respond_with_cookies(
+                StatusCode::Found,
+                "",
+                vec!["rtFa=rt-fa-cookie; domain=sharepoint.com; path=/; secure; HttpOnly", "FedAuth=fed-auth-cookie; path=/; secure; HttpOnly"],
+            )
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE);
+    }
+
+    /// Logs in to `https://company.sharepoint.com/sites/test/` through `transport`.
+    pub fn fake_login(transport: &FakeTransport) -> LoginContext {
+        respond_to_login(transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        client.login("https://company.sharepoint.com/sites/test/".to_string(), "user@company.onmicrosoft.com".to_string(), "secret".to_string()).unwrap()
+    }
+
+    #[test]
+    fn login_sends_the_expected_requests() {
+        let transport = FakeTransport::default();
+        let login = fake_login(&transport);
+
+        assert_eq!(login.access_token().unwrap().rt_fa, Some("rt-fa-cookie".to_string()));
+        assert_eq!(login.access_token().unwrap().fed_auth, Some("fed-auth-cookie".to_string()));
+        let digest = login.request_digest();
+        assert_eq!(digest.content, "0xDIGEST,01 Jan 2018 00:00:00 -0000");
+        let valid_for = digest.valid_until.unwrap().duration_since(SystemTime::now()).unwrap();
+        assert!(valid_for > Duration::from_secs(1790) && valid_for <= Duration::from_secs(1800));
+        assert!(!digest.is_expiring());
+
+        let realm = transport.request(0);
+        assert_eq!(realm.url, "https://login.microsoftonline.com/GetUserRealm.srf");
+        assert_eq!(realm.body, "login=user%40company.onmicrosoft.com&xml=1");
+
+        let sts = transport.request(1);
+        assert_eq!(sts.method, Method::Post);
+        assert_eq!(sts.url, "https://login.microsoftonline.com/extSTS.srf");
+        assert!(sts.body.contains("<o:Username>user@company.onmicrosoft.com</o:Username>"));
+        assert!(sts.body.contains("<a:Address>company.sharepoint.com</a:Address>"));
+
+        let sign_in = transport.request(2);
+        assert_eq!(sign_in.url, "https://company.sharepoint.com/_forms/default.aspx?wa=wsignin1.0");
+        assert_eq!(sign_in.body, "t=security-token");
+
+        let digest = transport.request(3);
+        assert!(digest.url.ends_with("/sites/test/_api/contextinfo"));
+        assert_eq!(digest.header("Cookie"), Some("rtFa=rt-fa-cookie; FedAuth=fed-auth-cookie"));
+    }
+
+    #[test]
+    fn reads_cookie_expiry() {
+        let cookies = vec![
+            "rtFa=rt-fa-cookie; domain=sharepoint.com; expires=Wed, 21 Oct 2037 07:28:00 GMT; path=/; secure; HttpOnly".to_string(),
+            "FedAuth=fed-auth-cookie; Max-Age=3600; Expires=Wed, 21 Oct 2037 07:28:00 GMT; path=/".to_string(),
+            "other=1; Max-Age=10".to_string(),
+        ];
+        let res = parse_cookies(String::new(), Vec::new(), cookies).unwrap();
+        let valid_for = res.valid_until.unwrap().duration_since(SystemTime::now()).unwrap();
+        assert!(valid_for > Duration::from_secs(3590) && valid_for <= Duration::from_secs(3600));
+
+        let session_cookies = vec!["rtFa=a; path=/".to_string(), "FedAuth=b; path=/".to_string()];
+        assert!(parse_cookies(String::new(), Vec::new(), session_cookies).unwrap().valid_until.is_none());
+        assert_eq!(
+            cookie_expiry("rtFa=a; expires=Thu, 01 Jan 1970 00:01:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn login_fails_without_cookies() {
+        let transport = FakeTransport::default();
+        transport
+            .respond(StatusCode::Ok, MANAGED_REALM_RESPONSE)
+            .respond(StatusCode::Ok, SECURITY_TOKEN_RESPONSE)
+            .respond(StatusCode::Found, "");
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        match client.login("https://company.sharepoint.com/sites/test/".to_string(), "user".to_string(), "secret".to_string()) {
+            Err(Error::MissingCookies) => {}
+            res => panic!("expected missing cookies, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn login_uses_configured_endpoints() {
+        let transport = FakeTransport::default();
+        transport
+            .respond(StatusCode::Ok, MANAGED_REALM_RESPONSE)
+            .respond(StatusCode::Ok, SECURITY_TOKEN_RESPONSE)
+            .respond_with_cookies(StatusCode::Found, "", vec!["rtFa=rt-fa-cookie", "FedAuth=fed-auth-cookie"])
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE);
+        let config = LoginConfig::china()
+            .sign_in_path("/_forms/custom.aspx?wa=wsignin1.0")
+            .scheme("http");
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        client.login_with_config("https://company.sharepoint.cn:8443/sites/test/".to_string(), "user".to_string(), "secret".to_string(), config).unwrap();
+
+        assert_eq!(transport.request(0).url, "https://login.chinacloudapi.cn/GetUserRealm.srf");
+        let sts = transport.request(1);
+        assert_eq!(sts.url, "https://login.chinacloudapi.cn/extSTS.srf");
+        assert!(sts.body.contains(">https://login.chinacloudapi.cn/extSTS.srf</a:To>"));
+        assert_eq!(transport.request(2).url, "http://company.sharepoint.cn:8443/_forms/custom.aspx?wa=wsignin1.0");
+        assert_eq!(transport.request(3).url, "http://company.sharepoint.cn:8443/sites/test/_api/contextinfo");
+    }
+
+    #[test]
+    fn credentials_are_xml_escaped() {
+        assert_eq!(xml_escape("a&b<c>d\"e'f"), "a&amp;b&lt;c&gt;d&quot;e&apos;f");
+        assert_eq!(xml_escape("]]>"), "]]&gt;");
+
+        let transport = FakeTransport::default();
+        transport.respond(StatusCode::Ok, SECURITY_TOKEN_RESPONSE);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        let site = Site { parent: "https://company.sharepoint.com/sites/test/".to_string() };
+        let work = client.client().get_security_token(
+            site,
+            "o'brien&co@company.com{password}".to_string(),
+            "p<a>ss&]]></o:Password>".to_string(),
+        );
+        client.run(work).unwrap();
+
+        let body = transport.request(0).body;
+        assert!(body.contains("<o:Username>o&apos;brien&amp;co@company.com{password}</o:Username>"));
+        assert!(body.contains("<o:Password>p&lt;a&gt;ss&amp;]]&gt;&lt;/o:Password&gt;</o:Password>"));
+        assert_eq!(body.matches("<o:Password>").count(), 1);
+    }
+
+    #[test]
+    fn national_cloud_presets() {
+        assert_eq!(LoginConfig::office365(), LoginConfig::default());
+        assert_eq!(LoginConfig::us_government().security_token_url, "https://login.microsoftonline.us/extSTS.srf");
+        assert_eq!(LoginConfig::us_government().user_realm_url, "https://login.microsoftonline.us/GetUserRealm.srf");
+        assert_eq!(LoginConfig::germany().security_token_url, "https://login.microsoftonline.de/extSTS.srf");
+        assert_eq!(LoginConfig::germany().sign_in_path, "/_forms/default.aspx?wa=wsignin1.0");
+        assert_eq!(LoginConfig::germany().scheme, None);
+        assert_eq!(LoginConfig::china().token_url, "https://login.chinacloudapi.cn/{tenant}/oauth2/v2.0/token");
+        assert_eq!(LoginConfig::china().acs_token_url, "https://accounts.accesscontrol.chinacloudapi.cn/{realm}/tokens/OAuth/2");
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[test]
+    fn wrong_password_is_rejected_by_mock_server() {
+        use mock::MockServer;
+
+        let server = MockServer::start();
+        let res = login_with_config(
+            server.site_url(),
+            MockServer::USER_NAME.to_string(),
+            "wrong".to_string(),
+            server.login_config(),
+        );
+        match res {
+            Err(Error::SoapFault(fault)) => assert_eq!(fault.kind(), LoginErrorKind::InvalidCredentials),
+            res => panic!("expected a SOAP fault, got {:?}", res.map(|_| ())),
+        }
     }
 
     #[test]
     fn xml_works() {
         let (user_name, password, site) = login_params();
-        let _res = get_security_token(site, user_name.to_string(), password.to_string());
+        let _res = get_security_token(site, user_name.to_string(), password.to_string()).unwrap();
         //println!("Got '{:?}'", _res);
     }
     #[test]
     fn get_access_token_cookies_works() {
         let (user_name, password, site) = login_params();
         let security_token =
-            get_security_token(site.clone(), user_name.to_string(), password.to_string());
-        let access_token = get_access_token_cookies(site, security_token);
+            get_security_token(site.clone(), user_name.to_string(), password.to_string()).unwrap();
+        let access_token = get_access_token_cookies(site, security_token).unwrap();
         assert!(access_token.rt_fa.is_some());
         assert!(access_token.fed_auth.is_some());
     }
@@ -291,9 +1370,9 @@ pub mod tests {
     fn get_the_request_digest_works() {
         let (user_name, password, site) = login_params();
         let security_token =
-            get_security_token(site.clone(), user_name.to_string(), password.to_string());
+            get_security_token(site.clone(), user_name.to_string(), password.to_string()).unwrap();
         let digest =
-            get_the_request_digest(site.clone(), get_access_token_cookies(site, security_token));
+            get_the_request_digest(site.clone(), get_access_token_cookies(site, security_token).unwrap()).unwrap();
         //println!("Digest '{:?}'", digest);
         assert!(digest.content.len() > 0);
     }
@@ -301,25 +1380,27 @@ pub mod tests {
     fn get_the_list() {
         let (user_name, password, site) = login_params();
         let security_token =
-            get_security_token(site.clone(), user_name.to_string(), password.to_string());
+            get_security_token(site.clone(), user_name.to_string(), password.to_string()).unwrap();
 
-        let access_token_cookies = get_access_token_cookies(site.clone(), security_token);
-        let digest = get_the_request_digest(site, access_token_cookies.clone());
+        let access_token_cookies = get_access_token_cookies(site.clone(), security_token).unwrap();
+        let digest = get_the_request_digest(site, access_token_cookies.clone()).unwrap();
 
         println!(
             "Trying to get to '{}'",
             env::var("RUST_LIST_GET_URL").unwrap().to_string()
         );
 
-        process(
+        block_on(|client| request(
+            client,
             env::var("RUST_LIST_GET_URL").unwrap().to_string(),
             "".to_string(),
-            Some(access_token_cookies),
+            Some(Authorization::Cookies(access_token_cookies)),
             parse_json,
             true,
             Some(digest),
             Method::Get,
             false,
-        );
+            false,
+        )).unwrap();
     }
 }
@@ -0,0 +1,655 @@
+//! Grouping many list operations into one `/_api/$batch` request.
+//!
+//! Operations are queued on a `Batch`, each returning a `BatchResponse` that holds its own
+//! result once the batch has been executed. Reads are sent one by one, consecutive writes
+//! together in a changeset, each with a `Content-ID` to find its response by.
+//!
+//! ```no_run
+//! # extern crate sharepoint;
+//! # extern crate serde_json;
+//! # use std::collections::HashMap;
+//! # use sharepoint::SharePointClient;
+//! # use sharepoint::batch::Batch;
+//! # use serde_json::Value;
+//! # fn main() {
+//! # let mut client = SharePointClient::new().unwrap();
+//! # let login = client.login("https://company.sharepoint.com/sites/test/".to_string(), "user".to_string(), "secret".to_string()).unwrap();
+//! let mut batch = Batch::new(&login);
+//! let created: Vec<_> = (0..500)
+//!     .map(|i| {
+//!         let mut item = HashMap::new();
+//!         item.insert("Title", format!("Item {}", i));
+//!         batch.add_list_item_by_list_title::<Value, _>("Tasks", item)
+//!     })
+//!     .collect();
+//! let items = batch.get_list_items_by_title::<Value>("Tasks");
+//! client.execute_batch(batch).unwrap();
+//! for item in created {
+//!     println!("created {}", item.into_result().unwrap()["Id"]);
+//! }
+//! println!("{} items", items.into_result().unwrap().len());
+//! # }
+//! ```
+extern crate futures;
+extern crate rand;
+extern crate serde;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use hyper::{Method, StatusCode};
+
+use self::futures::{stream, Future, Stream};
+use self::serde::de::DeserializeOwned;
+use self::serde::ser::Serialize;
+
+#[allow(unused_imports)]
+use super::*;
+
+use auth::LoginContext;
+use client::{block_on, Client, SharePointClient, SharePointFuture};
+use data::{post_batch_with_login, status_error, HeaderItem};
+use error::{Error, Result};
+use list::{list_items_url, list_url, List, ListItemsContainer};
+use odata::QueryOptions;
+
+static BATCH_URL: &'static str = "{site}/_api/$batch";
+
+/// Where the result of a queued operation ends up: the response body or the error.
+type Slot = Rc<RefCell<Option<Result<String>>>>;
+
+struct Operation {
+    method: Method,
+    url: String,
+    body: Option<String>,
+    /// `X-HTTP-Method` of updates and deletes tunneled through `POST`.
+    x_http_method: Option<&'static str>,
+    /// `Content-ID` of a write in its changeset, unique in the batch.
+    content_id: usize,
+    slot: Slot,
+}
+
+impl Operation {
+    fn is_write(&self) -> bool {
+        self.method != Method::Get
+    }
+
+    fn resolve(&self, response: &PartResponse) {
+        let result = if response.status.is_success() {
+            Ok(response.body.to_owned())
+        } else {
+            Err(status_error(response.status, response.body.to_owned()))
+        };
+        *self.slot.borrow_mut() = Some(result);
+    }
+
+    fn fail(&self) {
+        *self.slot.borrow_mut() = Some(Err(Error::Batch(format!("no response to {} {}", self.method, self.url))));
+    }
+}
+
+/// List operations queued to be sent in `$batch` requests by `Client::execute_batch`.
+pub struct Batch {
+    login: LoginContext,
+    operations: Vec<Operation>,
+    max_operations: usize,
+}
+
+/// Result of one operation of a `Batch`, available once the batch has been executed.
+pub struct BatchResponse<T> {
+    slot: Slot,
+    parser: fn(String) -> Result<T>,
+}
+
+impl<T> BatchResponse<T> {
+    /// The result of the operation; `Error::Batch` if the batch was not executed or its
+    /// request failed as a whole.
+    pub fn into_result(self) -> Result<T> {
+        match self.slot.borrow_mut().take() {
+            Some(Ok(body)) => (self.parser)(body),
+            Some(Err(e)) => Err(e),
+            None => Err(Error::Batch("the operation was not carried out".to_string())),
+        }
+    }
+}
+
+fn parse_json<T: DeserializeOwned>(body: String) -> Result<T> {
+    Ok(serde_json::from_str(&body)?)
+}
+
+fn parse_items<T: DeserializeOwned + Default>(body: String) -> Result<Vec<T>> {
+    let items: ListItemsContainer<T> = serde_json::from_str(&body)?;
+    Ok(items.results)
+}
+
+fn parse_nothing(_: String) -> Result<()> {
+    Ok(())
+}
+
+impl Batch {
+    /// Empty batch of operations on the site of `login`, sent with its session.
+    pub fn new(login: &LoginContext) -> Batch {
+        Batch {
+            login: login.clone(),
+            operations: Vec::new(),
+            max_operations: 100,
+        }
+    }
+
+    /// Sends at most `max_operations` operations per `$batch` request, 100 by default; more
+    /// are sent in further requests, one after the other.
+    pub fn with_max_operations(mut self, max_operations: usize) -> Batch {
+        self.max_operations = max_operations.max(1);
+        self
+    }
+
+    /// Number of queued operations.
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    fn queue<T>(
+        &mut self,
+        method: Method,
+        url: String,
+        body: Option<String>,
+        x_http_method: Option<&'static str>,
+        parser: fn(String) -> Result<T>,
+    ) -> BatchResponse<T> {
+        let slot: Slot = Rc::new(RefCell::new(None));
+        let content_id = self.operations.len() + 1;
+        self.operations.push(Operation {
+            method: method,
+            url: url,
+            body: body,
+            x_http_method: x_http_method,
+            content_id: content_id,
+            slot: slot.clone(),
+        });
+        BatchResponse { slot: slot, parser: parser }
+    }
+
+    /// Fails the operation right away, without queuing it.
+    fn failed<T>(&self, e: Error, parser: fn(String) -> Result<T>) -> BatchResponse<T> {
+        BatchResponse {
+            slot: Rc::new(RefCell::new(Some(Err(e)))),
+            parser: parser,
+        }
+    }
+
+    /// Queues `list::get_list_by_title`.
+    pub fn get_list_by_title(&mut self, title: &str) -> BatchResponse<List> {
+        let url = list_url(title, &self.login.site);
+        self.queue(Method::Get, url, None, None, parse_json)
+    }
+
+    /// Queues `list::get_list_items_by_title`.
+    pub fn get_list_items_by_title<T>(&mut self, title: &str) -> BatchResponse<Vec<T>>
+    where
+        T: DeserializeOwned + Default,
+    {
+        self.get_list_items_by_title_with_query(title, QueryOptions::default())
+    }
+
+    /// Queues `list::get_list_items_by_title_with_query`.
+    pub fn get_list_items_by_title_with_query<T>(&mut self, title: &str, options: QueryOptions) -> BatchResponse<Vec<T>>
+    where
+        T: DeserializeOwned + Default,
+    {
+        let url = options.append_to(&list_items_url(title, &self.login.site));
+        self.queue(Method::Get, url, None, None, parse_items)
+    }
+
+    /// Queues `list::add_list_item_by_list_title`.
+    pub fn add_list_item_by_list_title<T, U>(&mut self, title: &str, data: U) -> BatchResponse<T>
+    where
+        T: DeserializeOwned,
+        U: Serialize,
+    {
+        let body = match serde_json::to_string(&data) {
+            Ok(body) => body,
+            Err(e) => return self.failed(e.into(), parse_json),
+        };
+        let url = list_items_url(title, &self.login.site);
+        self.queue(Method::Post, url, Some(body), None, parse_json)
+    }
+
+    /// Queues `list::update_list_item_by_list_title`.
+    pub fn update_list_item_by_list_title<U>(&mut self, title: &str, data: U, id: i32) -> BatchResponse<()>
+    where
+        U: Serialize,
+    {
+        let body = match serde_json::to_string(&data) {
+            Ok(body) => body,
+            Err(e) => return self.failed(e.into(), parse_nothing),
+        };
+        let url = format!("{}({})", list_items_url(title, &self.login.site), id);
+        self.queue(Method::Post, url, Some(body), Some("PATCH"), parse_nothing)
+    }
+
+    /// Queues deleting the item `id` of the list `title`.
+    pub fn delete_list_item_by_list_title(&mut self, title: &str, id: i32) -> BatchResponse<()> {
+        let url = format!("{}({})", list_items_url(title, &self.login.site), id);
+        self.queue(Method::Post, url, None, Some("DELETE"), parse_nothing)
+    }
+}
+
+/// A read on its own or consecutive writes in a changeset, as sent in a `$batch`.
+enum Part<'a> {
+    Read(&'a Operation),
+    ChangeSet(Vec<&'a Operation>),
+}
+
+fn parts<'a>(operations: &'a [Operation]) -> Vec<Part<'a>> {
+    let mut parts = Vec::new();
+    for operation in operations {
+        if !operation.is_write() {
+            parts.push(Part::Read(operation));
+            continue;
+        }
+        if let Some(&mut Part::ChangeSet(ref mut writes)) = parts.last_mut() {
+            writes.push(operation);
+            continue;
+        }
+        parts.push(Part::ChangeSet(vec![operation]));
+    }
+    parts
+}
+
+fn random_boundary(prefix: &str) -> String {
+    format!("{}_{:016x}{:016x}", prefix, rand::random::<u64>(), rand::random::<u64>())
+}
+
+fn write_operation(body: &mut String, operation: &Operation) {
+    body.push_str("Content-Type: application/http\r\nContent-Transfer-Encoding: binary\r\n");
+    if operation.is_write() {
+        body.push_str(&format!("Content-ID: {}\r\n", operation.content_id));
+    }
+    body.push_str("\r\n");
+    body.push_str(&format!("{} {} HTTP/1.1\r\n", operation.method, operation.url));
+    body.push_str("Accept: application/json\r\n");
+    if let Some(method) = operation.x_http_method {
+        body.push_str(&format!("X-HTTP-Method: {}\r\nIf-Match: *\r\n", method));
+    }
+    match operation.body {
+        Some(ref content) => body.push_str(&format!("Content-Type: application/json\r\n\r\n{}\r\n", content)),
+        None => body.push_str("\r\n"),
+    }
+}
+
+/// The multipart/mixed body of a `$batch` request with `boundary`.
+fn batch_body(parts: &[Part], boundary: &str) -> String {
+    let mut body = String::new();
+    for part in parts {
+        body.push_str(&format!("--{}\r\n", boundary));
+        match *part {
+            Part::Read(operation) => write_operation(&mut body, operation),
+            Part::ChangeSet(ref writes) => {
+                let changeset = random_boundary("changeset");
+                body.push_str(&format!("Content-Type: multipart/mixed; boundary={}\r\n\r\n", changeset));
+                for operation in writes {
+                    body.push_str(&format!("--{}\r\n", changeset));
+                    write_operation(&mut body, operation);
+                }
+                body.push_str(&format!("--{}--\r\n", changeset));
+            }
+        }
+    }
+    body.push_str(&format!("--{}--\r\n", boundary));
+    body
+}
+
+/// One HTTP response of a `$batch` response.
+#[derive(Debug)]
+struct PartResponse {
+    /// `Content-ID` of the write answered, if SharePoint sent it back.
+    content_id: Option<usize>,
+    status: StatusCode,
+    body: String,
+}
+
+/// The `boundary` parameter of a multipart `content_type`.
+fn boundary(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).filter_map(|p| {
+        let mut param = p.splitn(2, '=');
+        match (param.next(), param.next()) {
+            (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("boundary") => Some(value.trim().trim_matches('"').to_owned()),
+            _ => None,
+        }
+    }).next()
+}
+
+/// Splits `content` at the first empty line into its headers and what follows.
+fn split_head(content: &str) -> (&str, &str) {
+    match content.find("\r\n\r\n") {
+        Some(i) => (&content[..i], &content[i + 4..]),
+        None => match content.find("\n\n") {
+            Some(i) => (&content[..i], &content[i + 2..]),
+            None => (content, ""),
+        },
+    }
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().filter_map(|line| {
+        let mut header = line.splitn(2, ':');
+        match (header.next(), header.next()) {
+            (Some(n), Some(value)) if n.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+            _ => None,
+        }
+    }).next()
+}
+
+/// The parts of a multipart `body` delimited by `boundary`.
+fn multipart<'a>(body: &'a str, boundary: &str) -> Vec<&'a str> {
+    let delimiter = format!("--{}", boundary);
+    body.split(delimiter.as_str())
+        .skip(1)
+        .take_while(|part| !part.starts_with("--"))
+        .map(|part| part.trim_start_matches(&['\r', '\n'][..]))
+        .collect()
+}
+
+/// An `application/http` part: MIME headers, then status line, headers and body.
+fn http_response(part: &str) -> Result<PartResponse> {
+    let (mime, content) = split_head(part);
+    let content_id = header(mime, "Content-ID").and_then(|id| id.trim_matches(&['<', '>'][..]).parse().ok());
+    let (head, body) = split_head(content);
+    let status_line = head.lines().next().unwrap_or("");
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .and_then(|code| StatusCode::try_from(code).ok())
+        .ok_or_else(|| Error::Batch(format!("invalid status line '{}'", status_line)))?;
+    Ok(PartResponse {
+        content_id: content_id,
+        status: status,
+        body: body.trim_end_matches(&['\r', '\n'][..]).to_owned(),
+    })
+}
+
+/// The responses of a `$batch` response in order, those in changesets included; SharePoint
+/// Online sends the responses to writes as top-level parts rather than in a changeset.
+fn parse_batch_response(body: String, headers: Vec<HeaderItem>, _: Vec<String>) -> Result<Vec<PartResponse>> {
+    let outer = headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Content-Type"))
+        .and_then(|h| boundary(&h.value))
+        .or_else(|| body.lines().next().filter(|l| l.starts_with("--")).map(|l| l[2..].trim().to_owned()))
+        .ok_or_else(|| Error::Batch("no multipart boundary".to_string()))?;
+    let mut responses = Vec::new();
+    for part in multipart(&body, &outer) {
+        let (head, content) = split_head(part);
+        match header(head, "Content-Type").and_then(boundary) {
+            Some(changeset) => {
+                for part in multipart(content, &changeset) {
+                    responses.push(http_response(part)?);
+                }
+            }
+            None => responses.push(http_response(part)?),
+        }
+    }
+    Ok(responses)
+}
+
+/// Hands every operation its response, or `Error::Batch` if there is none. The responses to
+/// the writes of a changeset are matched by `Content-ID`, by position if there is none.
+fn resolve(parts: &[Part], responses: Vec<PartResponse>) {
+    let mut responses = responses.into_iter().peekable();
+    for part in parts {
+        let writes = match *part {
+            Part::Read(operation) => {
+                match responses.next() {
+                    Some(res) => operation.resolve(&res),
+                    None => operation.fail(),
+                }
+                continue;
+            }
+            Part::ChangeSet(ref writes) => writes,
+        };
+        // SharePoint answers a failed changeset with a single error
+        if responses.peek().map_or(false, |res| res.content_id.is_none() && !res.status.is_success()) {
+            let res = responses.next().unwrap();
+            for operation in writes {
+                operation.resolve(&res);
+            }
+            continue;
+        }
+        let mut answers: Vec<Option<PartResponse>> = writes.iter().map(|_| None).collect();
+        for position in 0..writes.len() {
+            let index = match responses.peek() {
+                Some(&PartResponse { content_id: Some(id), .. }) => writes.iter().position(|operation| operation.content_id == id),
+                Some(_) => Some(position),
+                None => None,
+            };
+            match index {
+                Some(index) if answers[index].is_none() => answers[index] = responses.next(),
+                _ => break,
+            }
+        }
+        for (operation, answer) in writes.iter().zip(answers) {
+            match answer {
+                Some(res) => operation.resolve(&res),
+                None => operation.fail(),
+            }
+        }
+    }
+}
+
+/// `Client::execute_batch` with a client of its own.
+pub fn execute(batch: Batch) -> Result<()> {
+    block_on(|client| client.execute_batch(batch))
+}
+
+impl SharePointClient {
+    /// `batch::execute` reusing this client's connections.
+    pub fn execute_batch(&mut self, batch: Batch) -> Result<()> {
+        let work = self.client().execute_batch(batch);
+        self.run(work)
+    }
+}
+
+impl Client {
+    /// Sends the operations of `batch` in `$batch` requests and resolves their
+    /// `BatchResponse`s. Fails if a request fails as a whole, leaving the operations of it and
+    /// of the requests after it without result.
+    pub fn execute_batch(&self, batch: Batch) -> SharePointFuture<()> {
+        let Batch { login, mut operations, max_operations } = batch;
+        let mut chunks = Vec::new();
+        while !operations.is_empty() {
+            let rest = operations.split_off(operations.len().min(max_operations));
+            chunks.push(operations);
+            operations = rest;
+        }
+        let client = self.clone();
+        Box::new(stream::iter_ok(chunks).for_each(move |operations| client.send_batch(&login, operations)))
+    }
+
+    fn send_batch(&self, login: &LoginContext, operations: Vec<Operation>) -> SharePointFuture<()> {
+        let boundary = random_boundary("batch");
+        let body = batch_body(&parts(&operations), &boundary);
+        let url = BATCH_URL.replace("{site}", login.site.url());
+        let response = post_batch_with_login(
+            self,
+            url,
+            login,
+            format!("multipart/mixed; boundary={}", boundary),
+            body,
+            parse_batch_response,
+        );
+        Box::new(response.map(move |responses| resolve(&parts(&operations), responses)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::tests::fake_login;
+    use transport::tests::FakeTransport;
+
+    #[derive(Debug, Deserialize, Default)]
+    struct Item {
+        #[serde(rename = "Id", default)]
+        id: i32,
+    }
+
+    /// A `$batch` response with `boundary`, from its parts as sent by SharePoint.
+    fn response_body(boundary: &str, parts: &[String]) -> String {
+        let mut body = String::new();
+        for part in parts {
+            body.push_str(&format!("--{}\r\n{}\r\n", boundary, part));
+        }
+        body.push_str(&format!("--{}--\r\n", boundary));
+        body
+    }
+
+    fn http(status: &str, body: &str) -> String {
+        format!(
+            "Content-Type: application/http\r\nContent-Transfer-Encoding: binary\r\n\r\nHTTP/1.1 {}\r\nCONTENT-TYPE: application/json;odata=minimalmetadata;streaming=true;charset=utf-8\r\n\r\n{}",
+            status, body
+        )
+    }
+
+    /// `http` answering the write with `content_id`.
+    fn answer(content_id: usize, status: &str, body: &str) -> String {
+        http(status, body).replacen("\r\n\r\n", &format!("\r\nContent-ID: {}\r\n\r\n", content_id), 1)
+    }
+
+    fn changeset(boundary: &str, parts: &[String]) -> String {
+        format!("Content-Type: multipart/mixed; boundary={}\r\n\r\n{}", boundary, response_body(boundary, parts))
+    }
+
+    #[test]
+    fn writes_are_grouped_in_changesets() {
+        let transport = FakeTransport::default();
+        let login = fake_login(&transport);
+        let mut batch = Batch::new(&login);
+        batch.get_list_by_title("Tasks");
+        batch.add_list_item_by_list_title::<Item, _>("Tasks", json!({"Title": "One"}));
+        batch.update_list_item_by_list_title("Tasks", json!({"Title": "Two"}), 2);
+        batch.delete_list_item_by_list_title("Tasks", 3);
+        batch.get_list_items_by_title::<Item>("Tasks");
+        batch.delete_list_item_by_list_title("Bob's tasks", 4);
+
+        let body = batch_body(&parts(&batch.operations), "batch_1");
+        let sections: Vec<&str> = multipart(&body, "batch_1");
+        assert_eq!(sections.len(), 4);
+        assert!(sections[0].contains("\r\n\r\nGET https://company.sharepoint.com/sites/test/_api/web/lists/GetByTitle('Tasks') HTTP/1.1\r\n"));
+        assert!(sections[2].contains("GET https://company.sharepoint.com/sites/test/_api/web/lists/GetByTitle('Tasks')/items HTTP/1.1\r\n"));
+
+        let (head, content) = split_head(sections[1]);
+        let changeset = boundary(header(head, "Content-Type").unwrap()).unwrap();
+        let writes = multipart(content, &changeset);
+        assert_eq!(writes.len(), 3);
+        assert!(writes[0].starts_with("Content-Type: application/http\r\nContent-Transfer-Encoding: binary\r\nContent-ID: 2\r\n\r\n"));
+        assert!(writes[0].contains("POST https://company.sharepoint.com/sites/test/_api/web/lists/GetByTitle('Tasks')/items HTTP/1.1\r\n"));
+        assert!(writes[2].starts_with("Content-Type: application/http\r\nContent-Transfer-Encoding: binary\r\nContent-ID: 4\r\n\r\n"));
+        assert!(writes[0].ends_with("Content-Type: application/json\r\n\r\n{\"Title\":\"One\"}\r\n"));
+        assert!(writes[1].contains("/items(2) HTTP/1.1\r\nAccept: application/json\r\nX-HTTP-Method: PATCH\r\nIf-Match: *\r\n"));
+        assert!(writes[2].contains("/items(3) HTTP/1.1\r\nAccept: application/json\r\nX-HTTP-Method: DELETE\r\nIf-Match: *\r\n\r\n"));
+        assert!(sections[3].contains("POST https://company.sharepoint.com/sites/test/_api/web/lists/GetByTitle('Bob''s%20tasks')/items(4) HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn each_operation_gets_its_own_result() {
+        let transport = FakeTransport::default();
+        let login = fake_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        let mut batch = Batch::new(&login);
+        let items = batch.get_list_items_by_title::<Item>("Tasks");
+        let created = batch.add_list_item_by_list_title::<Item, _>("Tasks", json!({"Title": "One"}));
+        let updated = batch.update_list_item_by_list_title("Tasks", json!({"Title": "Two"}), 2);
+        let missing = batch.get_list_by_title("Missing");
+
+        let body = response_body(
+            "batchresponse_1",
+            &[
+                http("200 OK", r#"{"value":[{"Id":1},{"Id":2}]}"#),
+                changeset("changesetresponse_1", &[http("201 Created", r#"{"Id":3}"#), http("204 No Content", "")]),
+                http("404 Not Found", r#"{"odata.error":{"code":"-1, System.ArgumentException","message":{"lang":"en-US","value":"List 'Missing' does not exist."}}}"#),
+            ],
+        );
+        transport.respond_with_headers(StatusCode::Ok, &body, vec![("Content-Type", "multipart/mixed; boundary=batchresponse_1")]);
+        client.execute_batch(batch).unwrap();
+
+        let request = transport.request(4);
+        assert_eq!(request.url, "https://company.sharepoint.com/sites/test/_api/$batch");
+        assert_eq!(request.header("X-RequestDigest"), Some("0xDIGEST,01 Jan 2018 00:00:00 -0000"));
+        let sent = boundary(request.header("Content-Type").unwrap()).unwrap();
+        assert!(sent.starts_with("batch_"));
+        assert_eq!(multipart(&request.body, &sent).len(), 3);
+
+        assert_eq!(items.into_result().unwrap().len(), 2);
+        assert_eq!(created.into_result().unwrap().id, 3);
+        updated.into_result().unwrap();
+        match missing.into_result() {
+            Err(Error::OData(e)) => assert_eq!(e.message, "List 'Missing' does not exist."),
+            res => panic!("expected an OData error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn flattened_changeset_responses_are_matched_by_content_id() {
+        let transport = FakeTransport::default();
+        let login = fake_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        let mut batch = Batch::new(&login);
+        let first = batch.add_list_item_by_list_title::<Item, _>("Tasks", json!({"Title": "One"}));
+        let second = batch.add_list_item_by_list_title::<Item, _>("Tasks", json!({"Title": "Two"}));
+        let deleted = batch.delete_list_item_by_list_title("Tasks", 7);
+        let items = batch.get_list_items_by_title::<Item>("Tasks");
+
+        let body = response_body(
+            "batchresponse_1",
+            &[
+                answer(2, "201 Created", r#"{"Id":12}"#),
+                answer(3, "200 OK", ""),
+                answer(1, "201 Created", r#"{"Id":11}"#),
+                http("200 OK", r#"{"value":[{"Id":11},{"Id":12}]}"#),
+            ],
+        );
+        transport.respond_with_headers(StatusCode::Ok, &body, vec![("Content-Type", "multipart/mixed; boundary=batchresponse_1")]);
+        client.execute_batch(batch).unwrap();
+
+        assert_eq!(first.into_result().unwrap().id, 11);
+        assert_eq!(second.into_result().unwrap().id, 12);
+        deleted.into_result().unwrap();
+        assert_eq!(items.into_result().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn failed_changeset_fails_all_its_writes() {
+        let transport = FakeTransport::default();
+        let login = fake_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        let mut batch = Batch::new(&login).with_max_operations(2);
+        let first = batch.add_list_item_by_list_title::<Item, _>("Tasks", json!({"Title": "One"}));
+        let second = batch.add_list_item_by_list_title::<Item, _>("Tasks", json!({"Title": "Two"}));
+        let third = batch.add_list_item_by_list_title::<Item, _>("Tasks", json!({"Title": "Three"}));
+
+        let failed = response_body("batchresponse_1", &[http("400 Bad Request", r#"{"odata.error":{"code":"-1, Microsoft.SharePoint.Client.InvalidClientQueryException","message":{"lang":"en-US","value":"Invalid request."}}}"#)]);
+        transport.respond_with_headers(StatusCode::Ok, &failed, vec![("Content-Type", "multipart/mixed; boundary=batchresponse_1")]);
+        transport.respond_with_headers(StatusCode::Ok, "--batchresponse_2--\r\n", vec![("Content-Type", "multipart/mixed; boundary=batchresponse_2")]);
+        client.execute_batch(batch).unwrap();
+        assert_eq!(transport.requests.borrow().len(), 6);
+
+        let rejected = |res: BatchResponse<Item>| match res.into_result() {
+            Err(Error::OData(e)) => assert_eq!(e.message, "Invalid request."),
+            res => panic!("expected an OData error, got {:?}", res),
+        };
+        rejected(first);
+        rejected(second);
+        match third.into_result() {
+            Err(Error::Batch(_)) => {}
+            res => panic!("expected a missing response, got {:?}", res),
+        }
+    }
+}
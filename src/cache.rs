@@ -0,0 +1,367 @@
+//! On-disk cache of a `LoginContext`, so tools started again and again sign in only once
+//! the cached session has expired.
+//!
+//! ```no_run
+//! # extern crate sharepoint;
+//! # use sharepoint::auth::LoginConfig;
+//! # use sharepoint::cache::{self, TokenCache};
+//! # fn main() {
+//! let cache = TokenCache::new("/home/me/.cache/sharepoint/tokens.json").encrypted(b"key from the vault");
+//! let login = cache::login_with_cache(
+//!     &cache,
+//!     "https://company.sharepoint.com/sites/test/".to_string(),
+//!     "user@company.onmicrosoft.com".to_string(),
+//!     "secret".to_string(),
+//!     LoginConfig::default(),
+//! ).unwrap();
+//! // ... work with login, then keep a session renewed meanwhile for the next run
+//! cache.store(&login).unwrap();
+//! # }
+//! ```
+extern crate aes_gcm;
+extern crate base64;
+extern crate futures;
+extern crate rand;
+extern crate sha2;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use self::aes_gcm::aead::Aead;
+use self::aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use self::futures::{future, Future};
+use self::sha2::{Digest, Sha256};
+
+#[allow(unused_imports)]
+use super::*;
+
+use auth::{parse_site, LoginConfig, LoginContext};
+use client::{block_on, Client, SharePointClient, SharePointFuture};
+use error::{Error, Result};
+
+/// Cached context encrypted with AES-256-GCM.
+#[derive(Serialize, Deserialize)]
+struct EncryptedContext {
+    nonce: String,
+    ciphertext: String,
+}
+
+/// File keeping the `LoginContext` of one account, readable and writable by the current
+/// user only (on Unix). The credentials kept to sign in again are never written.
+#[derive(Clone)]
+pub struct TokenCache {
+    path: PathBuf,
+    key: Option<[u8; 32]>,
+}
+
+impl TokenCache {
+    pub fn new<P: Into<PathBuf>>(path: P) -> TokenCache {
+        TokenCache {
+            path: path.into(),
+            key: None,
+        }
+    }
+
+    /// Encrypts the cached context with AES-256-GCM, under the SHA-256 hash of `key`.
+    pub fn encrypted(mut self, key: &[u8]) -> TokenCache {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&Sha256::digest(key));
+        self.key = Some(hash);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The cached context, or `None` if nothing has been stored yet.
+    pub fn load(&self) -> Result<Option<LoginContext>> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let plain = match self.key {
+            Some(ref key) => decrypt(key, &content)?,
+            None => content,
+        };
+        Ok(Some(serde_json::from_slice(&plain)?))
+    }
+
+    /// Writes `login` to the cache, replacing what was there.
+    pub fn store(&self, login: &LoginContext) -> Result<()> {
+        let plain = serde_json::to_vec(login)?;
+        let content = match self.key {
+            Some(ref key) => encrypt(key, &plain)?,
+            None => plain,
+        };
+        // written next to the cache and renamed, so a crash never leaves half a file
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        let mut file = create_private(&temporary)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+
+    /// Removes the cached context, if there is one.
+    pub fn clear(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
+    }
+}
+
+fn encrypt(key: &[u8; 32], plain: &[u8]) -> Result<Vec<u8>> {
+    let nonce: [u8; 12] = rand::random();
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plain)
+        .map_err(|_| Error::TokenCache("cannot encrypt the login context".to_string()))?;
+    Ok(serde_json::to_vec(&EncryptedContext {
+        nonce: base64::encode(&nonce),
+        ciphertext: base64::encode(&ciphertext),
+    })?)
+}
+
+fn decrypt(key: &[u8; 32], content: &[u8]) -> Result<Vec<u8>> {
+    let encrypted: EncryptedContext = serde_json::from_slice(content)?;
+    let nonce = base64::decode(&encrypted.nonce).map_err(|e| Error::TokenCache(e.to_string()))?;
+    let ciphertext = base64::decode(&encrypted.ciphertext).map_err(|e| Error::TokenCache(e.to_string()))?;
+    if nonce.len() != 12 {
+        return Err(Error::TokenCache("invalid nonce".to_string()));
+    }
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| Error::TokenCache("cannot decrypt the cached login context, wrong key?".to_string()))
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // the mode only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create(true).truncate(true).open(path)
+}
+
+/// `auth::login_with_config`, reusing the context in `cache` while it is for the same site,
+/// endpoints and account and has not expired, and storing the new context otherwise. A context
+/// that cannot be stored is still returned.
+///
+/// Contexts whose cookies do not say when they expire are reused until SharePoint asks to
+/// sign in again, which the restored context then does with `user_name` and `password`.
+pub fn login_with_cache(
+    cache: &TokenCache,
+    site: String,
+    user_name: String,
+    password: String,
+    config: LoginConfig,
+) -> Result<LoginContext> {
+    block_on(|client| client.login_with_cache(cache, site, user_name, password, config))
+}
+
+impl SharePointClient {
+    /// `cache::login_with_cache` reusing this client's connections.
+    pub fn login_with_cache(
+        &mut self,
+        cache: &TokenCache,
+        site: String,
+        user_name: String,
+        password: String,
+        config: LoginConfig,
+    ) -> Result<LoginContext> {
+        let work = self.client().login_with_cache(cache, site, user_name, password, config);
+        self.run(work)
+    }
+}
+
+impl Client {
+    /// Asynchronous `cache::login_with_cache`.
+    pub fn login_with_cache(
+        &self,
+        cache: &TokenCache,
+        site: String,
+        user_name: String,
+        password: String,
+        config: LoginConfig,
+    ) -> SharePointFuture<LoginContext> {
+        let parsed = match parse_site(&site) {
+            Ok(parsed) => parsed,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        // an unreadable cache is signed in again and overwritten
+        if let Ok(Some(login)) = cache.load() {
+            let same_account = login.user_name().map_or(false, |cached| cached.eq_ignore_ascii_case(&user_name));
+            if same_account && login.site.parent == parsed.parent && login.config == config && !login.authorization().is_expiring() {
+                return Box::new(future::ok(login.with_credentials(user_name, password)));
+            }
+        }
+        let cache = cache.clone();
+        Box::new(self.login_with_config(site, user_name, password, config).map(move |login| {
+            if let Err(e) = cache.store(&login) {
+                warn!("cannot store the login context in {}: {}", cache.path().display(), e);
+            }
+            login
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::tests::{fake_login, respond_to_login};
+    use auth::{AccessTokenCookies, Authorization, RequestDigest};
+    use on_premises::WindowsCredentials;
+    use std::env;
+    use std::time::{Duration, SystemTime};
+    use transport::tests::FakeTransport;
+
+    fn cache_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("sharepoint-cache-{}-{}.json", name, ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn login_context_round_trips_through_json() {
+        let login = fake_login(&FakeTransport::default());
+        let json = serde_json::to_string(&login).unwrap();
+        assert!(!json.contains("secret"));
+
+        let restored: LoginContext = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.site.parent, login.site.parent);
+        assert_eq!(restored.config, login.config);
+        assert_eq!(restored.access_token().unwrap().fed_auth, Some("fed-auth-cookie".to_string()));
+        let digest = restored.request_digest();
+        assert_eq!(digest.content, login.request_digest().content);
+        assert_eq!(digest.valid_until, login.request_digest().valid_until);
+    }
+
+    #[test]
+    fn windows_accounts_are_not_serialized() {
+        let login = LoginContext::new(
+            Site { parent: "https://sharepoint.company.local/".to_string() },
+            Authorization::Ntlm(WindowsCredentials::new("COMPANY", "user", "p@ss")),
+            RequestDigest { content: "0xDIGEST".to_string(), valid_until: None },
+            LoginConfig::default(),
+        );
+        assert!(serde_json::to_string(&login).is_err());
+    }
+
+    #[test]
+    fn stores_and_loads_plain_and_encrypted_contexts() {
+        let login = fake_login(&FakeTransport::default());
+
+        let plain = TokenCache::new(cache_path("plain"));
+        assert!(plain.load().unwrap().is_none());
+        plain.store(&login).unwrap();
+        assert_eq!(plain.load().unwrap().unwrap().access_token().unwrap().rt_fa, Some("rt-fa-cookie".to_string()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(plain.path()).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        plain.clear().unwrap();
+        assert!(plain.load().unwrap().is_none());
+
+        let encrypted = TokenCache::new(cache_path("encrypted")).encrypted(b"correct horse");
+        encrypted.store(&login).unwrap();
+        let content = String::from_utf8(fs::read(encrypted.path()).unwrap()).unwrap();
+        assert!(!content.contains("fed-auth-cookie"));
+        assert_eq!(encrypted.load().unwrap().unwrap().request_digest().content, login.request_digest().content);
+
+        match TokenCache::new(encrypted.path()).encrypted(b"wrong").load() {
+            Err(Error::TokenCache(_)) => {}
+            res => panic!("expected a decryption error, got {:?}", res.map(|_| ())),
+        }
+        encrypted.clear().unwrap();
+    }
+
+    #[test]
+    fn cached_context_is_reused_while_valid() {
+        let cache = TokenCache::new(cache_path("reuse"));
+        let site = "https://company.sharepoint.com/sites/test/".to_string();
+        cache.store(&fake_login(&FakeTransport::default())).unwrap();
+
+        let transport = FakeTransport::default();
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        let login = client
+            .login_with_cache(&cache, site.clone(), "user@company.onmicrosoft.com".to_string(), "secret".to_string(), LoginConfig::default())
+            .unwrap();
+        assert_eq!(login.access_token().unwrap().fed_auth, Some("fed-auth-cookie".to_string()));
+        assert_eq!(transport.requests.borrow().len(), 0);
+
+        // expired cookies are signed in again and the cache is updated
+        let expired = fake_login(&FakeTransport::default());
+        expired.set_session(
+            Authorization::Cookies(AccessTokenCookies {
+                rt_fa: Some("old".to_string()),
+                fed_auth: Some("old".to_string()),
+                valid_until: Some(SystemTime::now() - Duration::from_secs(1)),
+            }),
+            expired.request_digest(),
+        );
+        cache.store(&expired).unwrap();
+        respond_to_login(&transport);
+        let login = client
+            .login_with_cache(&cache, site, "user@company.onmicrosoft.com".to_string(), "secret".to_string(), LoginConfig::default())
+            .unwrap();
+        assert_eq!(transport.requests.borrow().len(), 4);
+        assert_eq!(login.access_token().unwrap().fed_auth, Some("fed-auth-cookie".to_string()));
+        assert!(cache.load().unwrap().unwrap().access_token().unwrap().valid_until.is_none());
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn cached_context_of_another_account_is_not_reused() {
+        let cache = TokenCache::new(cache_path("account"));
+        let site = "https://company.sharepoint.com/sites/test/".to_string();
+        cache.store(&fake_login(&FakeTransport::default())).unwrap();
+        assert_eq!(cache.load().unwrap().unwrap().user_name(), Some("user@company.onmicrosoft.com"));
+
+        let transport = FakeTransport::default();
+        respond_to_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        let login = client
+            .login_with_cache(&cache, site, "other@company.onmicrosoft.com".to_string(), "secret".to_string(), LoginConfig::default())
+            .unwrap();
+        assert_eq!(transport.requests.borrow().len(), 4);
+        assert_eq!(login.user_name(), Some("other@company.onmicrosoft.com"));
+        assert_eq!(cache.load().unwrap().unwrap().user_name(), Some("other@company.onmicrosoft.com"));
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn context_is_returned_when_the_cache_cannot_be_written() {
+        use logging::tests::{capture, captured};
+
+        capture();
+        let cache = TokenCache::new(env::temp_dir().join("sharepoint-missing-directory").join("tokens.json"));
+        let transport = FakeTransport::default();
+        respond_to_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        let login = client
+            .login_with_cache(
+                &cache,
+                "https://company.sharepoint.com/sites/test/".to_string(),
+                "user@company.onmicrosoft.com".to_string(),
+                "secret".to_string(),
+                LoginConfig::default(),
+            )
+            .unwrap();
+        assert_eq!(login.access_token().unwrap().fed_auth, Some("fed-auth-cookie".to_string()));
+        assert!(captured().contains("cannot store the login context"));
+    }
+}
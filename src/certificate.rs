@@ -0,0 +1,294 @@
+//! App-only authentication with a certificate registered on an Azure AD app: the token
+//! request carries an RS256 client assertion signed with the certificate's private key,
+//! which SharePoint Online requires for app-only REST calls.
+extern crate futures;
+extern crate openssl;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::futures::{future, Future};
+use self::openssl::hash::MessageDigest;
+use self::openssl::pkcs12::Pkcs12;
+use self::openssl::pkey::{PKey, Private};
+use self::openssl::rand::rand_bytes;
+use self::openssl::sign::Signer;
+use self::openssl::x509::X509;
+
+#[allow(unused_imports)]
+use super::*;
+
+use auth::{parse_site, Authorization, Credentials, LoginConfig, LoginContext, RequestDigest};
+use client::{block_on, Client, SharePointClient, SharePointFuture};
+use error::{Error, Result};
+
+static CLIENT_ASSERTION_TYPE: &'static str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Lifetime of a client assertion, it is only used for one token request.
+const CLIENT_ASSERTION_LIFETIME_SECONDS: u64 = 600;
+
+/// Certificate of an Azure AD app together with its private key.
+#[derive(Clone)]
+pub struct Certificate {
+    key: PKey<Private>,
+    thumbprint: Vec<u8>,
+}
+
+impl Certificate {
+    /// Reads the certificate and its private key from PEM; both may be the same file.
+    pub fn from_pem(certificate: &[u8], private_key: &[u8]) -> Result<Certificate> {
+        Certificate::new(&X509::from_pem(certificate)?, PKey::private_key_from_pem(private_key)?)
+    }
+
+    /// Reads the certificate and its private key from a PFX (PKCS #12) archive.
+    pub fn from_pfx(der: &[u8], password: &str) -> Result<Certificate> {
+        let parsed = Pkcs12::from_der(der)?.parse2(password)?;
+        match (parsed.cert, parsed.pkey) {
+            (Some(certificate), Some(key)) => Certificate::new(&certificate, key),
+            _ => Err(Error::Certificate("the PFX archive lacks the certificate or its private key".to_string())),
+        }
+    }
+
+    fn new(certificate: &X509, key: PKey<Private>) -> Result<Certificate> {
+        if !certificate.public_key()?.public_eq(&key) {
+            return Err(Error::Certificate("the private key does not belong to the certificate".to_string()));
+        }
+        Ok(Certificate {
+            key: key,
+            thumbprint: certificate.digest(MessageDigest::sha1())?.to_vec(),
+        })
+    }
+
+    /// Base64url SHA-1 thumbprint of the certificate, sent as `x5t` in the assertion header.
+    pub fn x5t(&self) -> String {
+        base64url(&self.thumbprint)
+    }
+
+    /// Client assertion JWT identifying app `client_id` to the token endpoint `audience`.
+    pub fn client_assertion(&self, client_id: &str, audience: &str) -> Result<String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut jti = [0u8; 16];
+        rand_bytes(&mut jti)?;
+        let header = json!({"alg": "RS256", "typ": "JWT", "x5t": self.x5t()});
+        let claims = json!({
+            "aud": audience,
+            "iss": client_id,
+            "sub": client_id,
+            "jti": jti.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "nbf": now,
+            "exp": now + CLIENT_ASSERTION_LIFETIME_SECONDS,
+        });
+        let signed = format!("{}.{}", base64url(header.to_string().as_bytes()), base64url(claims.to_string().as_bytes()));
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(signed.as_bytes())?;
+        let signature = signer.sign_to_vec()?;
+        Ok(format!("{}.{}", signed, base64url(&signature)))
+    }
+}
+
+fn base64url(data: &[u8]) -> String {
+    openssl::base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+/// Logs in to `site` as the Azure AD app `client_id` of `tenant`, proving its identity with `certificate`.
+pub fn login_with_certificate(
+    site: String,
+    tenant: String,
+    client_id: String,
+    certificate: Certificate,
+    config: LoginConfig,
+) -> Result<LoginContext> {
+    block_on(|client| client.login_with_certificate(site, tenant, client_id, certificate, config))
+}
+
+impl SharePointClient {
+    /// `certificate::login_with_certificate` reusing this client's connections.
+    pub fn login_with_certificate(
+        &mut self,
+        site: String,
+        tenant: String,
+        client_id: String,
+        certificate: Certificate,
+        config: LoginConfig,
+    ) -> Result<LoginContext> {
+        let work = self.client().login_with_certificate(site, tenant, client_id, certificate, config);
+        self.run(work)
+    }
+}
+
+impl Client {
+    /// Asynchronous `certificate::login_with_certificate`.
+    pub fn login_with_certificate(
+        &self,
+        site: String,
+        tenant: String,
+        client_id: String,
+        certificate: Certificate,
+        config: LoginConfig,
+    ) -> SharePointFuture<LoginContext> {
+        let site = match parse_site(&site) {
+            Ok(site) => site,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let credentials = Credentials::Certificate {
+            tenant: tenant.to_owned(),
+            client_id: client_id.to_owned(),
+            certificate: certificate.clone(),
+        };
+        Box::new(
+            self.sign_in_with_certificate(&config, site.clone(), tenant, client_id, certificate)
+                .map(move |(authorization, digest)| LoginContext::new(site, authorization, digest, config).with_renewal(credentials)),
+        )
+    }
+
+    /// Gets an app-only token with a fresh client assertion and a request digest with it.
+    pub(crate) fn sign_in_with_certificate(
+        &self,
+        config: &LoginConfig,
+        site: Site,
+        tenant: String,
+        client_id: String,
+        certificate: Certificate,
+    ) -> SharePointFuture<(Authorization, RequestDigest)> {
+        let assertion = match certificate.client_assertion(&client_id, &config.token_url.replace("{tenant}", &tenant)) {
+            Ok(assertion) => assertion,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        self.sign_in_with_app_credentials(
+            config,
+            site,
+            &tenant,
+            &[
+                ("client_id", &client_id),
+                ("client_assertion_type", CLIENT_ASSERTION_TYPE),
+                ("client_assertion", &assertion),
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::openssl::asn1::Asn1Time;
+    use self::openssl::bn::BigNum;
+    use self::openssl::rsa::Rsa;
+    use self::openssl::sign::Verifier;
+    use self::openssl::x509::X509Name;
+    use self::serde_json::Value;
+    use auth::tests::CONTEXT_INFO_RESPONSE;
+    use hyper::StatusCode;
+    use transport::tests::FakeTransport;
+
+    fn self_signed() -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "sharepoint-rs test").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    fn unbase64url(part: &str) -> Vec<u8> {
+        let mut padded = part.replace('-', "+").replace('_', "/");
+        while padded.len() % 4 != 0 {
+            padded.push('=');
+        }
+        openssl::base64::decode_block(&padded).unwrap()
+    }
+
+    fn decode(part: &str) -> Value {
+        serde_json::from_slice(&unbase64url(part)).unwrap()
+    }
+
+    #[test]
+    fn signs_client_assertion() {
+        let (x509, key) = self_signed();
+        let certificate = Certificate::from_pem(&x509.to_pem().unwrap(), &key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let assertion = certificate.client_assertion("client-id", "https://login.microsoftonline.com/t/oauth2/v2.0/token").unwrap();
+
+        let parts: Vec<&str> = assertion.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        let header = decode(parts[0]);
+        assert_eq!(header["alg"], "RS256");
+        assert_eq!(header["x5t"], base64url(&x509.digest(MessageDigest::sha1()).unwrap()).as_str());
+        let claims = decode(parts[1]);
+        assert_eq!(claims["aud"], "https://login.microsoftonline.com/t/oauth2/v2.0/token");
+        assert_eq!(claims["iss"], "client-id");
+        assert_eq!(claims["sub"], "client-id");
+        assert_eq!(claims["exp"].as_u64().unwrap() - claims["nbf"].as_u64().unwrap(), 600);
+
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key).unwrap();
+        verifier.update(format!("{}.{}", parts[0], parts[1]).as_bytes()).unwrap();
+        assert!(verifier.verify(&unbase64url(parts[2])).unwrap());
+    }
+
+    #[test]
+    fn reads_pfx_and_rejects_foreign_key() {
+        let (x509, key) = self_signed();
+        let pfx = Pkcs12::builder().name("test").pkey(&key).cert(&x509).build2("pfx-password").unwrap();
+        let certificate = Certificate::from_pfx(&pfx.to_der().unwrap(), "pfx-password").unwrap();
+        assert_eq!(certificate.x5t(), base64url(&x509.digest(MessageDigest::sha1()).unwrap()));
+
+        let (_, other_key) = self_signed();
+        match Certificate::from_pem(&x509.to_pem().unwrap(), &other_key.private_key_to_pem_pkcs8().unwrap()) {
+            Err(Error::Certificate(_)) => {}
+            res => panic!("expected a certificate error, got {:?}", res.map(|c| c.x5t())),
+        }
+    }
+
+    #[test]
+    fn certificate_login_sends_client_assertion() {
+        let (x509, key) = self_signed();
+        let certificate = Certificate::from_pem(&x509.to_pem().unwrap(), &key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let transport = FakeTransport::default();
+        transport
+            .respond(StatusCode::Ok, r#"{"token_type":"Bearer","expires_in":3599,"access_token":"cert-token"}"#)
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        client.login_with_certificate(
+            "https://company.sharepoint.com/sites/test/".to_string(),
+            "company.onmicrosoft.com".to_string(),
+            "client-id".to_string(),
+            certificate,
+            LoginConfig::default(),
+        ).unwrap();
+
+        let token = transport.request(0);
+        assert_eq!(token.url, "https://login.microsoftonline.com/company.onmicrosoft.com/oauth2/v2.0/token");
+        assert!(token.body.starts_with("grant_type=client_credentials&client_id=client-id&client_assertion_type=urn%3Aietf%3Aparams%3Aoauth%3Aclient-assertion-type%3Ajwt-bearer&client_assertion=ey"));
+        assert!(!token.body.contains("client_secret"));
+        assert_eq!(transport.request(1).header("Authorization"), Some("Bearer cert-token"));
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[test]
+    fn certificate_login_against_mock_server() {
+        use mock::MockServer;
+
+        let (x509, key) = self_signed();
+        let certificate = Certificate::from_pem(&x509.to_pem().unwrap(), &key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let server = MockServer::start();
+        server.create_list("Tasks");
+        let login = login_with_certificate(
+            server.site_url(),
+            "mock.onmicrosoft.com".to_string(),
+            MockServer::CLIENT_ID.to_string(),
+            certificate,
+            server.login_config(),
+        ).unwrap();
+
+        server.expire_sessions();
+        assert!(list::get_list_by_title("Tasks".to_string(), login).is_ok());
+    }
+}
@@ -0,0 +1,298 @@
+extern crate futures;
+extern crate tokio_core;
+
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use self::futures::future::Either;
+use self::futures::sync::oneshot;
+use self::futures::{future, Future};
+use self::tokio_core::reactor::{Core, Handle};
+
+use connection::ConnectionConfig;
+use error::{Error, Result};
+use logging;
+use ntlm;
+use retry::RetryPolicy;
+use transport::{HyperTransport, Timeouts, Transport, TransportRequest, TransportResponse};
+
+/// Future returned by the asynchronous `Client` methods.
+pub type SharePointFuture<T> = Box<dyn Future<Item = T, Error = Error>>;
+
+/// One attempt at a request, see `Client::send_with`.
+pub(crate) type Exchange = Rc<dyn Fn(TransportRequest) -> SharePointFuture<TransportResponse>>;
+
+/// Asynchronous SharePoint client running on the caller's `tokio_core` event loop.
+///
+/// The methods (`login`, `get_list_items_by_title`, `add_list_item_by_list_title`, ...)
+/// are defined next to their blocking counterparts in `auth` and `list` and return
+/// futures, so many requests can be in flight at the same time.
+///
+/// Dropping a future cancels the call; see also `cancellable`.
+#[derive(Clone)]
+pub struct Client {
+    pub(crate) transport: Rc<dyn Transport>,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+    pub(crate) ntlm_connections: ntlm::Connections,
+}
+
+impl Client {
+    /// Creates a client sending requests with `HyperTransport` driven by `handle`.
+    pub fn new(handle: &Handle) -> Result<Client> {
+        Client::new_with_timeouts(handle, Timeouts::default())
+    }
+
+    /// `Client::new` with `timeouts` instead of `Timeouts::default()`.
+    pub fn new_with_timeouts(handle: &Handle, timeouts: Timeouts) -> Result<Client> {
+        Ok(Client::with_transport(HyperTransport::with_timeouts(handle, timeouts)?).with_timeouts(timeouts))
+    }
+
+    /// `Client::new` connecting through a proxy or with TLS settings of `config`.
+    pub fn new_with_config(handle: &Handle, config: ConnectionConfig) -> Result<Client> {
+        let timeouts = config.timeouts;
+        Ok(Client::with_transport(HyperTransport::with_config(handle, config)?).with_timeouts(timeouts))
+    }
+
+    /// Creates a client sending all requests through `transport`.
+    pub fn with_transport<T>(transport: T) -> Client
+    where
+        T: Transport + 'static,
+    {
+        Client {
+            transport: Rc::new(transport),
+            retry: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            ntlm_connections: ntlm::Connections::default(),
+        }
+    }
+
+    /// Gives the requests sent by this client the `read` and `total` of `timeouts`, e.g. on a
+    /// clone for one slow call. The `connect` timeout belongs to the transport.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Client {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Retries throttled and failed requests according to `policy` instead of `RetryPolicy::default()`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Client {
+        self.retry = policy;
+        self
+    }
+
+    /// Sends `request` through the transport and logs it, leaving out `secret` bodies; retries
+    /// it as the retry policy allows, all attempts within the `total` timeout.
+    pub(crate) fn send(&self, request: TransportRequest, secret: bool) -> SharePointFuture<TransportResponse> {
+        let transport = self.transport.clone();
+        self.send_with(request, secret, Rc::new(move |request| transport.send(request)))
+    }
+
+    /// `send` carrying out every attempt with `exchange`, e.g. a whole authentication handshake.
+    pub(crate) fn send_with(&self, mut request: TransportRequest, secret: bool, exchange: Exchange) -> SharePointFuture<TransportResponse> {
+        request.timeouts = Timeouts { total: None, ..self.timeouts };
+        let url = request.url.clone();
+        let attempts = self.send_attempt(request, secret, exchange, 1);
+        let total = match self.timeouts.total {
+            Some(total) => total,
+            None => return attempts,
+        };
+        Box::new(attempts.select2(self.transport.delay(total)).then(move |res| match res {
+            Ok(Either::A((res, _))) => Ok(res),
+            Ok(Either::B(_)) => Err(Error::Timeout { url: url, after: total }),
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+        }))
+    }
+
+    fn send_attempt(&self, request: TransportRequest, secret: bool, exchange: Exchange, attempt: u32) -> SharePointFuture<TransportResponse> {
+        logging::log_request(&request, secret);
+        let client = self.clone();
+        Box::new(exchange(request.clone()).then(move |res| -> SharePointFuture<TransportResponse> {
+            if let Ok(ref res) = res {
+                logging::log_response(&request.method, &request.url, res, secret);
+            }
+            match client.retry.delay(&request, attempt, &res) {
+                Some(delay) => {
+                    debug!("retrying {} {} in {:?}, attempt {} failed", request.method, request.url, delay, attempt);
+                    let again = client.clone();
+                    Box::new(client.transport.delay(delay).and_then(move |_| again.send_attempt(request, secret, exchange, attempt + 1)))
+                }
+                None => Box::new(future::result(res)),
+            }
+        }))
+    }
+}
+
+/// Blocking SharePoint client owning its event loop and connection pool.
+///
+/// The free functions in `auth` and `list` open new connections on every call; calls made
+/// through one `SharePointClient` reuse the kept-alive connections instead, which matters
+/// for bulk work such as importing many list items.
+pub struct SharePointClient {
+    core: Core,
+    client: Client,
+}
+
+impl SharePointClient {
+    /// Creates a client with its own event loop.
+    pub fn new() -> Result<SharePointClient> {
+        SharePointClient::new_with_timeouts(Timeouts::default())
+    }
+
+    /// `SharePointClient::new` with `timeouts` instead of `Timeouts::default()`.
+    pub fn new_with_timeouts(timeouts: Timeouts) -> Result<SharePointClient> {
+        SharePointClient::new_with_config(ConnectionConfig {
+            timeouts: timeouts,
+            ..ConnectionConfig::default()
+        })
+    }
+
+    /// `SharePointClient::new` connecting through a proxy or with TLS settings of `config`.
+    pub fn new_with_config(config: ConnectionConfig) -> Result<SharePointClient> {
+        let core = Core::new()?;
+        let client = Client::new_with_config(&core.handle(), config)?;
+        Ok(SharePointClient {
+            core: core,
+            client: client,
+        })
+    }
+
+    /// Creates a client with its own event loop sending all requests through `transport`.
+    pub fn with_transport<T>(transport: T) -> Result<SharePointClient>
+    where
+        T: Transport + 'static,
+    {
+        Ok(SharePointClient {
+            core: Core::new()?,
+            client: Client::with_transport(transport),
+        })
+    }
+
+    /// Retries throttled and failed requests according to `policy`, see `Client::with_retry_policy`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> SharePointClient {
+        self.client = self.client.with_retry_policy(policy);
+        self
+    }
+
+    /// Gives the requests the `read` and `total` of `timeouts`, see `Client::with_timeouts`.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> SharePointClient {
+        self.client = self.client.with_timeouts(timeouts);
+        self
+    }
+
+    /// The asynchronous client sharing this client's connections.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Runs any future created from `client()` to completion.
+    pub fn run<T>(&mut self, work: SharePointFuture<T>) -> Result<T> {
+        self.core.run(work)
+    }
+}
+
+/// Cancels the call wrapped by `cancellable`, also from another thread.
+#[derive(Clone)]
+pub struct CancelHandle {
+    sender: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+}
+
+impl CancelHandle {
+    /// Stops the call: its future fails with `Error::Cancelled` and the request in flight is
+    /// dropped. Does nothing once the call is done.
+    pub fn cancel(&self) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+/// `work`, which can be cancelled through the returned `CancelHandle`.
+pub fn cancellable<T>(work: SharePointFuture<T>) -> (SharePointFuture<T>, CancelHandle)
+where
+    T: 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    let handle = CancelHandle {
+        sender: Arc::new(Mutex::new(Some(sender))),
+    };
+    let work = work.select2(receiver).then(|res| -> SharePointFuture<T> {
+        match res {
+            Ok(Either::A((item, _))) => Box::new(future::ok(item)),
+            Err(Either::A((e, _))) => Box::new(future::err(e)),
+            Ok(Either::B(_)) => Box::new(future::err(Error::Cancelled)),
+            // every handle was dropped without cancelling, the call goes on
+            Err(Either::B((_, work))) => work,
+        }
+    });
+    (Box::new(work), handle)
+}
+
+/// Runs one asynchronous call to completion on a fresh client; backs the free blocking functions.
+pub fn block_on<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce(&Client) -> SharePointFuture<T>,
+{
+    let mut client = SharePointClient::new()?;
+    let work = f(client.client());
+    client.run(work)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn cancelled_call_fails() {
+        let mut core = Core::new().unwrap();
+        let (work, handle) = cancellable::<()>(Box::new(future::empty()));
+        let cancel = handle.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+        });
+        match core.run(work) {
+            Err(Error::Cancelled) => {}
+            res => panic!("expected the call to be cancelled, got {:?}", res),
+        }
+        handle.cancel();
+    }
+
+    #[test]
+    fn total_timeout_covers_all_attempts() {
+        use hyper::Method;
+        use std::time::Instant;
+        use transport::tests::stalling_server;
+
+        let mut core = Core::new().unwrap();
+        let timeouts = Timeouts { read: Some(Duration::from_millis(200)), total: Some(Duration::from_millis(500)), ..Timeouts::default() };
+        let client = Client::new_with_timeouts(&core.handle(), timeouts).unwrap().with_retry_policy(RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        });
+        let url = stalling_server("");
+        let request = TransportRequest {
+            method: Method::Get,
+            url: url,
+            headers: Vec::new(),
+            body: String::new(),
+            timeouts: Timeouts::default(),
+        };
+        let started = Instant::now();
+        match core.run(client.send(request, false)) {
+            Err(Error::Timeout { after, .. }) => assert_eq!(after, Duration::from_millis(500)),
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+        assert!(started.elapsed() < Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn call_goes_on_without_handle() {
+        let mut core = Core::new().unwrap();
+        let (work, handle) = cancellable(Box::new(future::ok(42)));
+        drop(handle);
+        assert_eq!(core.run(work).unwrap(), 42);
+    }
+}
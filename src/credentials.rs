@@ -0,0 +1,204 @@
+//! Where the user name and password of a password login come from.
+//!
+//! A `CredentialProvider` is asked for the credentials when signing in, and again every time
+//! the session has to be renewed, so a rotated password is picked up without restarting. It
+//! answers with a future, so a provider fetching them from a vault does not stall other requests.
+//!
+//! ```no_run
+//! # extern crate sharepoint;
+//! # use sharepoint::auth::{self, LoginConfig};
+//! # use sharepoint::credentials::{EnvCredentials, FileCredentials, UserCredentials};
+//! # fn main() {
+//! let site = "https://company.sharepoint.com/sites/test/".to_string();
+//! let login = auth::login_with_provider(site.clone(), EnvCredentials::default(), LoginConfig::default()).unwrap();
+//! let login = auth::login_with_provider(site.clone(), FileCredentials::new("/etc/sharepoint/credentials.json"), LoginConfig::default()).unwrap();
+//! let login = auth::login_with_provider(site, || Ok(UserCredentials::new("user@company.onmicrosoft.com", "secret")), LoginConfig::default()).unwrap();
+//! # }
+//! ```
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+extern crate futures;
+
+#[allow(unused_imports)]
+use super::*;
+
+use self::futures::future;
+
+use client::SharePointFuture;
+use error::{Error, Result};
+
+/// User name and password of an Office 365 account.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserCredentials {
+    pub user_name: String,
+    pub password: String,
+}
+
+impl UserCredentials {
+    pub fn new<U: Into<String>, P: Into<String>>(user_name: U, password: P) -> UserCredentials {
+        UserCredentials {
+            user_name: user_name.into(),
+            password: password.into(),
+        }
+    }
+}
+
+impl fmt::Debug for UserCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserCredentials")
+            .field("user_name", &self.user_name)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+/// Source of the credentials for `auth::login_with_provider`.
+///
+/// Closures returning `Result<UserCredentials>` are providers too, e.g. returning what a vault agent keeps up to date.
+///
+/// `credentials` is called on the event loop that sends the requests; a provider that has to
+/// wait, e.g. for a vault on the network, returns a future that resolves later instead of
+/// blocking the loop.
+pub trait CredentialProvider: Send + Sync {
+    /// The credentials to sign in with now.
+    fn credentials(&self) -> SharePointFuture<UserCredentials>;
+}
+
+impl CredentialProvider for UserCredentials {
+    fn credentials(&self) -> SharePointFuture<UserCredentials> {
+        Box::new(future::ok(self.clone()))
+    }
+}
+
+impl<F> CredentialProvider for F
+where
+    F: Fn() -> Result<UserCredentials> + Send + Sync,
+{
+    fn credentials(&self) -> SharePointFuture<UserCredentials> {
+        Box::new(future::result(self()))
+    }
+}
+
+/// Reads the credentials from two environment variables, `RUST_USERNAME` and `RUST_PASSWORD`
+/// by default.
+#[derive(Debug, Clone)]
+pub struct EnvCredentials {
+    pub user_name_var: String,
+    pub password_var: String,
+}
+
+impl EnvCredentials {
+    pub fn new<U: Into<String>, P: Into<String>>(user_name_var: U, password_var: P) -> EnvCredentials {
+        EnvCredentials {
+            user_name_var: user_name_var.into(),
+            password_var: password_var.into(),
+        }
+    }
+}
+
+impl Default for EnvCredentials {
+    fn default() -> EnvCredentials {
+        EnvCredentials::new("RUST_USERNAME", "RUST_PASSWORD")
+    }
+}
+
+fn env_var(name: &str) -> Result<String> {
+    env::var(name).map_err(|e| Error::Credentials(format!("{}: {}", name, e)))
+}
+
+impl EnvCredentials {
+    fn read(&self) -> Result<UserCredentials> {
+        Ok(UserCredentials {
+            user_name: env_var(&self.user_name_var)?,
+            password: env_var(&self.password_var)?,
+        })
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn credentials(&self) -> SharePointFuture<UserCredentials> {
+        Box::new(future::result(self.read()))
+    }
+}
+
+/// Reads the credentials from a JSON file such as
+/// `{"user_name": "user@company.onmicrosoft.com", "password": "secret"}`,
+/// every time they are needed.
+#[derive(Debug, Clone)]
+pub struct FileCredentials {
+    path: PathBuf,
+}
+
+impl FileCredentials {
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileCredentials {
+        FileCredentials { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl FileCredentials {
+    fn read(&self) -> Result<UserCredentials> {
+        let content = fs::read(&self.path)
+            .map_err(|e| Error::Credentials(format!("{}: {}", self.path.display(), e)))?;
+        serde_json::from_slice(&content).map_err(|e| Error::Credentials(format!("{}: {}", self.path.display(), e)))
+    }
+}
+
+/// Reads the small local file right away, on the event loop.
+impl CredentialProvider for FileCredentials {
+    fn credentials(&self) -> SharePointFuture<UserCredentials> {
+        Box::new(future::result(self.read()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::futures::Future;
+
+    #[test]
+    fn env_credentials_are_read_when_asked() {
+        let provider = EnvCredentials::new("SHAREPOINT_TEST_USER", "SHAREPOINT_TEST_PASSWORD");
+        env::remove_var("SHAREPOINT_TEST_PASSWORD");
+        env::set_var("SHAREPOINT_TEST_USER", "user@company.onmicrosoft.com");
+        match provider.credentials().wait() {
+            Err(Error::Credentials(e)) => assert!(e.starts_with("SHAREPOINT_TEST_PASSWORD")),
+            res => panic!("expected a missing variable, got {:?}", res),
+        }
+        env::set_var("SHAREPOINT_TEST_PASSWORD", "secret");
+        assert_eq!(provider.credentials().wait().unwrap(), UserCredentials::new("user@company.onmicrosoft.com", "secret"));
+    }
+
+    #[test]
+    fn file_credentials_are_read_again_every_time() {
+        let path = env::temp_dir().join(format!("sharepoint-credentials-{}.json", ::std::process::id()));
+        let provider = FileCredentials::new(path.clone());
+        assert!(provider.credentials().wait().is_err());
+
+        fs::write(&path, r#"{"user_name": "user@company.onmicrosoft.com", "password": "old"}"#).unwrap();
+        assert_eq!(provider.credentials().wait().unwrap().password, "old");
+        fs::write(&path, r#"{"user_name": "user@company.onmicrosoft.com", "password": "new"}"#).unwrap();
+        assert_eq!(provider.credentials().wait().unwrap().password, "new");
+
+        fs::write(&path, "user:password").unwrap();
+        match provider.credentials().wait() {
+            Err(Error::Credentials(_)) => {}
+            res => panic!("expected an invalid file, got {:?}", res),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn passwords_are_not_printed() {
+        let credentials = UserCredentials::new("user@company.onmicrosoft.com", "secret");
+        assert!(!format!("{:?}", credentials).contains("secret"));
+        let closure = || Ok(UserCredentials::new("vault-user", "vault-secret"));
+        assert_eq!(closure.credentials().wait().unwrap().user_name, "vault-user");
+    }
+}
@@ -1,191 +1,475 @@
 extern crate serde;
-extern crate tokio_core;
-extern crate hyper_tls;
 
 extern crate futures;
 
 use auth::*;
+use client::{Client, SharePointFuture};
+use error::{Error, ODataError, Result};
+use transport::{Timeouts, TransportRequest, TransportResponse};
 use self::futures::future;
 
-use hyper::{Method, Request};
+use hyper::{Headers, Method, StatusCode, Uri};
 
 #[allow(unused_imports)]
 use super::*;
 
 use self::serde::de::DeserializeOwned;
 use self::serde::ser::Serialize;
-use hyper::header::{ContentLength, ContentType, SetCookie, Accept, qitem, Cookie};
+use hyper::header::{self, ContentLength, ContentType, Accept, qitem, Basic, Bearer, Cookie};
 use hyper::mime;
-use self::futures::{Future, Stream};
+use self::futures::Future;
+
+pub use transport::HeaderItem;
 
 header! { (XRequestDigest, "X-RequestDigest") => [String] }
 header! { (XHttpMethod, "X-Http-Method") => [String] }
 header! { (IfMatch, "If-Match") => [String] }
 
 #[derive(Debug, Deserialize, Default)]
-pub struct HeaderItem {
-    name: String,
+struct ODataErrorMessage {
+    #[serde(default)]
+    lang: String,
+    #[serde(default)]
     value: String,
 }
 
-pub fn process<'a, T>(
+#[derive(Debug, Deserialize, Default)]
+struct ODataErrorDetail {
+    #[serde(default)]
+    code: String,
+    #[serde(default)]
+    message: ODataErrorMessage,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct ODataErrorBody {
+    #[serde(rename = "odata.error", default)]
+    odata_error: Option<ODataErrorDetail>,
+    #[serde(rename = "error", default)]
+    verbose_error: Option<ODataErrorDetail>,
+}
+
+/// Turns a non-success response into an `Error`, preferring the OData error payload if present.
+pub fn status_error(status: StatusCode, body: String) -> Error {
+    let detail = serde_json::from_str::<ODataErrorBody>(&body)
+        .ok()
+        .and_then(|e| e.odata_error.or(e.verbose_error));
+    match detail {
+        Some(e) => Error::OData(ODataError {
+            status: status,
+            code: e.code,
+            lang: e.message.lang,
+            message: e.message.value,
+        }),
+        None => Error::Status {
+            status: status,
+            body: body,
+        },
+    }
+}
+
+/// Whether SharePoint answered a request sent with cookies by asking to sign in again: a 401,
+/// a 403 naming the sign-in form, or a redirect to the sign-in form or service.
+fn session_expired(res: &TransportResponse) -> bool {
+    match res.status {
+        StatusCode::Unauthorized => true,
+        StatusCode::Forbidden => res.header("X-Forms_Based_Auth_Required").is_some(),
+        StatusCode::Found => res.header("Location").map_or(false, redirects_to_sign_in),
+        _ => false,
+    }
+}
+
+fn redirects_to_sign_in(location: &str) -> bool {
+    let location: Uri = match location.parse() {
+        Ok(location) => location,
+        Err(_) => return false,
+    };
+    let path = location.path().to_lowercase();
+    path.contains("/_forms/") || path.ends_with("/_layouts/15/authenticate.aspx") || location.host().map_or(false, is_login_host)
+}
+
+/// Sends one request through `client` and parses the answer with `parser`; the bodies of a
+/// `secret` request, such as a login, are not logged.
+pub fn request<T>(
+    client: &Client,
     url: String,
     body: String,
-    access_token_cookies: Option<AccessTokenCookies>,
-    parser: fn(String, Vec<HeaderItem>, Vec<String>) -> Option<T>,
+    authorization: Option<Authorization>,
+    parser: fn(String, Vec<HeaderItem>, Vec<String>) -> Result<T>,
     json: bool,
     x_request_digest: Option<RequestDigest>,
     method: Method,
     use_merge : bool,
-) -> Option<T>
+    secret: bool,
+) -> SharePointFuture<T>
+where
+    T: 'static,
+{
+    let x_http_method = if use_merge { Some("PATCH") } else { None };
+    request_with_content_type(client, url, body, None, authorization, parser, json, x_request_digest, method, x_http_method, secret)
+}
+
+/// `request` with a body of `content_type` instead of JSON, tunneling `x_http_method`
+/// through `method`.
+fn request_with_content_type<T>(
+    client: &Client,
+    url: String,
+    body: String,
+    content_type: Option<String>,
+    authorization: Option<Authorization>,
+    parser: fn(String, Vec<HeaderItem>, Vec<String>) -> Result<T>,
+    json: bool,
+    x_request_digest: Option<RequestDigest>,
+    method: Method,
+    x_http_method: Option<&'static str>,
+    secret: bool,
+) -> SharePointFuture<T>
 where
-    T: serde::Deserialize<'a>,
+    T: 'static,
 {
-    let mut core = self::tokio_core::reactor::Core::new().unwrap();
-
-    let client = ::hyper::Client::configure()
-        .connector(
-            self::hyper_tls::HttpsConnector::new(4, &core.handle()).unwrap(),
-        )
-        .build(&core.handle());
-
-    let uri = url.parse().unwrap();
-
-    let mut req = Request::new(method, uri);
-    req.set_body(body.to_owned());
-
-    req.headers_mut().set(ContentType::json());
-    req.headers_mut().set(ContentLength(body.len() as u64));
-    if access_token_cookies.is_some() {
-        let atc = access_token_cookies.unwrap();
-        let mut cookie = Cookie::new();
-        let rt_fa = atc.rt_fa.unwrap();
-        let fed_auth = atc.fed_auth.unwrap();
-        cookie.append("rtFa", rt_fa.to_owned());
-        cookie.append("FedAuth", fed_auth.to_owned());
-        println!("rtFa:{}", rt_fa);
-        println!("FedAuth:{}", fed_auth);
-        req.headers_mut().set(cookie);
+    // Windows accounts of on-premises farms do not expire, a 401 means they are refused
+    let renewable = match authorization {
+        Some(Authorization::Cookies(_)) | Some(Authorization::Bearer(_)) => true,
+        _ => false,
+    };
+    let ntlm = match authorization {
+        Some(Authorization::Ntlm(ref credentials)) => Some(credentials.clone()),
+        _ => None,
+    };
+    // only the sign-in answers with a redirect on purpose, to set the session cookies
+    let redirect_allowed = authorization.is_none();
+    let req = match build_request(url, body, content_type, authorization, json, x_request_digest, method, x_http_method) {
+        Ok(req) => req,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let response = match ntlm {
+        Some(credentials) => ntlm::send(client, req, credentials, secret),
+        None => client.send(req, secret),
+    };
+
+    Box::new(response.and_then(move |res| {
+        if renewable && session_expired(&res) {
+            return Err(Error::SessionExpired);
+        }
+        if res.status.is_client_error() || res.status.is_server_error() || (res.status.is_redirection() && !redirect_allowed) {
+            return Err(status_error(res.status, res.body));
+        }
+        parser(res.body, res.headers, res.cookies)
+    }))
+}
+
+fn build_request(
+    url: String,
+    body: String,
+    content_type: Option<String>,
+    authorization: Option<Authorization>,
+    json: bool,
+    x_request_digest: Option<RequestDigest>,
+    method: Method,
+    x_http_method: Option<&'static str>,
+) -> Result<TransportRequest> {
+    let mut headers = Headers::new();
+
+    match content_type {
+        Some(content_type) => headers.set_raw("Content-Type", content_type),
+        None => headers.set(ContentType::json()),
+    }
+    headers.set(ContentLength(body.len() as u64));
+    match authorization {
+        Some(Authorization::Cookies(atc)) => {
+            let mut cookie = Cookie::new();
+            let rt_fa = atc.rt_fa.ok_or(Error::MissingCookies)?;
+            let fed_auth = atc.fed_auth.ok_or(Error::MissingCookies)?;
+            cookie.append("rtFa", rt_fa.to_owned());
+            cookie.append("FedAuth", fed_auth.to_owned());
+            headers.set(cookie);
+        }
+        Some(Authorization::Bearer(token)) => {
+            headers.set(header::Authorization(Bearer { token: token.access_token }));
+        }
+        Some(Authorization::Basic(credentials)) => {
+            if !url.starts_with("https://") {
+                return Err(Error::InsecureBasicAuth(url));
+            }
+            headers.set(header::Authorization(Basic {
+                username: credentials.account(),
+                password: Some(credentials.password),
+            }));
+        }
+        // the NTLM handshake adds its own header
+        Some(Authorization::Ntlm(_)) | None => {}
     };
     if json {
-        req.headers_mut().set(
+        headers.set(
             Accept(vec![qitem(mime::APPLICATION_JSON)]),
         );
     }
-    if x_request_digest.is_some() {
-        let digest = x_request_digest.unwrap().content;
+    if let Some(x_request_digest) = x_request_digest {
+        let digest = x_request_digest.content;
 
-        req.headers_mut().set(XRequestDigest(digest.to_owned()));
-        println!("digest:{}", digest);
+        headers.set(XRequestDigest(digest.to_owned()));
     }
 
-    if use_merge {
-        req.headers_mut().set(XHttpMethod("PATCH".to_string()));
-        req.headers_mut().set(IfMatch("*".to_string()));
+    if let Some(x_http_method) = x_http_method {
+        headers.set(XHttpMethod(x_http_method.to_string()));
+        headers.set(IfMatch("*".to_string()));
     }
 
-    let mut result: Option<T> = None;
-    let mut headers: Vec<HeaderItem> = Vec::new();
-    let mut header_cookies: Vec<String> = Vec::new();
-    {
-        let post = client.request(req).and_then(|res| {
-            headers = res.headers()
-                .iter()
-                .map(|q| {
-                    HeaderItem {
-                        name: q.name().to_string(),
-                        value: q.value_string(),
-                    }
-                })
-                .collect();
-
-            if let Some(&SetCookie(ref cookies)) = res.headers().get() {
-                for cookie in cookies.iter() {
-                    header_cookies.push(cookie.to_string());
-                }
-            }
-
-            res.body()
-                .fold(Vec::new(), |mut v, chunk| {
-                    v.extend(&chunk[..]);
-                    future::ok::<_, hyper::Error>(v)
-                })
-                .and_then(|chunks| {
-                    let s = String::from_utf8(chunks).unwrap();
-                    result = parser(s.to_owned(), headers, header_cookies);
-                    future::ok::<_, hyper::Error>(s)
-                })
-        });
-
-        core.run(post).unwrap();
-    }
-
-    result
+    Ok(TransportRequest {
+        method: method,
+        url: url,
+        headers: headers
+            .iter()
+            .map(|h| HeaderItem {
+                name: h.name().to_string(),
+                value: h.value_string(),
+            })
+            .collect(),
+        body: body,
+        timeouts: Timeouts::default(),
+    })
 }
 
-fn parse_typed_json<T>(body: String, _: Vec<HeaderItem>, _: Vec<String>) -> Option<T>
+fn parse_typed_json<T>(body: String, _: Vec<HeaderItem>, _: Vec<String>) -> Result<T>
 where
     T: DeserializeOwned,
 {
-    println!("JSON Parsing '{:?}'", body.to_owned());
-    let v: T = serde_json::from_str(&body).unwrap();
-    Some(v)
+    let v: T = serde_json::from_str(&body)?;
+    Ok(v)
 }
 
-fn do_not_parse<T>(_: String, _: Vec<HeaderItem>, _: Vec<String>) -> Option<T>
+fn do_not_parse<T>(_: String, _: Vec<HeaderItem>, _: Vec<String>) -> Result<T>
 where
     T: DeserializeOwned + Default,
 {
-    Some(Default::default())
+    Ok(Default::default())
 }
 
 pub fn get_data<T>(
+    client: &Client,
     url: String,
-    access_token_cookies: AccessTokenCookies,
+    authorization: Authorization,
     digest: RequestDigest,
-) -> Option<T>
+) -> SharePointFuture<T>
 where
-    T: DeserializeOwned,
+    T: DeserializeOwned + 'static,
 {
-    process(
+    request(
+        client,
         url,
         "".to_string(),
-        Some(access_token_cookies),
+        Some(authorization),
         parse_typed_json,
         true,
         Some(digest),
         Method::Get,
-        false
+        false,
+        false,
     )
 }
 
-use self::serde_json::Value;
+/// `get_data` with the session of `login`, signing in again if it has expired.
+pub fn get_with_login<T>(client: &Client, url: String, login: &LoginContext) -> SharePointFuture<T>
+where
+    T: DeserializeOwned + 'static,
+{
+    let digest_login = login.clone();
+    let client_for_call = client.clone();
+    client.with_session(login, move |authorization| {
+        get_data(&client_for_call, url.to_owned(), authorization, digest_login.request_digest())
+    })
+}
 
-pub fn post_data<T, U>(
+/// Sends `body` with the session and a valid request digest of `login`, both renewed as needed,
+/// tunneling `x_http_method` through `method`. Without a `content_type` the body is JSON.
+fn send_with_login<T>(
+    client: &Client,
     url: String,
-    access_token_cookies: AccessTokenCookies,
-    digest: RequestDigest,
-    data: U,
-    use_merge: bool, 
-) -> Option<T>
+    login: &LoginContext,
+    method: Method,
+    x_http_method: Option<&'static str>,
+    content_type: Option<String>,
+    body: String,
+    parser: fn(String, Vec<HeaderItem>, Vec<String>) -> Result<T>,
+) -> SharePointFuture<T>
 where
-    T: DeserializeOwned + Default,
+    T: 'static,
+{
+    let json = content_type.is_none();
+    let digest_login = login.clone();
+    let client_for_call = client.clone();
+    client.with_session(login, move |authorization| {
+        let client = client_for_call.clone();
+        let url = url.to_owned();
+        let method = method.clone();
+        let content_type = content_type.clone();
+        let body = body.to_owned();
+        client_for_call.with_request_digest(&digest_login, move |digest| {
+            request_with_content_type(
+                &client,
+                url.to_owned(),
+                body.to_owned(),
+                content_type.clone(),
+                Some(authorization.clone()),
+                parser,
+                json,
+                Some(digest),
+                method.clone(),
+                x_http_method,
+                false,
+            )
+        })
+    })
+}
+
+/// Posts `data` as JSON with the session and a valid request digest of `login`, merging it into
+/// the object at `url` if `use_merge`.
+pub fn post_with_login<T, U>(client: &Client, url: String, login: &LoginContext, data: U, use_merge: bool) -> SharePointFuture<T>
+where
+    T: DeserializeOwned + Default + 'static,
     U: Serialize,
 {
-    let body = serde_json::to_string(&data).unwrap();
+    let data = match serde_json::to_value(&data) {
+        Ok(data) => data,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    let x_http_method = if use_merge { Some("PATCH") } else { None };
+    let parser = if use_merge { do_not_parse } else { parse_typed_json };
+    send_with_login(client, url, login, Method::Post, x_http_method, None, data.to_string(), parser)
+}
 
-    let v: Value = serde_json::from_str(&body).unwrap();
+/// Deletes the object at `url` with the session and a valid request digest of `login`.
+pub fn delete_with_login(client: &Client, url: String, login: &LoginContext) -> SharePointFuture<()> {
+    send_with_login(client, url, login, Method::Post, Some("DELETE"), None, "".to_string(), do_not_parse)
+}
 
-    println!("Will send '{}' to {}", v.to_string().to_owned(), url);
+/// Posts the multipart `body` of a `$batch` request with the session and a valid request
+/// digest of `login`, parsing the multipart answer with `parser`.
+pub fn post_batch_with_login<T>(
+    client: &Client,
+    url: String,
+    login: &LoginContext,
+    content_type: String,
+    body: String,
+    parser: fn(String, Vec<HeaderItem>, Vec<String>) -> Result<T>,
+) -> SharePointFuture<T>
+where
+    T: 'static,
+{
+    send_with_login(client, url, login, Method::Post, None, Some(content_type), body, parser)
+}
 
-    process(
-        url,
-        v.to_string(),
-        Some(access_token_cookies),
-        if use_merge { do_not_parse } else { parse_typed_json },
-        true,
-        Some(digest),
-        Method::Post,
-        use_merge
-    )
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::serde_json::Value;
+    use error::ODataErrorKind;
+
+    fn odata_error(status: StatusCode, body: &str) -> ODataError {
+        match status_error(status, body.to_string()) {
+            Error::OData(e) => e,
+            e => panic!("expected an OData error, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn requests_are_logged_without_secrets() {
+        use auth::tests::fake_login;
+        use client::SharePointClient;
+        use logging;
+        use logging::tests::{capture, captured};
+        use transport::tests::FakeTransport;
+
+        capture();
+        let transport = FakeTransport::default();
+        let login = fake_login(&transport);
+        transport.respond(StatusCode::Created, r#"{"Id":1,"Title":"Logged"}"#);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        logging::set_log_bodies(true);
+        let _: Value = client
+            .add_list_item_by_list_title("Tasks".to_string(), &login, json!({"Title": "Logged"}))
+            .unwrap();
+        logging::set_log_bodies(false);
+
+        let logged = captured();
+        assert!(logged.contains("/_api/contextinfo -> 200 OK"), "{}", logged);
+        assert!(logged.contains("POST https://login.microsoftonline.com/GetUserRealm.srf -> 200 OK"));
+        assert!(logged.contains("-> 201 Created"));
+        assert!(logged.contains("Cookie: <redacted>"));
+        assert!(logged.contains("response sets cookies: rtFa, FedAuth"));
+        assert!(logged.contains(r#"request body: {"Title":"Logged"}"#));
+        for secret in &["secret", "security-token", "rt-fa-cookie", "fed-auth-cookie", "0xDIGEST"] {
+            assert!(!logged.contains(secret), "{} was logged", secret);
+        }
+    }
+
+    #[test]
+    fn only_sign_in_answers_are_expired_sessions() {
+        let response = |status: StatusCode, headers: Vec<(&str, &str)>| TransportResponse {
+            status: status,
+            headers: headers.iter().map(|&(name, value)| HeaderItem { name: name.to_string(), value: value.to_string() }).collect(),
+            cookies: Vec::new(),
+            body: String::new(),
+        };
+        let redirect = |location: &str| response(StatusCode::Found, vec![("Location", location)]);
+
+        assert!(session_expired(&response(StatusCode::Unauthorized, Vec::new())));
+        assert!(session_expired(&response(StatusCode::Forbidden, vec![("X-Forms_Based_Auth_Required", "https://company.sharepoint.com/_forms/default.aspx")])));
+        assert!(session_expired(&redirect("/_forms/default.aspx?ReturnUrl=%2fsites%2ftest")));
+        assert!(session_expired(&redirect("https://company.sharepoint.com/sites/test/_layouts/15/Authenticate.aspx?Source=%2f")));
+        assert!(session_expired(&redirect("https://login.microsoftonline.com/common/oauth2/authorize?client_id=x")));
+
+        assert!(!session_expired(&response(StatusCode::Forbidden, Vec::new())));
+        assert!(!session_expired(&response(StatusCode::Found, Vec::new())));
+        assert!(!session_expired(&redirect("https://company.sharepoint.com/sites/test/SitePages/Home.aspx")));
+        assert!(!session_expired(&response(StatusCode::Ok, vec![("Content-Type", "text/html; charset=utf-8")])));
+    }
+
+    #[test]
+    fn parses_json_light_error() {
+        let e = odata_error(
+            StatusCode::Forbidden,
+            r#"{"odata.error":{"code":"-2130575251, Microsoft.SharePoint.SPException","message":{"lang":"en-US","value":"The security validation for this page is invalid."}}}"#,
+        );
+        assert_eq!(e.number(), Some(-2130575251));
+        assert_eq!(e.exception_type(), Some("Microsoft.SharePoint.SPException"));
+        assert_eq!(e.lang, "en-US");
+        assert_eq!(e.message, "The security validation for this page is invalid.");
+        assert_eq!(e.kind(), ODataErrorKind::InvalidRequestDigest);
+    }
+
+    #[test]
+    fn parses_verbose_error() {
+        let e = odata_error(
+            StatusCode::NotFound,
+            r#"{"error":{"code":"-1, System.ArgumentException","message":{"lang":"en-US","value":"List 'Missing' does not exist at site with URL 'https://company.sharepoint.com'."}}}"#,
+        );
+        assert_eq!(e.code, "-1, System.ArgumentException");
+        assert_eq!(e.kind(), ODataErrorKind::NotFound);
+    }
+
+    #[test]
+    fn classifies_known_errors() {
+        let e = odata_error(
+            StatusCode::PreconditionFailed,
+            r#"{"odata.error":{"code":"-1, Microsoft.SharePoint.Client.ClientServiceException","message":{"lang":"en-US","value":"The request ETag value does not match the object's ETag value."}}}"#,
+        );
+        assert_eq!(e.kind(), ODataErrorKind::VersionConflict);
+        let e = odata_error(
+            StatusCode::Forbidden,
+            r#"{"odata.error":{"code":"-2147024891, System.UnauthorizedAccessException","message":{"lang":"en-US","value":"Access denied."}}}"#,
+        );
+        assert_eq!(e.kind(), ODataErrorKind::AccessDenied);
+    }
+
+    #[test]
+    fn keeps_body_of_non_odata_errors() {
+        match status_error(StatusCode::BadGateway, "<html>Bad gateway</html>".to_string()) {
+            Error::Status { status, body } => {
+                assert_eq!(status, StatusCode::BadGateway);
+                assert_eq!(body, "<html>Bad gateway</html>");
+            }
+            e => panic!("expected a status error, got {:?}", e),
+        }
+    }
 }
@@ -0,0 +1,356 @@
+extern crate hyper_tls;
+#[cfg(feature = "certificate")]
+extern crate openssl;
+extern crate serde_json;
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+use std::time::Duration;
+
+use hyper::StatusCode;
+
+/// Everything that can go wrong when talking to SharePoint.
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent or the response could not be read.
+    Transport(::hyper::Error),
+    /// The TLS connector could not be created.
+    Tls(hyper_tls::Error),
+    /// The event loop driving the request could not be created.
+    Io(io::Error),
+    /// A site or endpoint URL could not be parsed.
+    Uri(String),
+    /// SharePoint answered with a non-success HTTP status and no OData error payload.
+    Status { status: StatusCode, body: String },
+    /// SharePoint answered with an OData error payload.
+    OData(ODataError),
+    /// The security token service rejected the login.
+    SoapFault(SoapFault),
+    /// The Azure AD token endpoint rejected an app-only login.
+    OAuth(OAuthError),
+    /// A certificate or private key could not be read or used for signing.
+    Certificate(String),
+    /// Basic authentication was asked for over plain http, which would expose the password.
+    InsecureBasicAuth(String),
+    /// A `CredentialProvider` could not provide the user name and password.
+    Credentials(String),
+    /// The sign-in response did not set both the `rtFa` and `FedAuth` cookies.
+    MissingCookies,
+    /// A list item type was asked for an empty list name.
+    EmptyListName,
+    /// A value that has no OData literal, such as a `NaN` float.
+    InvalidLiteral(String),
+    /// SharePoint no longer accepts the session cookies and there are no credentials to sign in again.
+    SessionExpired,
+    /// The request to `url` did not complete within the `after` of its `Timeouts`.
+    Timeout { url: String, after: Duration },
+    /// The call was cancelled through its `CancelHandle`.
+    Cancelled,
+    /// A `$batch` response could not be read or has no response to an operation.
+    Batch(String),
+    /// The token cache could not be decrypted, most likely with the wrong key.
+    TokenCache(String),
+    /// An XML response could not be decoded.
+    Xml(String),
+    /// A JSON request or response could not be encoded or decoded.
+    Json(serde_json::Error),
+    /// The response body is not valid UTF-8.
+    Utf8(FromUtf8Error),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Broad classification of a SharePoint error, see `ODataError::kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ODataErrorKind {
+    /// The list, item, file or folder does not exist.
+    NotFound,
+    /// The item was changed by someone else (ETag mismatch or save conflict).
+    VersionConflict,
+    /// The user is not allowed to access the object.
+    AccessDenied,
+    /// The `X-RequestDigest` is missing, invalid or expired.
+    InvalidRequestDigest,
+    /// Anything else, inspect `code` and `message`.
+    Other,
+}
+
+/// Error payload returned by the SharePoint REST API, in either the
+/// `odata.error` (JSON light) or the verbose `error` shape.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ODataError {
+    /// HTTP status of the response.
+    pub status: StatusCode,
+    /// Code as sent by SharePoint, e.g. `-2130575251, Microsoft.SharePoint.SPException`.
+    pub code: String,
+    /// Language of the message, e.g. `en-US`.
+    pub lang: String,
+    /// Human readable message.
+    pub message: String,
+}
+
+impl ODataError {
+    /// Numeric part of `code`, e.g. `-2130575251`.
+    pub fn number(&self) -> Option<i64> {
+        self.code.split(',').next().and_then(|n| n.trim().parse().ok())
+    }
+
+    /// Exception type part of `code`, e.g. `Microsoft.SharePoint.SPException`.
+    pub fn exception_type(&self) -> Option<&str> {
+        self.code.splitn(2, ',').nth(1).map(|t| t.trim())
+    }
+
+    /// Classifies the error by its code, exception type and HTTP status.
+    pub fn kind(&self) -> ODataErrorKind {
+        match self.number() {
+            Some(-2130575251) => return ODataErrorKind::InvalidRequestDigest,
+            Some(-2130575305) => return ODataErrorKind::VersionConflict,
+            Some(-2147024891) => return ODataErrorKind::AccessDenied,
+            Some(-2147024894) => return ODataErrorKind::NotFound,
+            _ => {}
+        }
+        match self.exception_type() {
+            Some("System.UnauthorizedAccessException") => return ODataErrorKind::AccessDenied,
+            Some("System.IO.FileNotFoundException") => return ODataErrorKind::NotFound,
+            _ => {}
+        }
+        match self.status {
+            StatusCode::NotFound => ODataErrorKind::NotFound,
+            StatusCode::Conflict | StatusCode::PreconditionFailed => ODataErrorKind::VersionConflict,
+            StatusCode::Forbidden | StatusCode::Unauthorized => ODataErrorKind::AccessDenied,
+            _ => ODataErrorKind::Other,
+        }
+    }
+}
+
+/// Broad classification of a login failure, see `SoapFault::kind` and `OAuthError::kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginErrorKind {
+    /// Wrong user name or password, or wrong or expired client secret.
+    InvalidCredentials,
+    /// The user, app or tenant does not exist.
+    UnknownUser,
+    /// The account is locked, disabled or its password has expired.
+    AccountUnusable,
+    /// Multi-factor authentication is required, which this flow cannot do.
+    MfaRequired,
+    /// The domain is federated and its STS could not be found through `GetUserRealm.srf`.
+    FederatedAccount,
+    /// Anything else, inspect `reason` and `text`.
+    Other,
+}
+
+/// SOAP fault returned by the security token service (`extSTS.srf`) or by the ADFS of a federated domain.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SoapFault {
+    /// Fault subcode, e.g. `wst:FailedAuthentication`.
+    pub code: String,
+    /// Fault reason, e.g. `Authentication Failure`.
+    pub reason: String,
+    /// Passport error value, e.g. `0x80048821`.
+    pub value: String,
+    /// Detailed STS text, e.g. `AADSTS50126: Invalid username or password.`
+    pub text: String,
+}
+
+impl SoapFault {
+    /// The `AADSTSxxxxx` code from `text`, if there is one.
+    pub fn aadsts_code(&self) -> Option<&str> {
+        aadsts_code(&self.text)
+    }
+
+    /// Classifies the fault by its AADSTS code and text.
+    pub fn kind(&self) -> LoginErrorKind {
+        match self.aadsts_code() {
+            Some("AADSTS50126") => return LoginErrorKind::InvalidCredentials,
+            Some("AADSTS50034") => return LoginErrorKind::UnknownUser,
+            Some("AADSTS50053") | Some("AADSTS50055") | Some("AADSTS50057") => {
+                return LoginErrorKind::AccountUnusable
+            }
+            Some("AADSTS50076") | Some("AADSTS50079") | Some("AADSTS50158") => {
+                return LoginErrorKind::MfaRequired
+            }
+            _ => {}
+        }
+        if self.reason.starts_with("ID3242") {
+            // the ADFS of a federated domain rejected the user name or password
+            LoginErrorKind::InvalidCredentials
+        } else if self.text.to_lowercase().contains("federat") {
+            LoginErrorKind::FederatedAccount
+        } else if self.value == "0x80048821" {
+            LoginErrorKind::InvalidCredentials
+        } else {
+            LoginErrorKind::Other
+        }
+    }
+}
+
+fn aadsts_code(text: &str) -> Option<&str> {
+    let start = text.find("AADSTS")?;
+    let rest = &text[start..];
+    let end = rest["AADSTS".len()..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(rest.len(), |i| i + "AADSTS".len());
+    Some(&rest[..end])
+}
+
+/// Error response of the Azure AD OAuth2 token endpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OAuthError {
+    pub status: StatusCode,
+    /// OAuth2 error code, e.g. `invalid_client`.
+    pub error: String,
+    /// Details, e.g. `AADSTS7000215: Invalid client secret provided. ...`
+    pub description: String,
+}
+
+impl OAuthError {
+    /// The `AADSTSxxxxx` code from `description`, if there is one.
+    pub fn aadsts_code(&self) -> Option<&str> {
+        aadsts_code(&self.description)
+    }
+
+    /// Classifies the error by its AADSTS code.
+    pub fn kind(&self) -> LoginErrorKind {
+        match self.aadsts_code() {
+            Some("AADSTS7000215") | Some("AADSTS7000222") => LoginErrorKind::InvalidCredentials,
+            Some("AADSTS700016") | Some("AADSTS90002") => LoginErrorKind::UnknownUser,
+            _ if self.error == "invalid_client" => LoginErrorKind::InvalidCredentials,
+            _ => LoginErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "token endpoint error {}", self.error)?;
+        if !self.description.is_empty() {
+            write!(f, " ({})", self.description.lines().next().unwrap_or("").trim())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SoapFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "security token service fault: {}", self.reason)?;
+        if !self.text.is_empty() {
+            write!(f, " ({})", self.text.trim())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ODataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharePoint error {} ({}): {}", self.code, self.status, self.message)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Transport(ref e) => write!(f, "transport error: {}", e),
+            Error::Tls(ref e) => write!(f, "TLS error: {}", e),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Uri(ref url) => write!(f, "invalid URL '{}'", url),
+            Error::Status { ref status, .. } => write!(f, "unexpected HTTP status {}", status),
+            Error::OData(ref e) => write!(f, "{}", e),
+            Error::SoapFault(ref e) => write!(f, "{}", e),
+            Error::OAuth(ref e) => write!(f, "{}", e),
+            Error::Certificate(ref e) => write!(f, "certificate error: {}", e),
+            Error::InsecureBasicAuth(ref url) => write!(f, "refusing to send Basic credentials over plain http to {}", url),
+            Error::Credentials(ref e) => write!(f, "cannot get credentials: {}", e),
+            Error::MissingCookies => write!(f, "sign-in did not return the rtFa and FedAuth cookies"),
+            Error::EmptyListName => write!(f, "the list name is empty"),
+            Error::InvalidLiteral(ref value) => write!(f, "{} has no OData literal", value),
+            Error::SessionExpired => write!(f, "the SharePoint session has expired"),
+            Error::Timeout { ref url, after } => write!(f, "{} did not answer within {:?}", url, after),
+            Error::Cancelled => write!(f, "the call was cancelled"),
+            Error::Batch(ref e) => write!(f, "invalid $batch response: {}", e),
+            Error::TokenCache(ref e) => write!(f, "token cache error: {}", e),
+            Error::Xml(ref e) => write!(f, "XML error: {}", e),
+            Error::Json(ref e) => write!(f, "JSON error: {}", e),
+            Error::Utf8(ref e) => write!(f, "response is not valid UTF-8: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Transport(_) => "transport error",
+            Error::Tls(_) => "TLS error",
+            Error::Io(_) => "I/O error",
+            Error::Uri(_) => "invalid URL",
+            Error::Status { .. } => "unexpected HTTP status",
+            Error::OData(_) => "SharePoint error",
+            Error::SoapFault(_) => "security token service fault",
+            Error::OAuth(_) => "token endpoint error",
+            Error::Certificate(_) => "certificate error",
+            Error::InsecureBasicAuth(_) => "Basic authentication over plain http",
+            Error::Credentials(_) => "cannot get credentials",
+            Error::MissingCookies => "missing authentication cookies",
+            Error::EmptyListName => "empty list name",
+            Error::InvalidLiteral(_) => "invalid OData literal",
+            Error::SessionExpired => "session expired",
+            Error::Timeout { .. } => "request timed out",
+            Error::Cancelled => "call cancelled",
+            Error::Batch(_) => "invalid $batch response",
+            Error::TokenCache(_) => "token cache error",
+            Error::Xml(_) => "XML error",
+            Error::Json(_) => "JSON error",
+            Error::Utf8(_) => "invalid UTF-8",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Transport(ref e) => Some(e),
+            Error::Tls(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            Error::Json(ref e) => Some(e),
+            Error::Utf8(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<::hyper::Error> for Error {
+    fn from(e: ::hyper::Error) -> Error {
+        Error::Transport(e)
+    }
+}
+
+impl From<hyper_tls::Error> for Error {
+    fn from(e: hyper_tls::Error) -> Error {
+        Error::Tls(e)
+    }
+}
+
+#[cfg(feature = "certificate")]
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(e: openssl::error::ErrorStack) -> Error {
+        Error::Certificate(e.to_string())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Error {
+        Error::Utf8(e)
+    }
+}
@@ -1,6 +1,9 @@
 #[macro_use]
 extern crate hyper;
 
+#[macro_use]
+extern crate log;
+
 #[macro_use]
 extern crate serde_derive;
 
@@ -9,11 +12,47 @@ extern crate serde_derive;
 extern crate serde_json;
 
 mod data;
+mod error;
+mod ntlm;
+mod timestamp;
 
+pub mod adfs;
 pub mod auth;
+pub mod batch;
+pub mod cache;
+pub mod client;
+pub mod connection;
+pub mod credentials;
 pub mod list;
+pub mod logging;
+pub mod oauth;
+pub mod odata;
+pub mod on_premises;
+pub mod retry;
+pub mod transport;
+pub mod web;
+
+#[cfg(feature = "certificate")]
+pub mod certificate;
+#[cfg(feature = "mock-server")]
+pub mod mock;
+
+pub use client::{Client, SharePointClient, SharePointFuture};
+pub use error::{Error, LoginErrorKind, OAuthError, ODataError, ODataErrorKind, Result, SoapFault};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Site {
     pub parent: String,
 }
+
+impl Site {
+    /// The site URL to put `/_api/...` after, e.g. `https://company.sharepoint.com/sites/test`.
+    pub(crate) fn url(&self) -> &str {
+        site_path(&self.parent)
+    }
+}
+
+/// `path` of a site without its trailing slashes, the one rule for joining site URLs and paths.
+pub(crate) fn site_path(path: &str) -> &str {
+    path.trim_end_matches('/')
+}
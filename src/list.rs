@@ -1,10 +1,16 @@
 extern crate uuid;
 
 extern crate serde;
+extern crate futures;
 
 use self::uuid::Uuid;
 use auth::*;
+use client::{block_on, Client, SharePointClient, SharePointFuture};
 use data::*;
+use error::{Error, Result};
+use odata::{path_literal, QueryOptions};
+
+use self::futures::Future;
 
 #[allow(unused_imports)]
 use super::*;
@@ -20,9 +26,9 @@ pub struct List {
 
 
 #[derive(Debug, Deserialize, Default)]
-struct ListItemsContainer<T> {
+pub(crate) struct ListItemsContainer<T> {
     #[serde(rename = "value", default)]
-    results: Vec<T>,
+    pub(crate) results: Vec<T>,
 }
 
 #[derive(Debug, Clone)]
@@ -30,89 +36,59 @@ pub struct ListItemType {
     pub name: String,
 }
 
-static GET_LIST_URL: &'static str = "{site}/_api/web/lists/GetByTitle('{title}')";
-static GET_LIST_ITEMS_URL: &'static str = "{site}/_api/web/lists/GetByTitle('{title}')/items";
+static GET_LIST_URL: &'static str = "{site}/_api/web/lists/GetByTitle({title})";
+static GET_LIST_ITEMS_URL: &'static str = "{site}/_api/web/lists/GetByTitle({title})/items";
 
 pub fn get_list_by_title(
     title: String,
     login : LoginContext,
-) -> Option<List> {
-    let access_token_cookies = login.access_token;
-    let digest = login.request_digest;
-    let site = login.site;
-    get_data(
-        GET_LIST_URL.replace("{title}", &title).replace(
-            "{site}",
-            site.parent
-                .to_string()
-                .as_str(),
-        ),
-        access_token_cookies,
-        digest,
-    )
+) -> Result<List> {
+    block_on(|client| client.get_list_by_title(title, &login))
 }
 
-pub fn get_list_default_item_type(list_name: String) -> ListItemType {
-    let mut v: Vec<char> = list_name.chars().collect();
-    v[0] = v[0].to_uppercase().nth(0).unwrap();
-    let s2: String = v.into_iter().collect();
+/// The default item type of the list `list_name`, e.g. `SP.Data.TasksListItem`; fails with
+/// `Error::EmptyListName` for an empty name.
+pub fn get_list_default_item_type(list_name: String) -> Result<ListItemType> {
+    let mut chars = list_name.chars();
+    let first = chars.next().ok_or(Error::EmptyListName)?;
+    let s2: String = first.to_uppercase().chain(chars).collect();
 
-    ListItemType { name: format!("{}{}{}", "SP.Data.", s2, "ListItem") }
+    Ok(ListItemType { name: format!("{}{}{}", "SP.Data.", s2, "ListItem") })
 }
 
 
 pub fn get_list_items_by_title<T>(
     title: String,
     login : LoginContext,
-) -> Vec<T>
+) -> Result<Vec<T>>
+where
+    T: DeserializeOwned + Default + 'static,
+{
+    block_on(|client| client.get_list_items_by_title(title, &login))
+}
+
+/// `get_list_items_by_title` sending the OData query `options`, e.g. to filter on the server.
+pub fn get_list_items_by_title_with_query<T>(
+    title: String,
+    login : LoginContext,
+    options: QueryOptions,
+) -> Result<Vec<T>>
 where
-    T: DeserializeOwned + Default,
+    T: DeserializeOwned + Default + 'static,
 {
-    let access_token_cookies = login.access_token;
-    let digest = login.request_digest;
-    let site = login.site;
-
-    let res: Option<ListItemsContainer<T>> = get_data(
-        GET_LIST_ITEMS_URL.replace("{title}", &title).replace(
-            "{site}",
-            site.parent
-                .to_string()
-                .as_str(),
-        ),
-        access_token_cookies,
-        digest,
-    );
-    //println!("res: '{:?}'", res);
-    res.unwrap().results
+    block_on(|client| client.get_list_items_by_title_with_query(title, &login, options))
 }
 
 pub fn add_list_item_by_list_title<T,U>(
     title: String,
     login : LoginContext,
     data: U,
-) -> T
+) -> Result<T>
 where
-    T: DeserializeOwned + Default,
+    T: DeserializeOwned + Default + 'static,
     U: Serialize + Default,
 {
-    let access_token_cookies = login.access_token;
-    let digest = login.request_digest;
-    let site = login.site;
-
-    let res: Option<T> = post_data(
-        GET_LIST_ITEMS_URL.replace("{title}", &title).replace(
-            "{site}",
-            site.parent
-                .to_string()
-                .as_str(),
-        ),
-        access_token_cookies,
-        digest,
-        data,
-        false,
-    );
-    //println!("res: '{:?}'", res);
-    res.unwrap()
+    block_on(|client| client.add_list_item_by_list_title(title, &login, data))
 }
 
 pub fn update_list_item_by_list_title<U>(
@@ -120,44 +96,169 @@ pub fn update_list_item_by_list_title<U>(
     login : LoginContext,
     data: U,
     id : i32,
-) -> ()
+) -> Result<()>
 where
     U: Serialize + Default,
 {
-    let access_token_cookies = login.access_token;
-    let digest = login.request_digest;
-    let site = login.site;
-
-    let res: Option<()> = post_data(
-        format!("{}({})", GET_LIST_ITEMS_URL.replace("{title}", &title).replace(
-            "{site}",
-            site.parent
-                .to_string()
-                .as_str(),
-        ), id ),
-        access_token_cookies,
-        digest,
-        data,
-        true,
-    );
-    //println!("res: '{:?}'", res);
-    res.unwrap()
+    block_on(|client| client.update_list_item_by_list_title(title, &login, data, id))
+}
+
+pub(crate) fn list_url(title: &str, site: &Site) -> String {
+    GET_LIST_URL.replace("{site}", site.url()).replace("{title}", &path_literal(title))
+}
+
+pub(crate) fn list_items_url(title: &str, site: &Site) -> String {
+    GET_LIST_ITEMS_URL.replace("{site}", site.url()).replace("{title}", &path_literal(title))
+}
+
+impl SharePointClient {
+    /// `list::get_list_by_title` reusing this client's connections.
+    pub fn get_list_by_title(&mut self, title: String, login: &LoginContext) -> Result<List> {
+        let work = self.client().get_list_by_title(title, login);
+        self.run(work)
+    }
+
+    /// `list::get_list_items_by_title` reusing this client's connections.
+    pub fn get_list_items_by_title<T>(&mut self, title: String, login: &LoginContext) -> Result<Vec<T>>
+    where
+        T: DeserializeOwned + Default + 'static,
+    {
+        let work = self.client().get_list_items_by_title(title, login);
+        self.run(work)
+    }
+
+    /// `list::get_list_items_by_title_with_query` reusing this client's connections.
+    pub fn get_list_items_by_title_with_query<T>(&mut self, title: String, login: &LoginContext, options: QueryOptions) -> Result<Vec<T>>
+    where
+        T: DeserializeOwned + Default + 'static,
+    {
+        let work = self.client().get_list_items_by_title_with_query(title, login, options);
+        self.run(work)
+    }
+
+    /// `list::add_list_item_by_list_title` reusing this client's connections.
+    pub fn add_list_item_by_list_title<T, U>(&mut self, title: String, login: &LoginContext, data: U) -> Result<T>
+    where
+        T: DeserializeOwned + Default + 'static,
+        U: Serialize + Default,
+    {
+        let work = self.client().add_list_item_by_list_title(title, login, data);
+        self.run(work)
+    }
+
+    /// `list::update_list_item_by_list_title` reusing this client's connections.
+    pub fn update_list_item_by_list_title<U>(&mut self, title: String, login: &LoginContext, data: U, id: i32) -> Result<()>
+    where
+        U: Serialize + Default,
+    {
+        let work = self.client().update_list_item_by_list_title(title, login, data, id);
+        self.run(work)
+    }
+}
+
+impl Client {
+    /// Asynchronous `list::get_list_by_title`.
+    pub fn get_list_by_title(&self, title: String, login: &LoginContext) -> SharePointFuture<List> {
+        get_with_login(self, list_url(&title, &login.site), login)
+    }
+
+    /// Asynchronous `list::get_list_items_by_title`.
+    pub fn get_list_items_by_title<T>(&self, title: String, login: &LoginContext) -> SharePointFuture<Vec<T>>
+    where
+        T: DeserializeOwned + Default + 'static,
+    {
+        self.get_list_items_by_title_with_query(title, login, QueryOptions::default())
+    }
+
+    /// Asynchronous `list::get_list_items_by_title_with_query`.
+    pub fn get_list_items_by_title_with_query<T>(&self, title: String, login: &LoginContext, options: QueryOptions) -> SharePointFuture<Vec<T>>
+    where
+        T: DeserializeOwned + Default + 'static,
+    {
+        let res: SharePointFuture<ListItemsContainer<T>> = get_with_login(
+            self,
+            options.append_to(&list_items_url(&title, &login.site)),
+            login,
+        );
+        Box::new(res.map(|res| res.results))
+    }
+
+    /// Asynchronous `list::add_list_item_by_list_title`.
+    pub fn add_list_item_by_list_title<T, U>(&self, title: String, login: &LoginContext, data: U) -> SharePointFuture<T>
+    where
+        T: DeserializeOwned + Default + 'static,
+        U: Serialize + Default,
+    {
+        post_with_login(
+            self,
+            list_items_url(&title, &login.site),
+            login,
+            data,
+            false,
+        )
+    }
+
+    /// Asynchronous `list::update_list_item_by_list_title`.
+    pub fn update_list_item_by_list_title<U>(
+        &self,
+        title: String,
+        login: &LoginContext,
+        data: U,
+        id: i32,
+    ) -> SharePointFuture<()>
+    where
+        U: Serialize + Default,
+    {
+        post_with_login(
+            self,
+            format!("{}({})", list_items_url(&title, &login.site), id),
+            login,
+            data,
+            true,
+        )
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    extern crate tokio_core;
+
     use super::*;
-    use std::env;
+    #[cfg(feature = "mock-server")]
+    use self::tokio_core::reactor::Core;
+    use error::ODataErrorKind;
+    use hyper::{Method, StatusCode};
+    #[cfg(feature = "mock-server")]
+    use mock::MockServer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use transport::tests::FakeTransport;
     use std::time::{SystemTime, UNIX_EPOCH};
 
+    #[cfg(feature = "mock-server")]
+    fn mock_login(server: &MockServer) -> LoginContext {
+        login_with_config(
+            server.site_url(),
+            MockServer::USER_NAME.to_string(),
+            MockServer::PASSWORD.to_string(),
+            server.login_config(),
+        ).unwrap()
+    }
+
+    #[cfg(feature = "mock-server")]
     #[test]
     fn get_list_by_title_works() {
-        let (user_name, password, site) = auth::tests::login_params();
-        let login = login( site.parent, user_name, password );
-        let title = env::var("RUST_TITLE").unwrap().to_string();
+        let server = MockServer::start();
+        server.create_list("Tasks");
+        let login = mock_login(&server);
+
+        let list = get_list_by_title("Tasks".to_string(), login.clone()).unwrap();
+        assert!(!list.id.is_nil());
 
-        let list = get_list_by_title(title, login).unwrap();
-        println!("ID: {}", list.id);
+        match get_list_by_title("Missing".to_string(), login) {
+            Err(Error::OData(e)) => assert_eq!(e.kind(), ODataErrorKind::NotFound),
+            res => panic!("expected an OData error, got {:?}", res),
+        }
     }
 
     #[derive(Debug, Deserialize, Default)]
@@ -180,18 +281,390 @@ mod tests {
         title: String,
     }
 
+    #[cfg(feature = "mock-server")]
     #[test]
     fn get_list_items_by_title_works() {
-        let (user_name, password, site) = auth::tests::login_params();
-        let login = login( site.parent, user_name, password );
-        let title = env::var("RUST_TITLE").unwrap().to_string();
+        let server = MockServer::start();
+        server.create_list("Tasks");
+        let login = mock_login(&server);
+        for title in &["First", "Second"] {
+            let new_item = GenericListItemWithTitleForCreate { title: title.to_string() };
+            let _: GenericListItemWithTitle = add_list_item_by_list_title("Tasks".to_string(), login.clone(), new_item).unwrap();
+        }
+
+        let items: Vec<GenericListItem> = get_list_items_by_title("Tasks".to_string(), login).unwrap();
+        assert_eq!(items.iter().map(|i| i.id).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[test]
+    fn get_list_items_by_title_async_works() {
+        let server = MockServer::start();
+        server.create_list("Tasks");
+
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle()).unwrap();
+        let login = core.run(client.login_with_config(
+            server.site_url(),
+            MockServer::USER_NAME.to_string(),
+            MockServer::PASSWORD.to_string(),
+            server.login_config(),
+        )).unwrap();
+        let new_item = GenericListItemWithTitleForCreate { title: "First".to_string() };
+        let _: GenericListItemWithTitle = core.run(client.add_list_item_by_list_title("Tasks".to_string(), &login, new_item)).unwrap();
+
+        let first: SharePointFuture<Vec<GenericListItem>> = client.get_list_items_by_title("Tasks".to_string(), &login);
+        let second: SharePointFuture<Vec<GenericListItem>> = client.get_list_items_by_title("Tasks".to_string(), &login);
+        let (first, second) = core.run(first.join(second)).unwrap();
+
+        assert_eq!(first.len(), 1);
+        assert_eq!(first.len(), second.len());
+    }
 
-        let items: Vec<GenericListItem> =
-            get_list_items_by_title(title, login);
+    /// Local server answering every request with one list item over kept-alive connections,
+    /// and the number of connections it accepted.
+    fn counting_server() -> (String, Arc<AtomicUsize>) {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let site = format!("http://{}/sites/test/", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    let body = r#"{"value":[{"Id":1}]}"#;
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 4096];
+                    while let Ok(read) = stream.read(&mut buffer) {
+                        if read == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buffer[..read]);
+                        // the requests are GETs without a body
+                        while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            request.drain(..end + 4);
+                            let response = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                                body.len(),
+                                body
+                            );
+                            if stream.write_all(response.as_bytes()).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        (site, accepted)
+    }
 
-        println!("items: '{:?}'", items);
+    #[test]
+    fn shared_client_reuses_connections() {
+        let (site, accepted) = counting_server();
+        let login = LoginContext::new(
+            Site { parent: site },
+            Authorization::Cookies(AccessTokenCookies {
+                rt_fa: Some("rt-fa-cookie".to_string()),
+                fed_auth: Some("fed-auth-cookie".to_string()),
+                valid_until: None,
+            }),
+            RequestDigest { content: "0xDIGEST".to_string(), valid_until: None },
+            LoginConfig::default(),
+        );
 
-        assert!(items.len() > 0);
+        let mut client = SharePointClient::new().unwrap();
+        for _ in 0..3 {
+            let items: Vec<GenericListItem> = client.get_list_items_by_title("Tasks".to_string(), &login).unwrap();
+            assert_eq!(items.len(), 1);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn list_requests_carry_authentication_and_merge_headers() {
+        let transport = FakeTransport::default();
+        let login = auth::tests::fake_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        transport.respond(StatusCode::Ok, r#"{"value":[{"Id":1},{"Id":2}]}"#);
+        let items: Vec<GenericListItem> = client.get_list_items_by_title("Tasks".to_string(), &login).unwrap();
+        assert_eq!(items.len(), 2);
+        let get = transport.request(4);
+        assert_eq!(get.method, Method::Get);
+        assert_eq!(get.url, "https://company.sharepoint.com/sites/test/_api/web/lists/GetByTitle('Tasks')/items");
+        assert_eq!(get.header("Accept"), Some("application/json"));
+        assert_eq!(get.header("Cookie"), Some("rtFa=rt-fa-cookie; FedAuth=fed-auth-cookie"));
+
+        transport.respond(StatusCode::Created, r#"{"Id":3,"Title":"New"}"#);
+        let item: GenericListItemWithTitle = client.add_list_item_by_list_title(
+            "Tasks".to_string(),
+            &login,
+            GenericListItemWithTitleForCreate { title: "New".to_string() },
+        ).unwrap();
+        assert_eq!(item.id, 3);
+        let add = transport.request(5);
+        assert_eq!(add.method, Method::Post);
+        assert_eq!(add.body, r#"{"Title":"New"}"#);
+        assert_eq!(add.header("X-RequestDigest"), Some("0xDIGEST,01 Jan 2018 00:00:00 -0000"));
+        assert_eq!(add.header("X-Http-Method"), None);
+
+        transport.respond(StatusCode::NoContent, "");
+        client.update_list_item_by_list_title("Tasks".to_string(), &login, item, 3).unwrap();
+        let update = transport.request(6);
+        assert_eq!(update.url, "https://company.sharepoint.com/sites/test/_api/web/lists/GetByTitle('Tasks')/items(3)");
+        assert_eq!(update.header("X-Http-Method"), Some("PATCH"));
+        assert_eq!(update.header("If-Match"), Some("*"));
+    }
+
+    #[test]
+    fn item_queries_carry_odata_options() {
+        use odata::field;
+
+        let transport = FakeTransport::default();
+        let login = auth::tests::fake_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        transport.respond(StatusCode::Ok, r#"{"value":[{"Id":7,"Title":"Bob's"}]}"#);
+        let options = QueryOptions::new()
+            .select(&["Id", "Title"])
+            .filter(field("Title").eq("Bob's").and(field("Id").gt(5)))
+            .orderby_desc("Id")
+            .top(1);
+        let items: Vec<GenericListItemWithTitle> = client.get_list_items_by_title_with_query("Tasks".to_string(), &login, options).unwrap();
+        assert_eq!(items[0].id, 7);
+        assert_eq!(
+            transport.request(4).url,
+            "https://company.sharepoint.com/sites/test/_api/web/lists/GetByTitle('Tasks')/items\
+             ?$select=Id,Title&$filter=Title%20eq%20'Bob''s'%20and%20Id%20gt%205&$orderby=Id%20desc&$top=1"
+        );
+    }
+
+    #[test]
+    fn default_item_type_of_empty_name_is_an_error() {
+        assert_eq!(get_list_default_item_type("tasks".to_string()).unwrap().name, "SP.Data.TasksListItem");
+        match get_list_default_item_type(String::new()) {
+            Err(Error::EmptyListName) => {}
+            res => panic!("expected an empty name error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn site_url_is_joined_with_one_slash() {
+        for site in &["https://company.sharepoint.com/sites/test", "https://company.sharepoint.com/sites/test/"] {
+            let site = Site { parent: site.to_string() };
+            assert_eq!(list_url("Tasks", &site), "https://company.sharepoint.com/sites/test/_api/web/lists/GetByTitle('Tasks')");
+        }
+        let root = Site { parent: "https://company.sharepoint.com/".to_string() };
+        assert_eq!(list_items_url("Tasks", &root), "https://company.sharepoint.com/_api/web/lists/GetByTitle('Tasks')/items");
+        assert_eq!(
+            list_items_url("Bob's tasks & notes", &root),
+            "https://company.sharepoint.com/_api/web/lists/GetByTitle('Bob''s%20tasks%20%26%20notes')/items"
+        );
+    }
+
+    #[test]
+    fn missing_list_is_reported() {
+        let transport = FakeTransport::default();
+        let login = auth::tests::fake_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        transport.respond(
+            StatusCode::NotFound,
+            r#"{"odata.error":{"code":"-1, System.ArgumentException","message":{"lang":"en-US","value":"List 'Missing' does not exist."}}}"#,
+        );
+        match client.get_list_by_title("Missing".to_string(), &login) {
+            Err(Error::OData(e)) => assert_eq!(e.kind(), ODataErrorKind::NotFound),
+            res => panic!("expected an OData error, got {:?}", res),
+        }
+    }
+
+    static INVALID_DIGEST_ERROR: &'static str = r#"{"odata.error":{"code":"-2130575251, Microsoft.SharePoint.SPException","message":{"lang":"en-US","value":"The security validation for this page is invalid."}}}"#;
+
+    #[test]
+    fn expiring_digest_is_refreshed_before_writing() {
+        let transport = FakeTransport::default();
+        let login = auth::tests::fake_login(&transport);
+        login.set_request_digest(RequestDigest { content: "0xOLD".to_string(), valid_until: Some(SystemTime::now()) });
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        transport
+            .respond(StatusCode::Ok, auth::tests::CONTEXT_INFO_RESPONSE)
+            .respond(StatusCode::NoContent, "");
+        client.update_list_item_by_list_title("Tasks".to_string(), &login, GenericListItemWithTitleForCreate::default(), 3).unwrap();
+
+        assert!(transport.request(4).url.ends_with("/_api/contextinfo"));
+        assert_eq!(transport.request(5).header("X-RequestDigest"), Some("0xDIGEST,01 Jan 2018 00:00:00 -0000"));
+        assert_eq!(login.request_digest().content, "0xDIGEST,01 Jan 2018 00:00:00 -0000");
+    }
+
+    #[test]
+    fn rejected_digest_is_refreshed_and_write_retried_once() {
+        let transport = FakeTransport::default();
+        let login = auth::tests::fake_login(&transport);
+        login.set_request_digest(RequestDigest { content: "0xOLD".to_string(), valid_until: None });
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        transport
+            .respond(StatusCode::Forbidden, INVALID_DIGEST_ERROR)
+            .respond(StatusCode::Ok, auth::tests::CONTEXT_INFO_RESPONSE)
+            .respond(StatusCode::Created, r#"{"Id":3,"Title":"New"}"#);
+        let item: GenericListItemWithTitle = client.add_list_item_by_list_title(
+            "Tasks".to_string(),
+            &login,
+            GenericListItemWithTitleForCreate { title: "New".to_string() },
+        ).unwrap();
+        assert_eq!(item.id, 3);
+        assert_eq!(transport.request(4).header("X-RequestDigest"), Some("0xOLD"));
+        assert!(transport.request(5).url.ends_with("/_api/contextinfo"));
+        assert_eq!(transport.request(6).header("X-RequestDigest"), Some("0xDIGEST,01 Jan 2018 00:00:00 -0000"));
+        assert_eq!(transport.request(6).body, r#"{"Title":"New"}"#);
+
+        transport
+            .respond(StatusCode::Forbidden, INVALID_DIGEST_ERROR)
+            .respond(StatusCode::Ok, auth::tests::CONTEXT_INFO_RESPONSE)
+            .respond(StatusCode::Forbidden, INVALID_DIGEST_ERROR);
+        match client.update_list_item_by_list_title("Tasks".to_string(), &login, item, 3) {
+            Err(Error::OData(e)) => assert_eq!(e.kind(), ODataErrorKind::InvalidRequestDigest),
+            res => panic!("expected an OData error, got {:?}", res),
+        }
+        assert_eq!(transport.requests.borrow().len(), 10);
+    }
+
+    #[test]
+    fn expired_session_is_renewed_and_request_replayed() {
+        let transport = FakeTransport::default();
+        let login = auth::tests::fake_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        transport
+            .respond_with_headers(StatusCode::Found, "", vec![("Location", "https://login.microsoftonline.com/common/oauth2/authorize")])
+            .respond(StatusCode::Ok, adfs::tests::MANAGED_REALM_RESPONSE)
+            .respond(StatusCode::Ok, auth::tests::SECURITY_TOKEN_RESPONSE)
+            .respond_with_cookies(StatusCode::Found, "", vec!["rtFa=new-rt-fa", "FedAuth=new-fed-auth"])
+            .respond(StatusCode::Ok, auth::tests::CONTEXT_INFO_RESPONSE)
+            .respond(StatusCode::Ok, r#"{"value":[{"Id":1}]}"#);
+        let items: Vec<GenericListItem> = client.get_list_items_by_title("Tasks".to_string(), &login).unwrap();
+        assert_eq!(items.len(), 1);
+
+        assert!(transport.request(6).body.contains("<o:Username>user@company.onmicrosoft.com</o:Username>"));
+        let replay = transport.request(9);
+        assert_eq!(replay.url, transport.request(4).url);
+        assert_eq!(replay.header("Cookie"), Some("rtFa=new-rt-fa; FedAuth=new-fed-auth"));
+        assert_eq!(login.access_token().unwrap().fed_auth, Some("new-fed-auth".to_string()));
+    }
+
+    #[test]
+    fn unrelated_redirect_is_reported() {
+        let transport = FakeTransport::default();
+        let login = auth::tests::fake_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        transport.respond_with_headers(
+            StatusCode::Found,
+            r#"{"value":[]}"#,
+            vec![("Location", "https://company.sharepoint.com/sites/test/SitePages/Home.aspx")],
+        );
+        match client.get_list_items_by_title::<GenericListItem>("Tasks".to_string(), &login) {
+            Err(Error::Status { status, .. }) => assert_eq!(status, StatusCode::Found),
+            res => panic!("expected the redirect as error, got {:?}", res),
+        }
+        assert_eq!(transport.requests.borrow().len(), 5);
+    }
+
+    #[test]
+    fn throttled_reads_are_retried_after_the_delay_asked_for() {
+        use std::time::Duration;
+
+        let transport = FakeTransport::default();
+        let login = auth::tests::fake_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        transport
+            .respond_with_headers(StatusCode::TooManyRequests, "", vec![("Retry-After", "2")])
+            .respond(StatusCode::ServiceUnavailable, "")
+            .respond(StatusCode::Ok, r#"{"value":[{"Id":1}]}"#);
+        let items: Vec<GenericListItem> = client.get_list_items_by_title("Tasks".to_string(), &login).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(transport.requests.borrow().len(), 7);
+        let delays = transport.delays.borrow().clone();
+        assert_eq!(delays[0], Duration::from_secs(2));
+        assert!(delays[1] >= Duration::from_secs(1) && delays[1] <= Duration::from_secs(2));
+
+        // creating an item twice would be worse than failing
+        transport.respond_with_headers(StatusCode::TooManyRequests, "", vec![("Retry-After", "2")]);
+        match client.add_list_item_by_list_title::<GenericListItem, _>("Tasks".to_string(), &login, json!({"Title": "Once"})) {
+            Err(Error::Status { status, .. }) => assert_eq!(status, StatusCode::TooManyRequests),
+            res => panic!("expected the throttling error, got {:?}", res),
+        }
+        assert_eq!(transport.requests.borrow().len(), 8);
+    }
+
+    #[test]
+    fn expired_session_asks_the_provider_again() {
+        use credentials::UserCredentials;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let transport = FakeTransport::default();
+        auth::tests::respond_to_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        let fetched = AtomicUsize::new(0);
+        let provider = move || {
+            let n = fetched.fetch_add(1, Ordering::SeqCst);
+            Ok(UserCredentials::new("user@company.onmicrosoft.com", format!("password-{}", n)))
+        };
+        let login = client
+            .login_with_provider("https://company.sharepoint.com/sites/test/".to_string(), provider, LoginConfig::default())
+            .unwrap();
+        assert!(transport.request(1).body.contains("<o:Password>password-0</o:Password>"));
+
+        auth::tests::respond_to_login(transport.respond(StatusCode::Unauthorized, ""));
+        transport.respond(StatusCode::Ok, r#"{"value":[{"Id":1}]}"#);
+        let items: Vec<GenericListItem> = client.get_list_items_by_title("Tasks".to_string(), &login).unwrap();
+        assert_eq!(items.len(), 1);
+        assert!(transport.request(6).body.contains("<o:Password>password-1</o:Password>"));
+    }
+
+    #[test]
+    fn expired_session_without_credentials_is_reported() {
+        let transport = FakeTransport::default();
+        let signed_in = auth::tests::fake_login(&transport);
+        let login = LoginContext::new(signed_in.site.clone(), signed_in.authorization(), signed_in.request_digest(), LoginConfig::default());
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        transport.respond(StatusCode::Unauthorized, "");
+        match client.get_list_by_title("Tasks".to_string(), &login) {
+            Err(Error::SessionExpired) => {}
+            res => panic!("expected an expired session, got {:?}", res),
+        }
+        assert_eq!(transport.requests.borrow().len(), 5);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[test]
+    fn expired_session_is_renewed_against_mock_server() {
+        let server = MockServer::start();
+        server.create_list("Tasks");
+        let mut client = SharePointClient::new().unwrap();
+        let login = client.login_with_config(
+            server.site_url(),
+            MockServer::USER_NAME.to_string(),
+            MockServer::PASSWORD.to_string(),
+            server.login_config(),
+        ).unwrap();
+
+        server.expire_sessions();
+        let item: GenericListItemWithTitle = client.add_list_item_by_list_title(
+            "Tasks".to_string(),
+            &login,
+            GenericListItemWithTitleForCreate { title: "After expiry".to_string() },
+        ).unwrap();
+        assert_eq!(item.title, "After expiry");
+        assert_eq!(login.access_token().unwrap().fed_auth, Some("mock-fed-auth-1".to_string()));
     }
 
     pub fn since_the_epoch() -> u64 {
@@ -202,32 +675,28 @@ mod tests {
         since_the_epoch.as_secs() * 1000 + since_the_epoch.subsec_nanos() as u64 / 1_000_000
     }
 
+    #[cfg(feature = "mock-server")]
     #[test]
     fn create_new_list_item_by_title_works() {
-        let (user_name, password, site) = auth::tests::login_params();
+        let server = MockServer::start();
+        server.create_list("TestColumn");
+        let login = mock_login(&server);
 
         let new_item = GenericListItemWithTitleForCreate { title: format!("Test-{}", since_the_epoch()) };
-
-        let login = login( site.parent, user_name, password );
-        let title = env::var("RUST_TITLE").unwrap().to_string();
-
         let item: GenericListItemWithTitle = add_list_item_by_list_title(
-            title.to_owned(),
+            "TestColumn".to_string(),
             login.clone(),
             new_item,
-        );
+        ).unwrap();
+        assert_eq!(item.id, 1);
 
-        println!("item: '{:?}'", item);
         let item2 = GenericListItemWithTitle{ title: format!("{}-Updated", item.title), .. item };
         let id = item2.id;
+        update_list_item_by_list_title("TestColumn".to_string(), login.clone(), item2, id).unwrap();
 
-        update_list_item_by_list_title(
-            title.to_owned(),
-            login.clone(),
-            item2,
-            id
-        );
-
-        //assert!(false);
+        let items: Vec<GenericListItemWithTitle> = get_list_items_by_title("TestColumn".to_string(), login).unwrap();
+        assert_eq!(items.len(), 1);
+        assert!(items[0].title.ends_with("-Updated"));
+        assert_eq!(server.items("TestColumn")[0]["Title"], items[0].title.as_str());
     }
 }
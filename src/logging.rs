@@ -0,0 +1,150 @@
+//! Diagnostics of the crate, sent through the `log` facade: every request and response at
+//! `debug`, their headers at `trace`.
+//!
+//! Cookies, tokens, passwords and request digests are never logged. Bodies are left out
+//! unless `set_log_bodies(true)` is called, and even then the bodies of login requests and
+//! of the request digest are not logged.
+//!
+//! Enable the `sharepoint` target only: `serde-xml-rs` logs the XML it parses at `debug`,
+//! including the security tokens of the login responses.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use hyper::Method;
+
+use transport::{HeaderItem, TransportRequest, TransportResponse};
+
+static LOG_BODIES: AtomicBool = AtomicBool::new(false);
+
+/// Headers whose values are replaced by `<redacted>`.
+static SECRET_HEADERS: &'static [&'static str] = &[
+    "Authorization",
+    "Cookie",
+    "Proxy-Authorization",
+    "Set-Cookie",
+    "WWW-Authenticate",
+    "X-RequestDigest",
+];
+
+/// Logs request and response bodies at `trace` from now on; they may contain personal data.
+pub fn set_log_bodies(enabled: bool) {
+    LOG_BODIES.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether `set_log_bodies(true)` was called.
+pub fn log_bodies() -> bool {
+    LOG_BODIES.load(Ordering::Relaxed)
+}
+
+/// The headers as `Name: value` lines, with the values of `SECRET_HEADERS` redacted.
+pub(crate) fn redacted_headers(headers: &[HeaderItem]) -> String {
+    headers
+        .iter()
+        .map(|h| {
+            if SECRET_HEADERS.iter().any(|s| s.eq_ignore_ascii_case(&h.name)) {
+                format!("{}: <redacted>", h.name)
+            } else {
+                format!("{}: {}", h.name, h.value)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Names of the cookies set by a response, without their values.
+fn cookie_names(cookies: &[String]) -> String {
+    cookies
+        .iter()
+        .map(|c| c.split('=').next().unwrap_or("").trim())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn body(body: &str, secret: bool) -> String {
+    if secret {
+        format!("<{} bytes, not logged>", body.len())
+    } else {
+        body.to_owned()
+    }
+}
+
+/// Logs `request`; `secret` bodies, such as a password login, are never written.
+pub(crate) fn log_request(request: &TransportRequest, secret: bool) {
+    debug!("{} {}", request.method, request.url);
+    trace!("request headers: {}", redacted_headers(&request.headers));
+    if log_bodies() {
+        trace!("request body: {}", body(&request.body, secret));
+    }
+}
+
+/// Logs the response to `method` `url`; `secret` bodies, such as tokens, are never written.
+pub(crate) fn log_response(method: &Method, url: &str, response: &TransportResponse, secret: bool) {
+    debug!("{} {} -> {}", method, url, response.status);
+    trace!("response headers: {}", redacted_headers(&response.headers));
+    if !response.cookies.is_empty() {
+        trace!("response sets cookies: {}", cookie_names(&response.cookies));
+    }
+    if log_bodies() {
+        trace!("response body: {}", body(&response.body, secret));
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    extern crate log;
+
+    use super::*;
+    use self::log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    thread_local! {
+        static RECORDS: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    /// Keeps the messages the crate logs on the current thread, so tests running in parallel do not mix them.
+    struct CapturingLogger;
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Trace && metadata.target().starts_with("sharepoint")
+        }
+
+        fn log(&self, record: &Record) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            RECORDS.with(|r| r.borrow_mut().push(format!("{}", record.args())));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger;
+    static INIT: Once = Once::new();
+
+    /// Starts capturing what the current thread logs, see `captured`.
+    pub fn capture() {
+        INIT.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+        RECORDS.with(|r| r.borrow_mut().clear());
+    }
+
+    /// Everything the current thread logged since `capture`.
+    pub fn captured() -> String {
+        RECORDS.with(|r| r.borrow().join("\n"))
+    }
+
+    #[test]
+    fn secret_headers_are_redacted() {
+        let headers = vec![
+            HeaderItem { name: "cookie".to_string(), value: "rtFa=secret-rt-fa; FedAuth=secret".to_string() },
+            HeaderItem { name: "Authorization".to_string(), value: "Bearer secret".to_string() },
+            HeaderItem { name: "Accept".to_string(), value: "application/json".to_string() },
+        ];
+        assert_eq!(redacted_headers(&headers), "cookie: <redacted>, Authorization: <redacted>, Accept: application/json");
+        let cookies = vec!["rtFa=secret; path=/".to_string(), "FedAuth=secret".to_string()];
+        assert_eq!(cookie_names(&cookies), "rtFa, FedAuth");
+    }
+}
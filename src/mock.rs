@@ -0,0 +1,576 @@
+//! Local HTTP server emulating the parts of Office 365 and SharePoint Online the crate talks to,
+//! so code using `auth` and `list` can be tested offline.
+//!
+//! ```no_run
+//! # extern crate sharepoint;
+//! # use sharepoint::mock::MockServer;
+//! # fn main() {
+//! let server = MockServer::start();
+//! server.create_list("Tasks");
+//! let login = sharepoint::auth::login_with_config(
+//!     server.site_url(),
+//!     MockServer::USER_NAME.to_string(),
+//!     MockServer::PASSWORD.to_string(),
+//!     server.login_config(),
+//! ).unwrap();
+//! # }
+//! ```
+extern crate base64;
+extern crate futures;
+extern crate tokio_core;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use hyper::header::{ContentType, Host, SetCookie};
+use hyper::server::{Http, Request, Response, Service};
+use hyper::{Method, StatusCode};
+
+use self::futures::sync::oneshot;
+use self::futures::{Future, Stream};
+use self::serde_json::Value;
+
+use auth::LoginConfig;
+use ntlm;
+use on_premises::WindowsCredentials;
+
+#[allow(unused_imports)]
+use super::*;
+
+static SITE_PATH: &'static str = "/sites/mock/";
+static SECURITY_TOKEN: &'static str = "t=mock-security-token";
+static RT_FA: &'static str = "mock-rt-fa";
+static FED_AUTH: &'static str = "mock-fed-auth";
+static BEARER: &'static str = "mock-bearer";
+/// NTLM server challenge, its last byte set to the number of the connection: a handshake
+/// only succeeds if both of its messages are sent over the same connection.
+static NTLM_CHALLENGE: [u8; 8] = *b"mock-nt\0";
+static DIGEST: &'static str = "0xMOCKDIGEST,01 Jan 2018 00:00:00 -0000";
+
+static SECURITY_TOKEN_RESPONSE: &'static str = r##"<S:Envelope xmlns:S="http://www.w3.org/2003/05/soap-envelope" xmlns:wst="http://schemas.xmlsoap.org/ws/2005/02/trust" xmlns:wsse="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd"><S:Body><wst:RequestSecurityTokenResponse><wst:RequestedSecurityToken><wsse:BinarySecurityToken Id="Compact0">{token}</wsse:BinarySecurityToken></wst:RequestedSecurityToken></wst:RequestSecurityTokenResponse></S:Body></S:Envelope>"##;
+
+static USER_REALM_RESPONSE: &'static str = r##"<RealmInfo Success="true"><State>{state}</State><Login>{login}</Login><NameSpaceType>{name_space_type}</NameSpaceType><STSAuthURL>{sts_auth_url}</STSAuthURL><CloudInstanceIssuerUri>urn:federation:MicrosoftOnline</CloudInstanceIssuerUri></RealmInfo>"##;
+
+static ADFS_PATH: &'static str = "/adfs/services/trust/2005/usernamemixed";
+static ADFS_ASSERTION: &'static str = r##"<saml:Assertion MajorVersion="1" MinorVersion="1" AssertionID="_mock-assertion" Issuer="http://mock/adfs/services/trust" xmlns:saml="urn:oasis:names:tc:SAML:1.0:assertion"><saml:AttributeStatement><saml:Subject><saml:NameIdentifier>{user_name}</saml:NameIdentifier></saml:Subject></saml:AttributeStatement></saml:Assertion>"##;
+static ADFS_RESPONSE: &'static str = r##"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"><s:Body><t:RequestSecurityTokenResponse xmlns:t="http://schemas.xmlsoap.org/ws/2005/02/trust"><t:RequestedSecurityToken>{assertion}</t:RequestedSecurityToken></t:RequestSecurityTokenResponse></s:Body></s:Envelope>"##;
+static ADFS_FAULT_RESPONSE: &'static str = r##"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"><s:Body><s:Fault><s:Code><s:Value>s:Sender</s:Value><s:Subcode><s:Value xmlns:a="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd">a:FailedAuthentication</s:Value></s:Subcode></s:Code><s:Reason><s:Text xml:lang="en-US">ID3242: The security token could not be authenticated or authorized.</s:Text></s:Reason></s:Fault></s:Body></s:Envelope>"##;
+
+static INVALID_CREDENTIALS_RESPONSE: &'static str = r##"<S:Envelope xmlns:S="http://www.w3.org/2003/05/soap-envelope" xmlns:psf="http://schemas.microsoft.com/Passport/SoapServices/SOAPFault"><S:Body><S:Fault><S:Code><S:Value>S:Sender</S:Value><S:Subcode><S:Value>wst:FailedAuthentication</S:Value></S:Subcode></S:Code><S:Reason><S:Text xml:lang="en-US">Authentication Failure</S:Text></S:Reason><S:Detail><psf:error><psf:value>0x80048821</psf:value><psf:internalerror><psf:code>0x80041012</psf:code><psf:text>AADSTS50126: Invalid username or password.</psf:text></psf:internalerror></psf:error></S:Detail></S:Fault></S:Body></S:Envelope>"##;
+
+header! { (FormsBasedAuthRequired, "X-Forms_Based_Auth_Required") => [String] }
+header! { (WwwAuthenticate, "WWW-Authenticate") => [String] }
+
+static CONTEXT_INFO_RESPONSE: &'static str = r##"<d:GetContextWebInformation xmlns:d="http://schemas.microsoft.com/ado/2007/08/dataservices" xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata"><d:FormDigestTimeoutSeconds m:type="Edm.Int32">1800</d:FormDigestTimeoutSeconds><d:FormDigestValue>{digest}</d:FormDigestValue></d:GetContextWebInformation>"##;
+
+#[derive(Default)]
+struct MockList {
+    id: usize,
+    next_item_id: i64,
+    items: Vec<Value>,
+}
+
+#[derive(Default)]
+struct State {
+    lists: HashMap<String, MockList>,
+    /// Bumped by `expire_sessions`; part of the cookie values handed out.
+    session: u32,
+}
+
+/// A running mock server, stopped when dropped.
+///
+/// It accepts `MockServer::USER_NAME` / `MockServer::PASSWORD` at its `extSTS.srf`,
+/// `MockServer::FEDERATED_USER_NAME` / `MockServer::PASSWORD` at its emulated ADFS,
+/// `MockServer::DOMAIN\MockServer::WINDOWS_USER_NAME` / `MockServer::PASSWORD` through NTLM and
+/// `MockServer::CLIENT_ID` / `MockServer::CLIENT_SECRET` at its token endpoints, hands out
+/// cookies and bearer tokens valid until `expire_sessions` and a fixed request digest, and
+/// keeps lists and their items in memory.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MockServer {
+    pub const USER_NAME: &'static str = "user@mock.onmicrosoft.com";
+    /// User of a federated domain, signed in through the emulated ADFS.
+    pub const FEDERATED_USER_NAME: &'static str = "user@mock-federated.com";
+    /// Windows account signed in through NTLM, as on an on-premises farm.
+    pub const DOMAIN: &'static str = "MOCK";
+    pub const WINDOWS_USER_NAME: &'static str = "mock-user";
+    pub const PASSWORD: &'static str = "mock-password";
+    pub const CLIENT_ID: &'static str = "00000000-0000-0000-0000-00000000c11e";
+    pub const CLIENT_SECRET: &'static str = "mock-client-secret";
+    /// Realm (tenant id) announced by the site to add-ins.
+    pub const REALM: &'static str = "00000000-0000-0000-0000-0000000ea1e5";
+
+    /// Starts the server on a free port of `127.0.0.1`.
+    pub fn start() -> MockServer {
+        let state = Arc::new(Mutex::new(State::default()));
+        let (addr_tx, addr_rx) = mpsc::channel();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let service_state = state.clone();
+        let connections = Arc::new(Mutex::new(0u8));
+        let thread = thread::spawn(move || {
+            let addr = "127.0.0.1:0".parse().unwrap();
+            let mut server = Http::new()
+                .bind(&addr, move || {
+                    let mut connections = connections.lock().unwrap();
+                    *connections = connections.wrapping_add(1);
+                    let mut ntlm_challenge = NTLM_CHALLENGE;
+                    ntlm_challenge[7] = *connections;
+                    Ok(MockService {
+                        state: service_state.clone(),
+                        ntlm_challenge: ntlm_challenge,
+                        ntlm_authenticated: Arc::new(AtomicBool::new(false)),
+                    })
+                })
+                .expect("mock server cannot bind");
+            server.shutdown_timeout(Duration::from_millis(100));
+            addr_tx.send(server.local_addr().unwrap()).unwrap();
+            server.run_until(shutdown_rx.then(|_| Ok(()))).unwrap();
+        });
+        MockServer {
+            addr: addr_rx.recv().expect("mock server did not start"),
+            state: state,
+            shutdown: Some(shutdown_tx),
+            thread: Some(thread),
+        }
+    }
+
+    /// Address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// URL of the emulated site, to be passed to `auth::login_with_config`.
+    pub fn site_url(&self) -> String {
+        format!("http://{}{}", self.addr, SITE_PATH)
+    }
+
+    /// Login configuration pointing the user realm, security token service and token endpoints to this server.
+    pub fn login_config(&self) -> LoginConfig {
+        LoginConfig::default()
+            .user_realm_url(format!("http://{}/GetUserRealm.srf", self.addr))
+            .security_token_url(format!("http://{}/extSTS.srf", self.addr))
+            .token_url(format!("http://{}/{{tenant}}/oauth2/v2.0/token", self.addr))
+            .acs_token_url(format!("http://{}/{{realm}}/tokens/OAuth/2", self.addr))
+    }
+
+    /// Creates an empty list called `title`.
+    pub fn create_list(&self, title: &str) {
+        let mut state = self.state.lock().unwrap();
+        let id = state.lists.len() + 1;
+        state.lists.insert(
+            title.to_string(),
+            MockList {
+                id: id,
+                next_item_id: 1,
+                items: Vec::new(),
+            },
+        );
+    }
+
+    /// Makes the cookies handed out so far invalid, as when a SharePoint Online session expires.
+    pub fn expire_sessions(&self) {
+        self.state.lock().unwrap().session += 1;
+    }
+
+    /// Current items of the list called `title`.
+    pub fn items(&self, title: &str) -> Vec<Value> {
+        let state = self.state.lock().unwrap();
+        state.lists.get(title).map(|l| l.items.clone()).unwrap_or_default()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Serves one connection.
+struct MockService {
+    state: Arc<Mutex<State>>,
+    ntlm_challenge: [u8; 8],
+    /// Whether an NTLM handshake succeeded on the connection, which authenticates what follows.
+    ntlm_authenticated: Arc<AtomicBool>,
+}
+
+impl Service for MockService {
+    type Request = Request;
+    type Response = Response;
+    type Error = ::hyper::Error;
+    type Future = Box<dyn Future<Item = Response, Error = ::hyper::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let state = self.state.clone();
+        let method = req.method().clone();
+        let path = req.path().to_string();
+        let query = req.query().unwrap_or("").to_string();
+        let host = req.headers().get::<Host>().map(|h| match h.port() {
+            Some(port) => format!("{}:{}", h.hostname(), port),
+            None => h.hostname().to_string(),
+        }).unwrap_or_default();
+        let header = |name: &str| req.headers().get_raw(name).and_then(|v| v.one()).map(|v| String::from_utf8_lossy(v).into_owned());
+        let cookie = header("Cookie").unwrap_or_default();
+        let authorization = header("Authorization");
+        let digest = header("X-RequestDigest");
+        let http_method = header("X-Http-Method");
+        let ntlm_challenge = self.ntlm_challenge;
+        let ntlm_authenticated = self.ntlm_authenticated.clone();
+        Box::new(req.body().concat2().map(move |body| {
+            let body = String::from_utf8_lossy(&body).into_owned();
+            let mut state = state.lock().unwrap();
+            let incoming = Incoming {
+                method: method,
+                path: path,
+                query: query,
+                host: host,
+                cookie: cookie,
+                authorization: authorization,
+                digest: digest,
+                http_method: http_method,
+                body: body,
+                ntlm_challenge: ntlm_challenge,
+                ntlm_authenticated: ntlm_authenticated,
+            };
+            incoming.respond(&mut state)
+        }))
+    }
+}
+
+struct Incoming {
+    method: Method,
+    path: String,
+    query: String,
+    host: String,
+    cookie: String,
+    authorization: Option<String>,
+    digest: Option<String>,
+    http_method: Option<String>,
+    body: String,
+    ntlm_challenge: [u8; 8],
+    ntlm_authenticated: Arc<AtomicBool>,
+}
+
+fn xml(status: StatusCode, body: String) -> Response {
+    Response::new()
+        .with_status(status)
+        .with_header(ContentType::xml())
+        .with_body(body)
+}
+
+fn json(status: StatusCode, body: Value) -> Response {
+    Response::new()
+        .with_status(status)
+        .with_header(ContentType::json())
+        .with_body(body.to_string())
+}
+
+fn odata_error(status: StatusCode, code: &str, message: &str) -> Response {
+    json(
+        status,
+        json!({"odata.error": {"code": code, "message": {"lang": "en-US", "value": message}}}),
+    )
+}
+
+/// Unescaped text between `<{tag}>` and `</{tag}>` in `body`.
+fn element(body: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = body.find(&open)? + open.len();
+    let end = body[start..].find(&close)? + start;
+    Some(
+        body[start..end]
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+/// Decoded value of `name` in the form-urlencoded `body`.
+fn form_value(body: &str, name: &str) -> Option<String> {
+    body.split('&').find(|pair| pair.split('=').next() == Some(name)).map(|pair| {
+        let raw = pair[name.len()..].trim_start_matches('=').replace('+', " ");
+        let mut bytes = Vec::new();
+        let mut rest = raw.as_bytes();
+        while let Some((&b, tail)) = rest.split_first() {
+            match (b, tail.get(..2).and_then(|h| u8::from_str_radix(&String::from_utf8_lossy(h), 16).ok())) {
+                (b'%', Some(decoded)) => {
+                    bytes.push(decoded);
+                    rest = &tail[2..];
+                }
+                _ => {
+                    bytes.push(b);
+                    rest = tail;
+                }
+            }
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
+impl Incoming {
+    fn respond(self, state: &mut State) -> Response {
+        if self.method == Method::Post && self.path == "/GetUserRealm.srf" {
+            return self.user_realm();
+        }
+        if self.method == Method::Post && self.path == ADFS_PATH {
+            return self.adfs_token();
+        }
+        if self.method == Method::Post && self.path == "/extSTS.srf" {
+            return self.security_token();
+        }
+        if self.method == Method::Post && self.path == "/_forms/default.aspx" && self.query == "wa=wsignin1.0" {
+            return self.sign_in(state.session);
+        }
+        if self.method == Method::Post && self.path.ends_with("/oauth2/v2.0/token") {
+            return self.app_token(state.session);
+        }
+        if self.method == Method::Post && self.path == format!("/{}/tokens/OAuth/2", MockServer::REALM) {
+            return self.add_in_token(state.session);
+        }
+        if self.method == Method::Get && self.path == "/_vti_bin/client.svc" && self.authorization.as_ref().map(|a| a.trim()) == Some("Bearer") {
+            return Response::new()
+                .with_status(StatusCode::Unauthorized)
+                .with_header(WwwAuthenticate(format!(
+                    "Bearer realm=\"{}\",client_id=\"00000003-0000-0ff1-ce00-000000000000\",trusted_issuers=\"00000001-0000-0000-c000-000000000000@*\"",
+                    MockServer::REALM
+                )));
+        }
+        if let Some((scheme, message)) = self.ntlm_message() {
+            // message type 1, NEGOTIATE_MESSAGE
+            if message.get(8) == Some(&1) {
+                let challenge = ntlm::challenge_message(self.ntlm_challenge, &[0, 0, 0, 0]);
+                return Response::new()
+                    .with_status(StatusCode::Unauthorized)
+                    .with_header(WwwAuthenticate(format!("{} {}", scheme, base64::encode(&challenge))));
+            }
+            let credentials = WindowsCredentials::new(MockServer::DOMAIN, MockServer::WINDOWS_USER_NAME, MockServer::PASSWORD);
+            if !ntlm::verify_authenticate_message(&message, &credentials, &self.ntlm_challenge) {
+                return Response::new()
+                    .with_status(StatusCode::Unauthorized)
+                    .with_header(WwwAuthenticate("NTLM".to_string()))
+                    .with_body("401 UNAUTHORIZED");
+            }
+            self.ntlm_authenticated.store(true, Ordering::SeqCst);
+        } else if !self.ntlm_authenticated.load(Ordering::SeqCst) && !self.authenticated(state.session) {
+            return Response::new()
+                .with_status(StatusCode::Forbidden)
+                .with_header(FormsBasedAuthRequired("/_forms/default.aspx".to_string()))
+                .with_body("403 FORBIDDEN");
+        }
+        if self.method == Method::Post && self.path.ends_with("/_api/contextinfo") {
+            return xml(StatusCode::Ok, CONTEXT_INFO_RESPONSE.replace("{digest}", DIGEST));
+        }
+        match self.path.find("/_api/web/lists/GetByTitle('") {
+            Some(start) => {
+                let rest = self.path[start + "/_api/web/lists/GetByTitle('".len()..].to_string();
+                match rest.find("')") {
+                    Some(end) => self.list(state, &rest[..end], &rest[end + 2..]),
+                    None => odata_error(StatusCode::BadRequest, "-1, System.ArgumentException", "Bad request."),
+                }
+            }
+            None => odata_error(StatusCode::NotFound, "-1, System.ArgumentException", "Not found."),
+        }
+    }
+
+    fn user_realm(&self) -> Response {
+        let login = form_value(&self.body, "login").unwrap_or_default();
+        let realm = if login == MockServer::FEDERATED_USER_NAME {
+            USER_REALM_RESPONSE
+                .replace("{state}", "3")
+                .replace("{name_space_type}", "Federated")
+                .replace("{sts_auth_url}", &format!("http://{}{}", self.host, ADFS_PATH))
+        } else {
+            USER_REALM_RESPONSE
+                .replace("{state}", "4")
+                .replace("{name_space_type}", "Managed")
+                .replace("{sts_auth_url}", "")
+        };
+        xml(StatusCode::Ok, realm.replace("{login}", &login))
+    }
+
+    fn adfs_token(&self) -> Response {
+        let user_name = element(&self.body, "o:Username");
+        let password = element(&self.body, "o:Password");
+        if user_name.as_ref().map(|u| u.as_str()) == Some(MockServer::FEDERATED_USER_NAME)
+            && password.as_ref().map(|p| p.as_str()) == Some(MockServer::PASSWORD)
+            && self.body.contains("<u:Timestamp")
+        {
+            let assertion = ADFS_ASSERTION.replace("{user_name}", MockServer::FEDERATED_USER_NAME);
+            xml(StatusCode::Ok, ADFS_RESPONSE.replace("{assertion}", &assertion))
+        } else {
+            xml(StatusCode::InternalServerError, ADFS_FAULT_RESPONSE.to_string())
+        }
+    }
+
+    fn security_token(&self) -> Response {
+        // assertions are not verified, one of the emulated ADFS stands for the password
+        if self.body.contains(&ADFS_ASSERTION.replace("{user_name}", MockServer::FEDERATED_USER_NAME)) {
+            return xml(StatusCode::Ok, SECURITY_TOKEN_RESPONSE.replace("{token}", SECURITY_TOKEN));
+        }
+        let user_name = element(&self.body, "o:Username");
+        let password = element(&self.body, "o:Password");
+        if user_name.as_ref().map(|u| u.as_str()) == Some(MockServer::USER_NAME)
+            && password.as_ref().map(|p| p.as_str()) == Some(MockServer::PASSWORD)
+        {
+            xml(StatusCode::Ok, SECURITY_TOKEN_RESPONSE.replace("{token}", SECURITY_TOKEN))
+        } else {
+            xml(StatusCode::InternalServerError, INVALID_CREDENTIALS_RESPONSE.to_string())
+        }
+    }
+
+    fn sign_in(&self, session: u32) -> Response {
+        if self.body != SECURITY_TOKEN {
+            return Response::new().with_status(StatusCode::Forbidden);
+        }
+        Response::new()
+            .with_status(StatusCode::Found)
+            .with_header(SetCookie(vec![
+                format!("rtFa={}-{}; path=/; secure; HttpOnly", RT_FA, session),
+                format!("FedAuth={}-{}; path=/; secure; HttpOnly", FED_AUTH, session),
+            ]))
+    }
+
+    fn app_token(&self, session: u32) -> Response {
+        if form_value(&self.body, "grant_type").as_ref().map(|g| g.as_str()) != Some("client_credentials") {
+            return json(
+                StatusCode::BadRequest,
+                json!({"error": "unsupported_grant_type", "error_description": "AADSTS70003: The app requested an unsupported grant type."}),
+            );
+        }
+        // Client assertions are not verified, any signed JWT stands for the app's certificate.
+        let assertion = form_value(&self.body, "client_assertion_type").as_ref().map(|t| t.as_str())
+            == Some("urn:ietf:params:oauth:client-assertion-type:jwt-bearer")
+            && form_value(&self.body, "client_assertion").map_or(false, |a| a.split('.').count() == 3);
+        let secret = form_value(&self.body, "client_secret").as_ref().map(|c| c.as_str()) == Some(MockServer::CLIENT_SECRET);
+        if form_value(&self.body, "client_id").as_ref().map(|c| c.as_str()) != Some(MockServer::CLIENT_ID) || !(secret || assertion) {
+            return json(
+                StatusCode::Unauthorized,
+                json!({"error": "invalid_client", "error_description": "AADSTS7000215: Invalid client secret provided.", "error_codes": [7000215]}),
+            );
+        }
+        json(
+            StatusCode::Ok,
+            json!({"token_type": "Bearer", "expires_in": 3599, "access_token": format!("{}-{}", BEARER, session)}),
+        )
+    }
+
+    fn add_in_token(&self, session: u32) -> Response {
+        let client_id = format!("{}@{}", MockServer::CLIENT_ID, MockServer::REALM);
+        if form_value(&self.body, "client_id") != Some(client_id)
+            || form_value(&self.body, "client_secret").as_ref().map(|c| c.as_str()) != Some(MockServer::CLIENT_SECRET)
+            || !form_value(&self.body, "resource").map_or(false, |r| r.starts_with("00000003-0000-0ff1-ce00-000000000000/") && r.ends_with(MockServer::REALM))
+        {
+            return json(
+                StatusCode::Unauthorized,
+                json!({"error": "invalid_client", "error_description": "AADSTS7000215: Invalid client secret is provided."}),
+            );
+        }
+        json(
+            StatusCode::Ok,
+            json!({"token_type": "Bearer", "expires_in": "3599", "not_before": "0", "resource": "00000003-0000-0ff1-ce00-000000000000", "access_token": format!("{}-{}", BEARER, session)}),
+        )
+    }
+
+    /// Scheme and decoded message of an `Authorization: NTLM` or `Negotiate` header.
+    fn ntlm_message(&self) -> Option<(String, Vec<u8>)> {
+        let authorization = self.authorization.as_ref()?;
+        let mut parts = authorization.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(scheme), Some(token)) if scheme == "NTLM" || scheme == "Negotiate" => {
+                base64::decode(token).ok().map(|message| (scheme.to_string(), message))
+            }
+            _ => None,
+        }
+    }
+
+    fn authenticated(&self, session: u32) -> bool {
+        if let Some(ref authorization) = self.authorization {
+            return *authorization == format!("Bearer {}-{}", BEARER, session);
+        }
+        let cookies: Vec<&str> = self.cookie.split(';').map(|c| c.trim()).collect();
+        cookies.contains(&format!("rtFa={}-{}", RT_FA, session).as_str())
+            && cookies.contains(&format!("FedAuth={}-{}", FED_AUTH, session).as_str())
+    }
+
+    fn list(&self, state: &mut State, title: &str, rest: &str) -> Response {
+        if self.method == Method::Post && self.digest.as_ref().map(|d| d.as_str()) != Some(DIGEST) {
+            return odata_error(
+                StatusCode::Forbidden,
+                "-2130575251, Microsoft.SharePoint.SPException",
+                "The security validation for this page is invalid and might be corrupted.",
+            );
+        }
+        let list = match state.lists.get_mut(title) {
+            Some(list) => list,
+            None => {
+                return odata_error(
+                    StatusCode::NotFound,
+                    "-1, System.ArgumentException",
+                    &format!("List '{}' does not exist at site with URL '{}'.", title, SITE_PATH),
+                )
+            }
+        };
+        if rest.is_empty() && self.method == Method::Get {
+            return json(
+                StatusCode::Ok,
+                json!({"Id": format!("00000000-0000-0000-0000-{:012x}", list.id), "Title": title}),
+            );
+        }
+        if rest == "/items" && self.method == Method::Get {
+            return json(StatusCode::Ok, json!({ "value": list.items }));
+        }
+        if rest == "/items" && self.method == Method::Post {
+            let mut item: Value = match serde_json::from_str(&self.body) {
+                Ok(item @ Value::Object(_)) => item,
+                _ => return odata_error(StatusCode::BadRequest, "-1, Microsoft.SharePoint.Client.InvalidClientQueryException", "Invalid JSON."),
+            };
+            item["Id"] = json!(list.next_item_id);
+            list.next_item_id += 1;
+            list.items.push(item.clone());
+            return json(StatusCode::Created, item);
+        }
+        if rest.starts_with("/items(") && rest.ends_with(')') && self.method == Method::Post {
+            let merge = match self.http_method.as_ref().map(|m| m.as_str()) {
+                Some("PATCH") | Some("MERGE") => true,
+                _ => false,
+            };
+            let id: Option<i64> = rest["/items(".len()..rest.len() - 1].parse().ok();
+            let fields: Value = serde_json::from_str(&self.body).unwrap_or(Value::Null);
+            let item = list.items.iter_mut().find(|i| i["Id"].as_i64() == id);
+            return match (merge, item, fields) {
+                (true, Some(item), Value::Object(fields)) => {
+                    for (name, value) in fields {
+                        if name != "Id" {
+                            item[name.as_str()] = value;
+                        }
+                    }
+                    Response::new().with_status(StatusCode::NoContent)
+                }
+                (true, None, _) => odata_error(
+                    StatusCode::NotFound,
+                    "-2147024809, System.ArgumentException",
+                    "Item does not exist. It may have been deleted by another user.",
+                ),
+                _ => odata_error(StatusCode::BadRequest, "-1, Microsoft.SharePoint.Client.InvalidClientQueryException", "Invalid request."),
+            };
+        }
+        odata_error(StatusCode::BadRequest, "-1, Microsoft.SharePoint.Client.InvalidClientQueryException", "Unsupported request.")
+    }
+}
@@ -0,0 +1,449 @@
+//! NTLMv2 handshake for on-premises farms using Windows authentication.
+//!
+//! A new connection is authenticated by sending the request twice over it: once with a negotiate
+//! message, and once more answering the server's challenge. Further requests of the account go
+//! over that connection as they are, see `Connections`. Only the NTLMv2 response is computed, and
+//! messages are neither signed nor sealed.
+extern crate base64;
+extern crate futures;
+extern crate hmac;
+extern crate md4;
+extern crate md5;
+extern crate rand;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::StatusCode;
+
+use self::futures::{future, Future};
+use self::hmac::{Hmac, Mac};
+use self::md4::{Digest, Md4};
+use self::md5::Md5;
+
+use client::{Client, SharePointFuture};
+use on_premises::WindowsCredentials;
+use transport::{HeaderItem, Transport, TransportRequest, TransportResponse};
+
+static SIGNATURE: &'static [u8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+/// `MsvAvTimestamp` in the target information of the challenge.
+const AV_TIMESTAMP: u16 = 7;
+
+/// Seconds between 1601-01-01, where Windows file times start, and 1970-01-01.
+const FILETIME_UNIX_EPOCH_SECONDS: u64 = 11_644_473_600;
+
+/// What the server sends in its `CHALLENGE_MESSAGE`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Challenge {
+    pub flags: u32,
+    pub server_challenge: [u8; 8],
+    pub target_info: Vec<u8>,
+}
+
+/// The `NEGOTIATE_MESSAGE` starting the handshake, without domain or workstation.
+pub(crate) fn negotiate_message() -> Vec<u8> {
+    let mut message = SIGNATURE.to_vec();
+    push_u32(&mut message, 1);
+    push_u32(&mut message, NEGOTIATE_FLAGS);
+    // empty domain and workstation fields
+    message.extend_from_slice(&[0; 16]);
+    message
+}
+
+/// Reads the `CHALLENGE_MESSAGE` sent back by the server.
+pub(crate) fn parse_challenge(message: &[u8]) -> Option<Challenge> {
+    if message.len() < 32 || &message[..8] != SIGNATURE || read_u32(message, 8)? != 2 {
+        return None;
+    }
+    let flags = read_u32(message, 20)?;
+    let mut server_challenge = [0; 8];
+    server_challenge.copy_from_slice(&message[24..32]);
+    let target_info = if flags & NEGOTIATE_TARGET_INFO != 0 {
+        let len = read_u16(message, 40)? as usize;
+        let offset = read_u32(message, 44)? as usize;
+        message.get(offset..offset + len)?.to_vec()
+    } else {
+        Vec::new()
+    };
+    Some(Challenge {
+        flags: flags,
+        server_challenge: server_challenge,
+        target_info: target_info,
+    })
+}
+
+/// The `AUTHENTICATE_MESSAGE` answering `challenge` with the NTLMv2 response for `credentials`.
+///
+/// `timestamp` is the current time in Windows file time, used unless the server sent its own.
+pub(crate) fn authenticate_message(
+    credentials: &WindowsCredentials,
+    challenge: &Challenge,
+    client_challenge: [u8; 8],
+    timestamp: u64,
+) -> Vec<u8> {
+    let key = ntowf_v2(credentials);
+    let server_timestamp = av_pair(&challenge.target_info, AV_TIMESTAMP);
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    match server_timestamp {
+        Some(ref t) if t.len() == 8 => blob.extend_from_slice(t),
+        _ => blob.extend_from_slice(&u64_le(timestamp)),
+    }
+    blob.extend_from_slice(&client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&challenge.target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let mut nt_response = hmac_md5(&key, &[&challenge.server_challenge, &blob]);
+    nt_response.extend_from_slice(&blob);
+    // with a server timestamp the LMv2 response has to be left empty
+    let lm_response = if server_timestamp.is_some() {
+        vec![0; 24]
+    } else {
+        let mut lm_response = hmac_md5(&key, &[&challenge.server_challenge, &client_challenge]);
+        lm_response.extend_from_slice(&client_challenge);
+        lm_response
+    };
+
+    let unicode = challenge.flags & NEGOTIATE_UNICODE != 0;
+    let encode = |s: &str| if unicode { utf16le(s) } else { s.as_bytes().to_vec() };
+    let fields = [
+        lm_response,
+        nt_response,
+        encode(&credentials.domain),
+        encode(&credentials.user_name),
+        encode(""),
+        Vec::new(),
+    ];
+    let mut message = SIGNATURE.to_vec();
+    push_u32(&mut message, 3);
+    let mut offset = 64;
+    for field in fields.iter() {
+        push_u16(&mut message, field.len() as u16);
+        push_u16(&mut message, field.len() as u16);
+        push_u32(&mut message, offset as u32);
+        offset += field.len();
+    }
+    push_u32(&mut message, challenge.flags & NEGOTIATE_FLAGS);
+    for field in fields.iter() {
+        message.extend_from_slice(field);
+    }
+    message
+}
+
+/// A `CHALLENGE_MESSAGE` with `server_challenge` and `target_info`, as a server sends it.
+#[cfg(any(test, feature = "mock-server"))]
+pub(crate) fn challenge_message(server_challenge: [u8; 8], target_info: &[u8]) -> Vec<u8> {
+    let mut message = SIGNATURE.to_vec();
+    push_u32(&mut message, 2);
+    // empty target name
+    push_u16(&mut message, 0);
+    push_u16(&mut message, 0);
+    push_u32(&mut message, 48);
+    push_u32(&mut message, NEGOTIATE_FLAGS | NEGOTIATE_TARGET_INFO);
+    message.extend_from_slice(&server_challenge);
+    message.extend_from_slice(&[0; 8]);
+    push_u16(&mut message, target_info.len() as u16);
+    push_u16(&mut message, target_info.len() as u16);
+    push_u32(&mut message, 48);
+    message.extend_from_slice(target_info);
+    message
+}
+
+/// Whether `message` is an `AUTHENTICATE_MESSAGE` of `credentials` answering `server_challenge`.
+#[cfg(any(test, feature = "mock-server"))]
+pub(crate) fn verify_authenticate_message(message: &[u8], credentials: &WindowsCredentials, server_challenge: &[u8; 8]) -> bool {
+    let field = |i: usize| -> Option<&[u8]> {
+        let len = read_u16(message, 12 + 8 * i)? as usize;
+        let offset = read_u32(message, 16 + 8 * i)? as usize;
+        message.get(offset..offset + len)
+    };
+    if message.len() < 64 || &message[..8] != SIGNATURE || read_u32(message, 8) != Some(3) {
+        return false;
+    }
+    match (field(1), field(2), field(3)) {
+        (Some(nt_response), Some(domain), Some(user_name)) if nt_response.len() > 16 => {
+            domain == utf16le(&credentials.domain).as_slice()
+                && user_name == utf16le(&credentials.user_name).as_slice()
+                && hmac_md5(&ntowf_v2(credentials), &[server_challenge, &nt_response[16..]]) == nt_response[..16].to_vec()
+        }
+        _ => false,
+    }
+}
+
+/// `NTOWFv2`, the key of the NTLMv2 responses.
+fn ntowf_v2(credentials: &WindowsCredentials) -> Vec<u8> {
+    let nt_hash = Md4::digest(utf16le(&credentials.password));
+    let identity = utf16le(&format!("{}{}", credentials.user_name.to_uppercase(), credentials.domain));
+    hmac_md5(&nt_hash, &[&identity])
+}
+
+fn hmac_md5(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    let mut mac = Hmac::<Md5>::new_from_slice(key).expect("HMAC takes keys of any length");
+    for d in data {
+        mac.update(d);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Value of the first `AV_PAIR` with id `id`.
+fn av_pair(target_info: &[u8], id: u16) -> Option<Vec<u8>> {
+    let mut rest = target_info;
+    while rest.len() >= 4 {
+        let av_id = read_u16(rest, 0)?;
+        let len = read_u16(rest, 2)? as usize;
+        let value = rest.get(4..4 + len)?;
+        if av_id == 0 {
+            return None;
+        }
+        if av_id == id {
+            return Some(value.to_vec());
+        }
+        rest = &rest[4 + len..];
+    }
+    None
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect()
+}
+
+fn u64_le(n: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (n >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+fn push_u16(message: &mut Vec<u8>, n: u16) {
+    message.extend_from_slice(&u64_le(n as u64)[..2]);
+}
+
+fn push_u32(message: &mut Vec<u8>, n: u32) {
+    message.extend_from_slice(&u64_le(n as u64)[..4]);
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    let bytes = message.get(offset..offset + 2)?;
+    Some(bytes[0] as u16 | (bytes[1] as u16) << 8)
+}
+
+fn read_u32(message: &[u8], offset: usize) -> Option<u32> {
+    Some(read_u16(message, offset)? as u32 | (read_u16(message, offset + 2)? as u32) << 16)
+}
+
+/// Current time in Windows file time, 100 ns intervals since 1601-01-01.
+fn filetime_now() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs() + FILETIME_UNIX_EPOCH_SECONDS) * 10_000_000 + since_epoch.subsec_nanos() as u64 / 100
+}
+
+/// The decoded token of the first `WWW-Authenticate: {scheme} <token>` challenge of `res`.
+fn challenge_token(res: &TransportResponse, scheme: &str) -> Option<Vec<u8>> {
+    res.headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("WWW-Authenticate"))
+        .flat_map(|h| h.value.split(','))
+        .filter_map(|c| {
+            let mut parts = c.trim().splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some(s), Some(token)) if s.eq_ignore_ascii_case(scheme) => base64::decode(token.trim()).ok(),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Whether `res` offers authentication with `scheme`.
+fn offers(res: &TransportResponse, scheme: &str) -> bool {
+    res.headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("WWW-Authenticate"))
+        .flat_map(|h| h.value.split(','))
+        .any(|c| c.trim().split(' ').next().map_or(false, |s| s.eq_ignore_ascii_case(scheme)))
+}
+
+fn with_authorization(mut request: TransportRequest, scheme: &str, message: &[u8]) -> TransportRequest {
+    request.headers.retain(|h| !h.name.eq_ignore_ascii_case("Authorization"));
+    request.headers.push(HeaderItem {
+        name: "Authorization".to_string(),
+        value: format!("{} {}", scheme, base64::encode(message)),
+    });
+    request
+}
+
+/// Idle connections authenticated with NTLM, by account; NTLM authenticates the connection,
+/// so each is used by one request at a time.
+#[derive(Clone, Default)]
+pub(crate) struct Connections(Rc<RefCell<HashMap<WindowsCredentials, Vec<Connection>>>>);
+
+type Connection = Rc<dyn Transport>;
+
+impl Connections {
+    fn take(&self, credentials: &WindowsCredentials) -> Option<Connection> {
+        self.0.borrow_mut().get_mut(credentials).and_then(|idle| idle.pop())
+    }
+
+    fn put_back(&self, credentials: WindowsCredentials, connection: Connection) {
+        self.0.borrow_mut().entry(credentials).or_default().push(connection);
+    }
+}
+
+/// Sends `request` authenticated with `credentials`, answering the server's NTLM challenge.
+///
+/// The request goes over an idle connection of `credentials` if there is one, and gets the
+/// handshake only if the server answers `401` there; otherwise the handshake authenticates a new
+/// connection, kept for the next request once the response is in. Servers offering only
+/// `Negotiate` get the same NTLM messages under that scheme, which they accept in place of
+/// Kerberos. A response without a challenge is returned as it is.
+pub(crate) fn send(client: &Client, request: TransportRequest, credentials: WindowsCredentials, secret: bool) -> SharePointFuture<TransportResponse> {
+    let transport = client.transport.clone();
+    let connections = client.ntlm_connections.clone();
+    client.send_with(
+        request,
+        secret,
+        Rc::new(move |request| {
+            let credentials = credentials.clone();
+            let (connection, exchange) = match connections.take(&credentials) {
+                Some(connection) => {
+                    let exchange = resend(connection.clone(), request, credentials.clone());
+                    (connection, exchange)
+                }
+                None => match transport.dedicated_connection() {
+                    Ok(connection) => {
+                        let exchange = handshake(connection.clone(), request, credentials.clone(), "NTLM");
+                        (connection, exchange)
+                    }
+                    Err(e) => return Box::new(future::err(e)),
+                },
+            };
+            let connections = connections.clone();
+            Box::new(exchange.map(move |res| {
+                connections.put_back(credentials, connection);
+                res
+            }))
+        }),
+    )
+}
+
+/// Sends `request` over a `connection` authenticated before, with the handshake if the server
+/// no longer takes it as authenticated.
+fn resend(connection: Rc<dyn Transport>, request: TransportRequest, credentials: WindowsCredentials) -> SharePointFuture<TransportResponse> {
+    Box::new(connection.send(request.clone()).and_then(move |res| -> SharePointFuture<TransportResponse> {
+        if res.status == StatusCode::Unauthorized {
+            handshake(connection, request, credentials, "NTLM")
+        } else {
+            Box::new(future::ok(res))
+        }
+    }))
+}
+
+fn handshake(
+    connection: Rc<dyn Transport>,
+    request: TransportRequest,
+    credentials: WindowsCredentials,
+    scheme: &'static str,
+) -> SharePointFuture<TransportResponse> {
+    let negotiate = with_authorization(request.clone(), scheme, &negotiate_message());
+    Box::new(connection.send(negotiate).and_then(move |res| -> SharePointFuture<TransportResponse> {
+        if res.status != StatusCode::Unauthorized {
+            return Box::new(future::ok(res));
+        }
+        match challenge_token(&res, scheme).and_then(|token| parse_challenge(&token)) {
+            Some(challenge) => {
+                let authenticate = authenticate_message(&credentials, &challenge, rand::random(), filetime_now());
+                connection.send(with_authorization(request, scheme, &authenticate))
+            }
+            None if scheme == "NTLM" && offers(&res, "Negotiate") => handshake(connection, request, credentials, "Negotiate"),
+            None => Box::new(future::ok(res)),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn credentials() -> WindowsCredentials {
+        WindowsCredentials::new("Domain", "User", "Password")
+    }
+
+    /// Target information of the MS-NLMP NTLMv2 example: `Domain` on computer `Server`.
+    fn target_info() -> Vec<u8> {
+        let mut info = vec![2, 0, 12, 0];
+        info.extend(utf16le("Domain"));
+        info.extend(vec![1, 0, 12, 0]);
+        info.extend(utf16le("Server"));
+        info.extend(vec![0, 0, 0, 0]);
+        info
+    }
+
+    #[test]
+    fn computes_ntlm_v2_response() {
+        assert_eq!(hex(&Md4::digest(utf16le("Password"))), "a4f49c406510bdcab6824ee7c30fd852");
+        assert_eq!(hex(&ntowf_v2(&credentials())), "0c868a403bfd7a93a3001ef22ef02e3f");
+
+        let challenge = Challenge {
+            flags: NEGOTIATE_FLAGS | NEGOTIATE_TARGET_INFO,
+            server_challenge: [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+            target_info: target_info(),
+        };
+        let message = authenticate_message(&credentials(), &challenge, [0xaa; 8], 0);
+        assert_eq!(&message[..12], b"NTLMSSP\0\x03\0\0\0");
+
+        let field = |i: usize| {
+            let len = read_u16(&message, 12 + 8 * i).unwrap() as usize;
+            let offset = read_u32(&message, 16 + 8 * i).unwrap() as usize;
+            message[offset..offset + len].to_vec()
+        };
+        assert_eq!(hex(&field(0)), "86c35097ac9cec102554764a57cccc19aaaaaaaaaaaaaaaa");
+        assert_eq!(hex(&field(1)[..16]), "68cd0ab851e51c96aabc927bebef6a1c");
+        assert_eq!(field(2), utf16le("Domain"));
+        assert_eq!(field(3), utf16le("User"));
+
+        let challenge_bytes = challenge.server_challenge;
+        assert!(verify_authenticate_message(&message, &credentials(), &challenge_bytes));
+        assert!(!verify_authenticate_message(&message, &WindowsCredentials::new("Domain", "User", "wrong"), &challenge_bytes));
+    }
+
+    #[test]
+    fn parses_challenge_message() {
+        let info = target_info();
+        let message = challenge_message([1, 2, 3, 4, 5, 6, 7, 8], &info);
+
+        let challenge = parse_challenge(&message).unwrap();
+        assert_eq!(challenge.server_challenge, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(challenge.target_info, info);
+        assert_eq!(av_pair(&challenge.target_info, 1), Some(utf16le("Server")));
+        assert_eq!(av_pair(&challenge.target_info, AV_TIMESTAMP), None);
+
+        assert_eq!(parse_challenge(&negotiate_message()), None);
+        assert_eq!(parse_challenge(&message[..20]), None);
+    }
+}
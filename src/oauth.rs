@@ -0,0 +1,512 @@
+//! App-only authentication with an Azure AD app registration or a SharePoint add-in
+//! registered with the Access Control Service (`appregnew.aspx`), sending
+//! `Authorization: Bearer` instead of the `rtFa`/`FedAuth` cookies.
+extern crate futures;
+
+use std::time::{Duration, SystemTime};
+
+use hyper::Method;
+
+use self::futures::{future, Future};
+use self::serde_json::Value;
+
+#[allow(unused_imports)]
+use super::*;
+
+use auth::{parse_site, Authorization, BearerToken, Credentials, LoginConfig, LoginContext, RequestDigest};
+use client::{block_on, Client, SharePointClient, SharePointFuture};
+use data::status_error;
+use error::{Error, OAuthError, Result};
+use transport::{HeaderItem, Timeouts, TransportRequest, TransportResponse};
+
+/// Application id of SharePoint Online itself, the resource add-ins request tokens for.
+static SHAREPOINT_PRINCIPAL: &'static str = "00000003-0000-0ff1-ce00-000000000000";
+static CLIENT_SVC_URL: &'static str = "{origin}/_vti_bin/client.svc";
+
+#[derive(Debug, Deserialize, Default)]
+struct TokenResponse {
+    #[serde(default)]
+    access_token: String,
+    /// Seconds, a number from the v2.0 endpoint and a string from the v1.0 one.
+    #[serde(default)]
+    expires_in: Option<Value>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct TokenErrorResponse {
+    #[serde(default)]
+    error: String,
+    #[serde(default)]
+    error_description: String,
+}
+
+/// `application/x-www-form-urlencoded` body made of `pairs`.
+pub(crate) fn form_urlencoded(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|&(name, value)| format!("{}={}", form_urlencode(name), form_urlencode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn form_urlencode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => encoded.push(b as char),
+            b' ' => encoded.push('+'),
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Value of parameter `name` in a challenge like `Bearer realm="...",client_id="..."`.
+fn challenge_parameter(challenge: &str, name: &str) -> Option<String> {
+    let mut challenge = challenge.trim().splitn(2, char::is_whitespace);
+    let params = match (challenge.next(), challenge.next()) {
+        (Some(scheme), Some(params)) if scheme.eq_ignore_ascii_case("Bearer") => params,
+        _ => return None,
+    };
+    params.split(',').filter_map(|param| {
+        let mut parts = param.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim().trim_matches('"').to_string()),
+            _ => None,
+        }
+    }).next()
+}
+
+fn parse_realm(res: TransportResponse) -> Result<String> {
+    let realm = res.headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("WWW-Authenticate"))
+        .filter_map(|h| challenge_parameter(&h.value, "realm"))
+        .find(|realm| !realm.is_empty());
+    match realm {
+        Some(realm) => Ok(realm),
+        None => Err(status_error(res.status, res.body)),
+    }
+}
+
+fn parse_token(res: TransportResponse) -> Result<BearerToken> {
+    if res.status.is_client_error() || res.status.is_server_error() {
+        return Err(match serde_json::from_str::<TokenErrorResponse>(&res.body) {
+            Ok(ref e) if !e.error.is_empty() => Error::OAuth(OAuthError {
+                status: res.status,
+                error: e.error.to_owned(),
+                description: e.error_description.to_owned(),
+            }),
+            _ => status_error(res.status, res.body),
+        });
+    }
+    let token: TokenResponse = serde_json::from_str(&res.body)?;
+    let expires_in = token.expires_in.and_then(|v| match v {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    });
+    Ok(BearerToken {
+        access_token: token.access_token,
+        valid_until: expires_in.map(|seconds| SystemTime::now() + Duration::from_secs(seconds)),
+    })
+}
+
+/// Logs in to `site` as the Azure AD app `client_id` of `tenant` (e.g. `company.onmicrosoft.com`),
+/// with the client credentials grant.
+pub fn login_with_client_secret(
+    site: String,
+    tenant: String,
+    client_id: String,
+    client_secret: String,
+    config: LoginConfig,
+) -> Result<LoginContext> {
+    block_on(|client| client.login_with_client_secret(site, tenant, client_id, client_secret, config))
+}
+
+/// Logs in to `site` as the SharePoint add-in `client_id`, getting its token from the
+/// Access Control Service of the realm the site announces.
+pub fn login_with_add_in(site: String, client_id: String, client_secret: String, config: LoginConfig) -> Result<LoginContext> {
+    block_on(|client| client.login_with_add_in(site, client_id, client_secret, config))
+}
+
+impl SharePointClient {
+    /// `oauth::login_with_add_in` reusing this client's connections.
+    pub fn login_with_add_in(&mut self, site: String, client_id: String, client_secret: String, config: LoginConfig) -> Result<LoginContext> {
+        let work = self.client().login_with_add_in(site, client_id, client_secret, config);
+        self.run(work)
+    }
+
+    /// `oauth::login_with_client_secret` reusing this client's connections.
+    pub fn login_with_client_secret(
+        &mut self,
+        site: String,
+        tenant: String,
+        client_id: String,
+        client_secret: String,
+        config: LoginConfig,
+    ) -> Result<LoginContext> {
+        let work = self.client().login_with_client_secret(site, tenant, client_id, client_secret, config);
+        self.run(work)
+    }
+}
+
+impl Client {
+    /// Asynchronous `oauth::login_with_client_secret`.
+    pub fn login_with_client_secret(
+        &self,
+        site: String,
+        tenant: String,
+        client_id: String,
+        client_secret: String,
+        config: LoginConfig,
+    ) -> SharePointFuture<LoginContext> {
+        let site = match parse_site(&site) {
+            Ok(site) => site,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let credentials = Credentials::ClientSecret {
+            tenant: tenant.to_owned(),
+            client_id: client_id.to_owned(),
+            client_secret: client_secret.to_owned(),
+        };
+        Box::new(
+            self.sign_in_with_client_secret(&config, site.clone(), tenant, client_id, client_secret)
+                .map(move |(authorization, digest)| LoginContext::new(site, authorization, digest, config).with_renewal(credentials)),
+        )
+    }
+
+    /// Gets an app-only token for the site's host and a request digest with it.
+    pub(crate) fn sign_in_with_client_secret(
+        &self,
+        config: &LoginConfig,
+        site: Site,
+        tenant: String,
+        client_id: String,
+        client_secret: String,
+    ) -> SharePointFuture<(Authorization, RequestDigest)> {
+        self.sign_in_with_app_credentials(config, site, &tenant, &[("client_id", &client_id), ("client_secret", &client_secret)])
+    }
+
+    /// Requests a client credentials token for the site's host from the token endpoint of `tenant`,
+    /// authenticating the app with `credentials`, then a request digest with the token.
+    pub(crate) fn sign_in_with_app_credentials(
+        &self,
+        config: &LoginConfig,
+        site: Site,
+        tenant: &str,
+        credentials: &[(&str, &str)],
+    ) -> SharePointFuture<(Authorization, RequestDigest)> {
+        let (origin, _) = match config.origin_and_path(site.clone()) {
+            Ok(origin_and_path) => origin_and_path,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let scope = format!("{}/.default", origin);
+        let mut pairs = vec![("grant_type", "client_credentials")];
+        pairs.extend_from_slice(credentials);
+        pairs.push(("scope", &scope));
+        self.sign_in_with_token_request(config, site, config.token_url.replace("{tenant}", tenant), form_urlencoded(&pairs))
+    }
+
+    /// Posts the token request `form` to `token_url` and gets a request digest with the token.
+    fn sign_in_with_token_request(
+        &self,
+        config: &LoginConfig,
+        site: Site,
+        token_url: String,
+        form: String,
+    ) -> SharePointFuture<(Authorization, RequestDigest)> {
+        let client = self.clone();
+        let config = config.clone();
+        Box::new(self.request_app_token(token_url, form).and_then(move |token| {
+            let authorization = Authorization::Bearer(token);
+            client
+                .request_digest(&config, site, authorization.clone())
+                .map(move |digest| (authorization, digest))
+        }))
+    }
+
+    /// Asynchronous `oauth::login_with_add_in`.
+    pub fn login_with_add_in(
+        &self,
+        site: String,
+        client_id: String,
+        client_secret: String,
+        config: LoginConfig,
+    ) -> SharePointFuture<LoginContext> {
+        let site = match parse_site(&site) {
+            Ok(site) => site,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let credentials = Credentials::AddIn {
+            client_id: client_id.to_owned(),
+            client_secret: client_secret.to_owned(),
+        };
+        Box::new(
+            self.sign_in_with_add_in(&config, site.clone(), client_id, client_secret)
+                .map(move |(authorization, digest)| LoginContext::new(site, authorization, digest, config).with_renewal(credentials)),
+        )
+    }
+
+    /// Realm (tenant id) of `site`, read from the bearer challenge of `/_vti_bin/client.svc`.
+    pub fn get_realm(&self, config: &LoginConfig, site: Site) -> SharePointFuture<String> {
+        let (origin, _) = match config.origin_and_path(site) {
+            Ok(origin_and_path) => origin_and_path,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let request = TransportRequest {
+            method: Method::Get,
+            url: CLIENT_SVC_URL.replace("{origin}", &origin),
+            headers: vec![HeaderItem {
+                name: "Authorization".to_string(),
+                value: "Bearer".to_string(),
+            }],
+            body: String::new(),
+            timeouts: Timeouts::default(),
+        };
+        Box::new(self.send(request, true).and_then(parse_realm))
+    }
+
+    /// Gets an add-in token from the Access Control Service and a request digest with it.
+    pub(crate) fn sign_in_with_add_in(
+        &self,
+        config: &LoginConfig,
+        site: Site,
+        client_id: String,
+        client_secret: String,
+    ) -> SharePointFuture<(Authorization, RequestDigest)> {
+        let authority = match site.parent.parse::<hyper::Uri>().ok().and_then(|uri| uri.authority().map(|a| a.to_string())) {
+            Some(authority) => authority,
+            None => return Box::new(future::err(Error::Uri(site.parent.to_owned()))),
+        };
+        let client = self.clone();
+        let config = config.clone();
+        Box::new(self.get_realm(&config, site.clone()).and_then(move |realm| {
+            let form = form_urlencoded(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", &format!("{}@{}", client_id, realm)),
+                ("client_secret", &client_secret),
+                ("resource", &format!("{}/{}@{}", SHAREPOINT_PRINCIPAL, authority, realm)),
+            ]);
+            client.sign_in_with_token_request(&config, site, config.acs_token_url.replace("{realm}", &realm), form)
+        }))
+    }
+
+    /// Posts the token request `form` to `token_url`.
+    pub(crate) fn request_app_token(&self, token_url: String, form: String) -> SharePointFuture<BearerToken> {
+        let request = TransportRequest {
+            method: Method::Post,
+            url: token_url,
+            headers: vec![
+                HeaderItem {
+                    name: "Content-Type".to_string(),
+                    value: "application/x-www-form-urlencoded".to_string(),
+                },
+                HeaderItem {
+                    name: "Accept".to_string(),
+                    value: "application/json".to_string(),
+                },
+            ],
+            body: form,
+            timeouts: Timeouts::default(),
+        };
+        Box::new(self.send(request, true).and_then(parse_token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::tests::CONTEXT_INFO_RESPONSE;
+    use error::LoginErrorKind;
+    use hyper::StatusCode;
+    use transport::tests::FakeTransport;
+
+    static TOKEN_RESPONSE: &'static str = r#"{"token_type":"Bearer","expires_in":3599,"ext_expires_in":3599,"access_token":"app-token"}"#;
+
+    fn fake_app_login(transport: &FakeTransport) -> LoginContext {
+        transport
+            .respond(StatusCode::Ok, TOKEN_RESPONSE)
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        client.login_with_client_secret(
+            "https://company.sharepoint.com/sites/test/".to_string(),
+            "company.onmicrosoft.com".to_string(),
+            "client-id".to_string(),
+            "s3cret&=+".to_string(),
+            LoginConfig::default(),
+        ).unwrap()
+    }
+
+    #[test]
+    fn encodes_form_values() {
+        assert_eq!(
+            form_urlencoded(&[("scope", "https://company.sharepoint.com/.default"), ("secret", "a b&c=d~")]),
+            "scope=https%3A%2F%2Fcompany.sharepoint.com%2F.default&secret=a+b%26c%3Dd%7E"
+        );
+    }
+
+    #[test]
+    fn client_secret_login_sends_bearer_token() {
+        let transport = FakeTransport::default();
+        let login = fake_app_login(&transport);
+
+        let token = transport.request(0);
+        assert_eq!(token.url, "https://login.microsoftonline.com/company.onmicrosoft.com/oauth2/v2.0/token");
+        assert_eq!(token.header("Content-Type"), Some("application/x-www-form-urlencoded"));
+        assert_eq!(
+            token.body,
+            "grant_type=client_credentials&client_id=client-id&client_secret=s3cret%26%3D%2B&scope=https%3A%2F%2Fcompany.sharepoint.com%2F.default"
+        );
+
+        let digest = transport.request(1);
+        assert_eq!(digest.header("Authorization"), Some("Bearer app-token"));
+        assert_eq!(digest.header("Cookie"), None);
+        assert!(login.access_token().is_none());
+        match login.authorization() {
+            Authorization::Bearer(token) => assert!(token.valid_until.is_some()),
+            _ => panic!("expected a bearer token"),
+        }
+    }
+
+    #[test]
+    fn expiring_token_is_renewed_before_the_request() {
+        let transport = FakeTransport::default();
+        let login = fake_app_login(&transport);
+        let expiring = BearerToken {
+            access_token: "expiring-token".to_string(),
+            valid_until: Some(SystemTime::now()),
+        };
+        login.set_session(Authorization::Bearer(expiring), login.request_digest());
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+
+        transport
+            .respond(StatusCode::Ok, r#"{"token_type":"Bearer","expires_in":"3599","access_token":"new-token"}"#)
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE)
+            .respond(StatusCode::Ok, r#"{"Id":"a4b5d5e5-98a5-4c4b-8a3c-e9f6c1a2b3c4"}"#);
+        client.get_list_by_title("Tasks".to_string(), &login).unwrap();
+        assert!(transport.request(2).url.ends_with("/oauth2/v2.0/token"));
+        assert_eq!(transport.request(4).header("Authorization"), Some("Bearer new-token"));
+        assert!(!login.authorization().is_expiring());
+    }
+
+    #[test]
+    fn reports_token_endpoint_errors() {
+        let transport = FakeTransport::default();
+        transport.respond(
+            StatusCode::Unauthorized,
+            r#"{"error":"invalid_client","error_description":"AADSTS7000215: Invalid client secret provided.\r\nTrace ID: 1","error_codes":[7000215]}"#,
+        );
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        let res = client.login_with_client_secret(
+            "https://company.sharepoint.com/".to_string(),
+            "company.onmicrosoft.com".to_string(),
+            "client-id".to_string(),
+            "wrong".to_string(),
+            LoginConfig::default(),
+        );
+        match res {
+            Err(Error::OAuth(e)) => {
+                assert_eq!(e.error, "invalid_client");
+                assert_eq!(e.aadsts_code(), Some("AADSTS7000215"));
+                assert_eq!(e.kind(), LoginErrorKind::InvalidCredentials);
+            }
+            res => panic!("expected a token endpoint error, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn reads_bearer_challenge() {
+        let challenge = r#"Bearer realm="3c1b7d2e-1111-2222-3333-444455556666",client_id="00000003-0000-0ff1-ce00-000000000000",trusted_issuers="00000001-0000-0000-c000-000000000000@*""#;
+        assert_eq!(challenge_parameter(challenge, "realm"), Some("3c1b7d2e-1111-2222-3333-444455556666".to_string()));
+        assert_eq!(challenge_parameter(challenge, "client_id"), Some("00000003-0000-0ff1-ce00-000000000000".to_string()));
+        assert_eq!(challenge_parameter("NTLM", "realm"), None);
+        assert_eq!(challenge_parameter("BearerX realm=\"x\"", "realm"), None);
+        assert_eq!(challenge_parameter("Beare€ realm=\"x\"", "realm"), None);
+        assert_eq!(challenge_parameter("bearer\trealm=\"x\"", "realm"), Some("x".to_string()));
+    }
+
+    #[test]
+    fn add_in_login_discovers_realm() {
+        let transport = FakeTransport::default();
+        transport
+            .respond_with_headers(
+                StatusCode::Unauthorized,
+                "",
+                vec![("WWW-Authenticate", r#"Bearer realm="realm-id",client_id="00000003-0000-0ff1-ce00-000000000000""#)],
+            )
+            .respond(StatusCode::Ok, r#"{"token_type":"Bearer","expires_in":"3599","access_token":"acs-token"}"#)
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        client.login_with_add_in(
+            "https://company.sharepoint.com/sites/test/".to_string(),
+            "add-in-id".to_string(),
+            "add-in-secret".to_string(),
+            LoginConfig::default(),
+        ).unwrap();
+
+        let challenge = transport.request(0);
+        assert_eq!(challenge.method, Method::Get);
+        assert_eq!(challenge.url, "https://company.sharepoint.com/_vti_bin/client.svc");
+        assert_eq!(challenge.header("Authorization"), Some("Bearer"));
+
+        let token = transport.request(1);
+        assert_eq!(token.url, "https://accounts.accesscontrol.windows.net/realm-id/tokens/OAuth/2");
+        assert_eq!(
+            token.body,
+            "grant_type=client_credentials&client_id=add-in-id%40realm-id&client_secret=add-in-secret&resource=00000003-0000-0ff1-ce00-000000000000%2Fcompany.sharepoint.com%40realm-id"
+        );
+        assert_eq!(transport.request(2).header("Authorization"), Some("Bearer acs-token"));
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[test]
+    fn add_in_login_against_mock_server() {
+        use mock::MockServer;
+
+        let server = MockServer::start();
+        server.create_list("Tasks");
+        let login = login_with_add_in(
+            server.site_url(),
+            MockServer::CLIENT_ID.to_string(),
+            MockServer::CLIENT_SECRET.to_string(),
+            server.login_config(),
+        ).unwrap();
+        server.expire_sessions();
+        assert!(list::get_list_by_title("Tasks".to_string(), login).is_ok());
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[test]
+    fn app_only_login_against_mock_server() {
+        use mock::MockServer;
+
+        let server = MockServer::start();
+        server.create_list("Tasks");
+        let mut client = SharePointClient::new().unwrap();
+        let login = client.login_with_client_secret(
+            server.site_url(),
+            "mock.onmicrosoft.com".to_string(),
+            MockServer::CLIENT_ID.to_string(),
+            MockServer::CLIENT_SECRET.to_string(),
+            server.login_config(),
+        ).unwrap();
+        let list = client.get_list_by_title("Tasks".to_string(), &login).unwrap();
+        assert!(!list.id.is_nil());
+
+        server.expire_sessions();
+        assert!(client.get_list_by_title("Tasks".to_string(), &login).is_ok());
+
+        let res = client.login_with_client_secret(
+            server.site_url(),
+            "mock.onmicrosoft.com".to_string(),
+            MockServer::CLIENT_ID.to_string(),
+            "wrong".to_string(),
+            server.login_config(),
+        );
+        match res {
+            Err(Error::OAuth(e)) => assert_eq!(e.kind(), LoginErrorKind::InvalidCredentials),
+            res => panic!("expected a token endpoint error, got {:?}", res.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,447 @@
+//! OData query options of item queries: `$select`, `$filter`, `$expand`, `$orderby`, `$top`
+//! and `$skip`, URL-encoded when appended to the request.
+//!
+//! `$filter` expressions are built with `field`, so literals are quoted and escaped:
+//!
+//! ```
+//! # extern crate sharepoint;
+//! # use std::time::{Duration, UNIX_EPOCH};
+//! # use sharepoint::odata::{field, QueryOptions};
+//! # fn main() {
+//! let since = UNIX_EPOCH + Duration::from_secs(1_514_764_800);
+//! let options = QueryOptions::new()
+//!     .select(&["Id", "Title", "Author/Title"])
+//!     .expand(&["Author"])
+//!     .filter(field("Status").ne("Done").and(field("Modified").gt(since)).and(!field("Title").starts_with("Bob's")))
+//!     .orderby_desc("Modified")
+//!     .top(50);
+//! assert_eq!(
+//!     options.filter.as_ref().unwrap().to_string(),
+//!     "Status ne 'Done' and Modified gt datetime'2018-01-01T00:00:00Z' and not startswith(Title,'Bob''s')"
+//! );
+//! # }
+//! ```
+use std::fmt;
+use std::ops;
+use std::time::SystemTime;
+
+use error::{Error, Result};
+use timestamp::utc_timestamp;
+
+/// Percent-encodes an OData query option value or path literal.
+pub(crate) fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            b'\'' | b'(' | b')' | b',' | b'/' | b':' | b'*' => encoded.push(b as char),
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// `value` as an OData string literal in a path, e.g. `GetByTitle('Bob''s%20list')`.
+pub(crate) fn path_literal(value: &str) -> String {
+    format!("'{}'", encode(&value.replace('\'', "''")))
+}
+
+/// A value compared with a field in a `Filter`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    String(String),
+    Int(i64),
+    /// Built with `Literal::float`, which rejects `NaN` and infinities.
+    Float(Double),
+    Bool(bool),
+    /// Sent as `datetime'2018-01-01T00:00:00Z'`, in UTC.
+    DateTime(SystemTime),
+    Null,
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Literal::String(ref s) => write!(f, "'{}'", s.replace('\'', "''")),
+            Literal::Int(i) => write!(f, "{}", i),
+            Literal::Float(ref x) => write!(f, "{}", x),
+            Literal::Bool(b) => write!(f, "{}", b),
+            Literal::DateTime(time) => write!(f, "datetime'{}'", utc_timestamp(time)),
+            Literal::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl Literal {
+    /// A floating point literal, or `Error::InvalidLiteral` for `NaN` and infinities, which OData
+    /// cannot express.
+    pub fn float(x: f64) -> Result<Literal> {
+        if x.is_finite() {
+            Ok(Literal::Float(Double(x)))
+        } else {
+            Err(Error::InvalidLiteral(format!("{}", x)))
+        }
+    }
+}
+
+/// A finite `f64`, sent as `9.5` or, when very large or small, as `1E+20`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Double(f64);
+
+impl Double {
+    pub fn value(&self) -> f64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Double {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let magnitude = self.0.abs();
+        if magnitude == 0.0 || (magnitude >= 1e-5 && magnitude < 1e15) {
+            return write!(f, "{}", self.0);
+        }
+        // `{:E}` gives `1E20` and `1.5E-7`, OData's double literals spell out the exponent sign
+        let exponent = format!("{:E}", self.0);
+        match exponent.find("E-") {
+            Some(_) => write!(f, "{}", exponent),
+            None => write!(f, "{}", exponent.replacen('E', "E+", 1)),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Literal {
+    fn from(s: &'a str) -> Literal {
+        Literal::String(s.to_owned())
+    }
+}
+
+impl From<String> for Literal {
+    fn from(s: String) -> Literal {
+        Literal::String(s)
+    }
+}
+
+impl From<i32> for Literal {
+    fn from(i: i32) -> Literal {
+        Literal::Int(i64::from(i))
+    }
+}
+
+impl From<i64> for Literal {
+    fn from(i: i64) -> Literal {
+        Literal::Int(i)
+    }
+}
+
+impl From<u32> for Literal {
+    fn from(i: u32) -> Literal {
+        Literal::Int(i64::from(i))
+    }
+}
+
+impl From<bool> for Literal {
+    fn from(b: bool) -> Literal {
+        Literal::Bool(b)
+    }
+}
+
+impl From<SystemTime> for Literal {
+    fn from(time: SystemTime) -> Literal {
+        Literal::DateTime(time)
+    }
+}
+
+/// A field of the items, by its internal name, e.g. `Title` or `Author/Title` of an expanded lookup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field(String);
+
+/// The field `name`, to build a `Filter` from.
+pub fn field(name: &str) -> Field {
+    Field(name.to_owned())
+}
+
+impl Field {
+    fn compare<V: Into<Literal>>(&self, operator: &str, value: V) -> Filter {
+        Filter::new(format!("{} {} {}", self.0, operator, value.into()), Kind::Comparison)
+    }
+
+    pub fn eq<V: Into<Literal>>(&self, value: V) -> Filter {
+        self.compare("eq", value)
+    }
+
+    pub fn ne<V: Into<Literal>>(&self, value: V) -> Filter {
+        self.compare("ne", value)
+    }
+
+    pub fn gt<V: Into<Literal>>(&self, value: V) -> Filter {
+        self.compare("gt", value)
+    }
+
+    pub fn ge<V: Into<Literal>>(&self, value: V) -> Filter {
+        self.compare("ge", value)
+    }
+
+    pub fn lt<V: Into<Literal>>(&self, value: V) -> Filter {
+        self.compare("lt", value)
+    }
+
+    pub fn le<V: Into<Literal>>(&self, value: V) -> Filter {
+        self.compare("le", value)
+    }
+
+    /// The text of the field starts with `prefix`.
+    pub fn starts_with(&self, prefix: &str) -> Filter {
+        Filter::new(format!("startswith({},{})", self.0, Literal::from(prefix)), Kind::Call)
+    }
+
+    /// The text of the field contains `text`.
+    pub fn substring_of(&self, text: &str) -> Filter {
+        Filter::new(format!("substringof({},{})", Literal::from(text), self.0), Kind::Call)
+    }
+}
+
+/// What is at the top of a `Filter` expression, to know when it needs parentheses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Comparison,
+    Call,
+    Not,
+    And,
+    Or,
+    /// Hand-written, always put in parentheses.
+    Raw,
+}
+
+/// A `$filter` expression; build it with `field`, `and`, `or` and `!`, or take a hand-written
+/// one with `Filter::from`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    expression: String,
+    kind: Kind,
+}
+
+impl Filter {
+    fn new(expression: String, kind: Kind) -> Filter {
+        Filter {
+            expression: expression,
+            kind: kind,
+        }
+    }
+
+    /// The expression as operand of `operator`, in parentheses unless it binds tighter.
+    fn operand(&self, operator: Kind) -> String {
+        let tighter = match self.kind {
+            Kind::Call | Kind::Not => true,
+            Kind::Comparison => operator != Kind::Not,
+            kind => kind == operator,
+        };
+        if tighter {
+            self.expression.to_owned()
+        } else {
+            format!("({})", self.expression)
+        }
+    }
+
+    /// Both this and `other` hold.
+    pub fn and(self, other: Filter) -> Filter {
+        let expression = format!("{} and {}", self.operand(Kind::And), other.operand(Kind::And));
+        Filter::new(expression, Kind::And)
+    }
+
+    /// This or `other` holds.
+    pub fn or(self, other: Filter) -> Filter {
+        let expression = format!("{} or {}", self.operand(Kind::Or), other.operand(Kind::Or));
+        Filter::new(expression, Kind::Or)
+    }
+}
+
+impl ops::Not for Filter {
+    type Output = Filter;
+
+    /// This does not hold.
+    fn not(self) -> Filter {
+        Filter::new(format!("not {}", self.operand(Kind::Not)), Kind::Not)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl<'a> From<&'a str> for Filter {
+    fn from(expression: &'a str) -> Filter {
+        Filter::new(expression.to_owned(), Kind::Raw)
+    }
+}
+
+impl From<String> for Filter {
+    fn from(expression: String) -> Filter {
+        Filter::new(expression, Kind::Raw)
+    }
+}
+
+/// OData query options sent with a query, see `list::get_list_items_by_title_with_query`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryOptions {
+    /// Fields to return instead of the default ones.
+    pub select: Vec<String>,
+    pub filter: Option<Filter>,
+    /// Lookup fields whose target is returned too, e.g. `Author`.
+    pub expand: Vec<String>,
+    /// Sort order, e.g. `Modified desc`.
+    pub orderby: Vec<String>,
+    pub top: Option<u32>,
+    /// Items skipped first; list items page with `$skiptoken` instead and ignore it.
+    pub skip: Option<u32>,
+}
+
+impl QueryOptions {
+    pub fn new() -> QueryOptions {
+        QueryOptions::default()
+    }
+
+    /// Returns `fields` too, besides those selected before.
+    pub fn select(mut self, fields: &[&str]) -> QueryOptions {
+        self.select.extend(fields.iter().map(|f| f.to_string()));
+        self
+    }
+
+    /// Returns only what matches `filter`, replacing the filter set before.
+    pub fn filter<F: Into<Filter>>(mut self, filter: F) -> QueryOptions {
+        self.filter = Some(filter.into());
+        self
+    }
+
+    /// Expands the lookup `fields` too.
+    pub fn expand(mut self, fields: &[&str]) -> QueryOptions {
+        self.expand.extend(fields.iter().map(|f| f.to_string()));
+        self
+    }
+
+    /// Sorts by `field` ascending, after the fields sorted by before.
+    pub fn orderby(mut self, field: &str) -> QueryOptions {
+        self.orderby.push(field.to_owned());
+        self
+    }
+
+    /// Sorts by `field` descending, after the fields sorted by before.
+    pub fn orderby_desc(mut self, field: &str) -> QueryOptions {
+        self.orderby.push(format!("{} desc", field));
+        self
+    }
+
+    pub fn top(mut self, count: u32) -> QueryOptions {
+        self.top = Some(count);
+        self
+    }
+
+    pub fn skip(mut self, count: u32) -> QueryOptions {
+        self.skip = Some(count);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == QueryOptions::default()
+    }
+
+    /// The options as URL-encoded query string, e.g. `$select=Id,Title&$top=10`.
+    pub fn to_query_string(&self) -> String {
+        let mut options = Vec::new();
+        if !self.select.is_empty() {
+            options.push(("$select", self.select.join(",")));
+        }
+        if let Some(ref filter) = self.filter {
+            options.push(("$filter", filter.to_string()));
+        }
+        if !self.expand.is_empty() {
+            options.push(("$expand", self.expand.join(",")));
+        }
+        if !self.orderby.is_empty() {
+            options.push(("$orderby", self.orderby.join(",")));
+        }
+        if let Some(top) = self.top {
+            options.push(("$top", top.to_string()));
+        }
+        if let Some(skip) = self.skip {
+            options.push(("$skip", skip.to_string()));
+        }
+        options
+            .iter()
+            .map(|&(name, ref value)| format!("{}={}", name, encode(value)))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// `url` with the options appended.
+    pub fn append_to(&self, url: &str) -> String {
+        if self.is_empty() {
+            return url.to_owned();
+        }
+        let separator = if url.contains('?') { '&' } else { '?' };
+        format!("{}{}{}", url, separator, self.to_query_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn literals_are_quoted_and_escaped() {
+        assert_eq!(field("Title").eq("Bob's").to_string(), "Title eq 'Bob''s'");
+        assert_eq!(field("Id").gt(10).to_string(), "Id gt 10");
+        assert_eq!(field("Price").le(Literal::float(9.5).unwrap()).to_string(), "Price le 9.5");
+        assert_eq!(field("Done").eq(true).to_string(), "Done eq true");
+        assert_eq!(field("Due").lt(UNIX_EPOCH + Duration::from_secs(1_500_000_000)).to_string(), "Due lt datetime'2017-07-14T02:40:00Z'");
+        assert_eq!(field("Manager").eq(Literal::Null).to_string(), "Manager eq null");
+        assert_eq!(field("Title").substring_of("a'b").to_string(), "substringof('a''b',Title)");
+    }
+
+    #[test]
+    fn floats_are_finite_and_use_odata_exponents() {
+        assert_eq!(Literal::float(0.0).unwrap().to_string(), "0");
+        assert_eq!(Literal::float(-2.25).unwrap().to_string(), "-2.25");
+        assert_eq!(Literal::float(1e20).unwrap().to_string(), "1E+20");
+        assert_eq!(Literal::float(-1.5e-7).unwrap().to_string(), "-1.5E-7");
+        for &x in &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            match Literal::float(x) {
+                Err(Error::InvalidLiteral(_)) => {}
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn combined_filters_keep_their_precedence() {
+        let a = || field("A").eq(1);
+        let b = || field("B").eq(2);
+        let c = || field("C").eq(3);
+        assert_eq!(a().and(b()).and(c()).to_string(), "A eq 1 and B eq 2 and C eq 3");
+        assert_eq!(a().or(b()).and(c()).to_string(), "(A eq 1 or B eq 2) and C eq 3");
+        assert_eq!(a().and(b().or(c())).to_string(), "A eq 1 and (B eq 2 or C eq 3)");
+        assert_eq!((!a().and(b())).to_string(), "not (A eq 1 and B eq 2)");
+        assert_eq!((!a()).or(Filter::from("B eq 2")).to_string(), "not (A eq 1) or (B eq 2)");
+        assert_eq!((!field("Title").starts_with("A")).and(c()).to_string(), "not startswith(Title,'A') and C eq 3");
+    }
+
+    #[test]
+    fn options_are_url_encoded() {
+        let options = QueryOptions::new()
+            .select(&["Id", "Title"])
+            .filter(field("Title").eq("A&B + C").or(field("Id").ge(100)))
+            .expand(&["Author"])
+            .orderby("Title")
+            .orderby_desc("Id")
+            .top(10)
+            .skip(20);
+        assert_eq!(
+            options.to_query_string(),
+            "$select=Id,Title&$filter=Title%20eq%20'A%26B%20%2B%20C'%20or%20Id%20ge%20100&$expand=Author&$orderby=Title,Id%20desc&$top=10&$skip=20"
+        );
+        assert_eq!(QueryOptions::new().top(1).append_to("https://x/items?a=b"), "https://x/items?a=b&$top=1");
+        assert_eq!(QueryOptions::new().append_to("https://x/items"), "https://x/items");
+    }
+}
@@ -0,0 +1,291 @@
+//! Authentication against on-premises SharePoint Server farms (2013, 2016, 2019, Subscription
+//! Edition) with a Windows account, either through the NTLM handshake or with Basic
+//! authentication over https. The returned `LoginContext` works with the `list` functions
+//! like one from `auth::login`.
+extern crate futures;
+
+use self::futures::{future, Future};
+
+#[allow(unused_imports)]
+use super::*;
+
+use auth::{parse_site, Authorization, LoginConfig, LoginContext};
+use client::{block_on, Client, SharePointClient, SharePointFuture};
+use error::Result;
+
+/// Windows account signing in to an on-premises farm.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct WindowsCredentials {
+    /// NetBIOS name of the domain, e.g. `COMPANY`; empty for a local account of the server.
+    pub domain: String,
+    pub user_name: String,
+    pub password: String,
+}
+
+impl WindowsCredentials {
+    pub fn new<D, U, P>(domain: D, user_name: U, password: P) -> WindowsCredentials
+    where
+        D: Into<String>,
+        U: Into<String>,
+        P: Into<String>,
+    {
+        WindowsCredentials {
+            domain: domain.into(),
+            user_name: user_name.into(),
+            password: password.into(),
+        }
+    }
+
+    /// The account as `DOMAIN\user`, or just `user` without a domain.
+    pub fn account(&self) -> String {
+        if self.domain.is_empty() {
+            self.user_name.to_owned()
+        } else {
+            format!("{}\\{}", self.domain, self.user_name)
+        }
+    }
+}
+
+/// Logs in to the on-premises `site` with Windows authentication: NTLM, or NTLM inside
+/// `Negotiate` when the farm only offers that.
+pub fn login_with_ntlm(site: String, credentials: WindowsCredentials, config: LoginConfig) -> Result<LoginContext> {
+    block_on(|client| client.login_with_ntlm(site, credentials, config))
+}
+
+/// Logs in to the on-premises `site` with Basic authentication, which is refused unless the
+/// site uses https.
+pub fn login_with_basic(site: String, credentials: WindowsCredentials, config: LoginConfig) -> Result<LoginContext> {
+    block_on(|client| client.login_with_basic(site, credentials, config))
+}
+
+impl SharePointClient {
+    /// `on_premises::login_with_ntlm` reusing this client's connections.
+    pub fn login_with_ntlm(&mut self, site: String, credentials: WindowsCredentials, config: LoginConfig) -> Result<LoginContext> {
+        let work = self.client().login_with_ntlm(site, credentials, config);
+        self.run(work)
+    }
+
+    /// `on_premises::login_with_basic` reusing this client's connections.
+    pub fn login_with_basic(&mut self, site: String, credentials: WindowsCredentials, config: LoginConfig) -> Result<LoginContext> {
+        let work = self.client().login_with_basic(site, credentials, config);
+        self.run(work)
+    }
+}
+
+impl Client {
+    /// Asynchronous `on_premises::login_with_ntlm`.
+    pub fn login_with_ntlm(&self, site: String, credentials: WindowsCredentials, config: LoginConfig) -> SharePointFuture<LoginContext> {
+        self.login_on_premises(site, Authorization::Ntlm(credentials), config)
+    }
+
+    /// Asynchronous `on_premises::login_with_basic`.
+    pub fn login_with_basic(&self, site: String, credentials: WindowsCredentials, config: LoginConfig) -> SharePointFuture<LoginContext> {
+        self.login_on_premises(site, Authorization::Basic(credentials), config)
+    }
+
+    /// Gets a request digest with `authorization`, which also checks the account.
+    fn login_on_premises(&self, site: String, authorization: Authorization, config: LoginConfig) -> SharePointFuture<LoginContext> {
+        let site = match parse_site(&site) {
+            Ok(site) => site,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        Box::new(
+            self.request_digest(&config, site.clone(), authorization.clone())
+                .map(move |digest| LoginContext::new(site, authorization, digest, config)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate base64;
+
+    use super::*;
+    use auth::tests::CONTEXT_INFO_RESPONSE;
+    use error::Error;
+    use hyper::StatusCode;
+    use self::serde_json::Value;
+    use ntlm;
+    use transport::tests::FakeTransport;
+
+    fn credentials() -> WindowsCredentials {
+        WindowsCredentials::new("COMPANY", "user", "p@ss")
+    }
+
+    fn challenge_header(scheme: &str) -> String {
+        format!("{} {}", scheme, base64::encode(&ntlm::challenge_message([7; 8], &[0, 0, 0, 0])))
+    }
+
+    fn decode(header: Option<&str>, scheme: &str) -> Vec<u8> {
+        let header = header.unwrap();
+        assert!(header.starts_with(&format!("{} ", scheme)), "unexpected header {}", header);
+        base64::decode(&header[scheme.len() + 1..]).unwrap()
+    }
+
+    #[test]
+    fn ntlm_login_answers_the_challenge() {
+        let transport = FakeTransport::default();
+        transport
+            .respond_with_headers(StatusCode::Unauthorized, "", vec![("WWW-Authenticate", &challenge_header("NTLM"))])
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE)
+            .respond(StatusCode::Ok, r#"{"value":[{"Id":1}]}"#);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        let login = client
+            .login_with_ntlm("https://sharepoint.company.local/sites/test/".to_string(), credentials(), LoginConfig::default())
+            .unwrap();
+        assert_eq!(login.request_digest().content, "0xDIGEST,01 Jan 2018 00:00:00 -0000");
+        assert!(login.access_token().is_none());
+
+        let negotiate = transport.request(0);
+        assert!(negotiate.url.ends_with("/sites/test/_api/contextinfo"));
+        assert_eq!(decode(negotiate.header("Authorization"), "NTLM"), ntlm::negotiate_message());
+        let authenticate = transport.request(1);
+        assert_eq!(authenticate.url, negotiate.url);
+        assert!(ntlm::verify_authenticate_message(&decode(authenticate.header("Authorization"), "NTLM"), &credentials(), &[7; 8]));
+        assert_eq!(*transport.connections.borrow(), vec![1, 1]);
+
+        // the authenticated connection is used again without a handshake
+        let items: Vec<Value> = client.get_list_items_by_title("Tasks".to_string(), &login).unwrap();
+        assert_eq!(items.len(), 1);
+        assert!(transport.request(2).url.ends_with("/_api/web/lists/GetByTitle('Tasks')/items"));
+        assert_eq!(transport.request(2).header("Authorization"), None);
+        assert_eq!(transport.request(2).header("Cookie"), None);
+        assert_eq!(*transport.connections.borrow(), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn negotiate_only_farm_gets_ntlm_inside_negotiate() {
+        let transport = FakeTransport::default();
+        transport
+            .respond_with_headers(StatusCode::Unauthorized, "", vec![("WWW-Authenticate", "Negotiate")])
+            .respond_with_headers(StatusCode::Unauthorized, "", vec![("WWW-Authenticate", &challenge_header("Negotiate"))])
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        client
+            .login_with_ntlm("https://sharepoint.company.local/sites/test/".to_string(), credentials(), LoginConfig::default())
+            .unwrap();
+
+        assert_eq!(decode(transport.request(1).header("Authorization"), "Negotiate"), ntlm::negotiate_message());
+        assert!(ntlm::verify_authenticate_message(&decode(transport.request(2).header("Authorization"), "Negotiate"), &credentials(), &[7; 8]));
+    }
+
+    #[test]
+    fn refused_account_is_reported() {
+        let transport = FakeTransport::default();
+        transport
+            .respond_with_headers(StatusCode::Unauthorized, "", vec![("WWW-Authenticate", &challenge_header("NTLM"))])
+            .respond(StatusCode::Unauthorized, "401 UNAUTHORIZED");
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        match client.login_with_ntlm("https://sharepoint.company.local/sites/test/".to_string(), credentials(), LoginConfig::default()) {
+            Err(Error::Status { status, .. }) => assert_eq!(status, StatusCode::Unauthorized),
+            res => panic!("expected a 401, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn basic_login_requires_https() {
+        let transport = FakeTransport::default();
+        transport.respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        client
+            .login_with_basic("https://sharepoint.company.local/sites/test/".to_string(), credentials(), LoginConfig::default())
+            .unwrap();
+        assert_eq!(
+            transport.request(0).header("Authorization"),
+            Some(format!("Basic {}", base64::encode("COMPANY\\user:p@ss")).as_str())
+        );
+
+        match client.login_with_basic("http://sharepoint.company.local/sites/test/".to_string(), credentials(), LoginConfig::default()) {
+            Err(Error::InsecureBasicAuth(url)) => assert!(url.starts_with("http://sharepoint.company.local/")),
+            res => panic!("expected Basic over http to be refused, got {:?}", res.map(|_| ())),
+        }
+        assert_eq!(transport.requests.borrow().len(), 1);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[test]
+    fn ntlm_login_against_mock_server() {
+        use mock::MockServer;
+
+        let server = MockServer::start();
+        server.create_list("Tasks");
+        let mut client = SharePointClient::new().unwrap();
+        let credentials = WindowsCredentials::new(MockServer::DOMAIN, MockServer::WINDOWS_USER_NAME, MockServer::PASSWORD);
+        let login = client.login_with_ntlm(server.site_url(), credentials, server.login_config()).unwrap();
+        let item: Value = client
+            .add_list_item_by_list_title("Tasks".to_string(), &login, json!({"Title": "On-premises"}))
+            .unwrap();
+        assert_eq!(item["Title"], "On-premises");
+        assert_eq!(server.items("Tasks").len(), 1);
+
+        let wrong = WindowsCredentials::new(MockServer::DOMAIN, MockServer::WINDOWS_USER_NAME, "wrong");
+        match client.login_with_ntlm(server.site_url(), wrong, server.login_config()) {
+            Err(Error::Status { status, .. }) => assert_eq!(status, StatusCode::Unauthorized),
+            res => panic!("expected a 401, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn concurrent_ntlm_requests_get_a_connection_each() {
+        extern crate tokio_core;
+
+        // both negotiate messages go out before either challenge is answered
+        let transport = FakeTransport::default();
+        transport
+            .respond_with_headers(StatusCode::Unauthorized, "", vec![("WWW-Authenticate", &challenge_header("NTLM"))])
+            .respond_with_headers(StatusCode::Unauthorized, "", vec![("WWW-Authenticate", &challenge_header("NTLM"))])
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE)
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE);
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = Client::with_transport(transport.clone());
+        let site = "https://sharepoint.company.local/sites/test/".to_string();
+        let first = client.login_with_ntlm(site.clone(), credentials(), LoginConfig::default());
+        let second = client.login_with_ntlm(site, credentials(), LoginConfig::default());
+        core.run(first.join(second)).unwrap();
+        assert_eq!(*transport.connections.borrow(), vec![1, 2, 1, 2]);
+        assert_eq!(decode(transport.request(1).header("Authorization"), "NTLM"), ntlm::negotiate_message());
+    }
+
+    #[test]
+    fn connection_refused_again_gets_a_new_handshake() {
+        let transport = FakeTransport::default();
+        transport
+            .respond_with_headers(StatusCode::Unauthorized, "", vec![("WWW-Authenticate", &challenge_header("NTLM"))])
+            .respond(StatusCode::ServiceUnavailable, "")
+            .respond_with_headers(StatusCode::Unauthorized, "", vec![("WWW-Authenticate", "NTLM")])
+            .respond_with_headers(StatusCode::Unauthorized, "", vec![("WWW-Authenticate", &challenge_header("NTLM"))])
+            .respond(StatusCode::Ok, CONTEXT_INFO_RESPONSE);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        client
+            .login_with_ntlm("https://sharepoint.company.local/sites/test/".to_string(), credentials(), LoginConfig::default())
+            .unwrap();
+        assert_eq!(transport.delays.borrow().len(), 1);
+        assert_eq!(*transport.connections.borrow(), vec![1, 1, 1, 1, 1]);
+        assert_eq!(transport.request(2).header("Authorization"), None);
+        assert_eq!(decode(transport.request(3).header("Authorization"), "NTLM"), ntlm::negotiate_message());
+        assert!(ntlm::verify_authenticate_message(&decode(transport.request(4).header("Authorization"), "NTLM"), &credentials(), &[7; 8]));
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[test]
+    fn concurrent_ntlm_requests_keep_their_connections() {
+        extern crate tokio_core;
+
+        use mock::MockServer;
+
+        let server = MockServer::start();
+        server.create_list("Tasks");
+        server.create_list("Issues");
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = Client::new(&core.handle()).unwrap();
+        let credentials = WindowsCredentials::new(MockServer::DOMAIN, MockServer::WINDOWS_USER_NAME, MockServer::PASSWORD);
+        let login = core.run(client.login_with_ntlm(server.site_url(), credentials, server.login_config())).unwrap();
+
+        for _ in 0..5 {
+            let tasks = client.get_list_by_title("Tasks".to_string(), &login);
+            let issues = client.get_list_by_title("Issues".to_string(), &login);
+            let (tasks, issues) = core.run(tasks.join(issues)).unwrap();
+            assert!(tasks.id != issues.id);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Retrying requests that SharePoint throttled (`429 Too Many Requests`, `503 Service
+//! Unavailable`) or that failed on the way (`502`, `504`, connection errors, timeouts).
+//!
+//! A `Retry-After` header is honored; otherwise the delay grows exponentially, with jitter
+//! so that many clients throttled at once do not come back at the same moment. Only
+//! idempotent requests are retried unless `retry_non_idempotent` is set.
+extern crate rand;
+
+use std::time::{Duration, SystemTime};
+
+use hyper::header::HttpDate;
+use hyper::{Method, StatusCode};
+
+use error::{Error, Result};
+use transport::{TransportRequest, TransportResponse};
+
+/// When and how often a `Client` sends a request again, see `Client::with_retry_policy`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first one included; 1 never retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry without `Retry-After`, doubled for every further one.
+    pub initial_backoff: Duration,
+    /// Longest delay computed by the backoff.
+    pub max_backoff: Duration,
+    /// Longest `Retry-After` waited for; the error is returned if SharePoint asks for more.
+    pub max_retry_after: Duration,
+    /// Retries `POST` requests too, which may then be carried out twice, e.g. create an item twice.
+    pub retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    /// 4 attempts, 1 second backoff up to 30 seconds, `Retry-After` up to 5 minutes.
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            max_retry_after: Duration::from_secs(300),
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Sends every request only once.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// How long to wait before sending `request` again after `attempt` ended in `result`,
+    /// or `None` to give up.
+    pub fn delay(&self, request: &TransportRequest, attempt: u32, result: &Result<TransportResponse>) -> Option<Duration> {
+        if attempt >= self.max_attempts || !(self.retry_non_idempotent || is_idempotent(request)) {
+            return None;
+        }
+        match *result {
+            Ok(ref res) if is_transient(res.status) => match retry_after(res) {
+                Some(retry_after) if retry_after > self.max_retry_after => None,
+                Some(retry_after) => Some(retry_after),
+                None => Some(self.backoff(attempt)),
+            },
+            Err(Error::Transport(_)) | Err(Error::Timeout { .. }) => Some(self.backoff(attempt)),
+            _ => None,
+        }
+    }
+
+    /// Exponential backoff after `attempt`, randomly between half and all of it.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self.initial_backoff * 2u32.saturating_pow(attempt.saturating_sub(1).min(16));
+        let backoff = if exponential > self.max_backoff { self.max_backoff } else { exponential };
+        backoff / 2 + backoff.mul_f64(rand::random::<f64>() / 2.0)
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    match status {
+        StatusCode::TooManyRequests | StatusCode::BadGateway | StatusCode::ServiceUnavailable | StatusCode::GatewayTimeout => true,
+        _ => false,
+    }
+}
+
+/// Reads, writes tunneled through `X-Http-Method` and the request digest may be sent twice;
+/// other `POST` requests may not.
+pub(crate) fn is_idempotent(request: &TransportRequest) -> bool {
+    match request.method {
+        Method::Get | Method::Head | Method::Put | Method::Delete | Method::Options => true,
+        Method::Post => {
+            let tunneled = request.header("X-Http-Method").map_or(false, |m| {
+                ["MERGE", "PATCH", "PUT", "DELETE"].iter().any(|i| i.eq_ignore_ascii_case(m))
+            });
+            tunneled || request.url.ends_with("/_api/contextinfo")
+        }
+        _ => false,
+    }
+}
+
+/// The `Retry-After` of `response`, in seconds or as an HTTP date.
+pub(crate) fn retry_after(response: &TransportResponse) -> Option<Duration> {
+    let value = response.header("Retry-After")?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = SystemTime::from(value.parse::<HttpDate>().ok()?);
+    Some(date.duration_since(SystemTime::now()).unwrap_or_else(|_| Duration::from_secs(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use transport::{HeaderItem, Timeouts};
+
+    fn request(method: Method, headers: Vec<(&str, &str)>) -> TransportRequest {
+        TransportRequest {
+            method: method,
+            url: "https://company.sharepoint.com/sites/test/_api/web/lists".to_string(),
+            headers: headers
+                .iter()
+                .map(|&(name, value)| HeaderItem { name: name.to_string(), value: value.to_string() })
+                .collect(),
+            body: String::new(),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    fn response(status: StatusCode, retry_after: Option<&str>) -> Result<TransportResponse> {
+        Ok(TransportResponse {
+            status: status,
+            headers: retry_after
+                .iter()
+                .map(|v| HeaderItem { name: "Retry-After".to_string(), value: v.to_string() })
+                .collect(),
+            cookies: Vec::new(),
+            body: String::new(),
+        })
+    }
+
+    #[test]
+    fn honors_retry_after() {
+        let policy = RetryPolicy::default();
+        let get = request(Method::Get, Vec::new());
+        assert_eq!(policy.delay(&get, 1, &response(StatusCode::TooManyRequests, Some("7"))), Some(Duration::from_secs(7)));
+        assert_eq!(policy.delay(&get, 1, &response(StatusCode::ServiceUnavailable, Some("Thu, 01 Jan 1970 00:00:00 GMT"))), Some(Duration::from_secs(0)));
+        assert_eq!(policy.delay(&get, 1, &response(StatusCode::TooManyRequests, Some("3600"))), None);
+        assert_eq!(policy.delay(&get, 4, &response(StatusCode::TooManyRequests, Some("7"))), None);
+    }
+
+    #[test]
+    fn backs_off_exponentially_with_jitter() {
+        let policy = RetryPolicy { max_attempts: 20, ..RetryPolicy::default() };
+        let get = request(Method::Get, Vec::new());
+        for &(attempt, max) in &[(1, 1), (2, 2), (3, 4), (10, 30)] {
+            let delay = policy.delay(&get, attempt, &response(StatusCode::GatewayTimeout, None)).unwrap();
+            let max = Duration::from_secs(max);
+            assert!(delay >= max / 2 && delay <= max, "attempt {} waits {:?}", attempt, delay);
+        }
+        let failed: Result<TransportResponse> = Err(Error::Io(io::Error::new(io::ErrorKind::Other, "no response queued")));
+        assert_eq!(policy.delay(&get, 1, &failed), None);
+        assert_eq!(policy.delay(&get, 1, &response(StatusCode::InternalServerError, None)), None);
+        assert_eq!(policy.delay(&get, 1, &response(StatusCode::Ok, Some("7"))), None);
+        assert_eq!(RetryPolicy::never().delay(&get, 1, &response(StatusCode::TooManyRequests, Some("7"))), None);
+    }
+
+    #[test]
+    fn retries_only_idempotent_requests_by_default() {
+        let throttled = response(StatusCode::TooManyRequests, Some("1"));
+        let create = request(Method::Post, Vec::new());
+        let update = request(Method::Post, vec![("X-Http-Method", "PATCH")]);
+        assert_eq!(RetryPolicy::default().delay(&create, 1, &throttled), None);
+        assert_eq!(RetryPolicy::default().delay(&update, 1, &throttled), Some(Duration::from_secs(1)));
+
+        let policy = RetryPolicy { retry_non_idempotent: true, ..RetryPolicy::default() };
+        assert_eq!(policy.delay(&create, 1, &throttled), Some(Duration::from_secs(1)));
+    }
+}
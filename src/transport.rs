@@ -0,0 +1,462 @@
+extern crate futures;
+extern crate tokio_core;
+
+use hyper::header::SetCookie;
+use hyper::{Method, Request, StatusCode, Uri};
+
+use self::futures::future::Either;
+use self::futures::{future, Async, Future, Poll, Stream};
+use self::tokio_core::reactor::{Handle, Timeout};
+
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use client::SharePointFuture;
+use connection::{ConnectionConfig, Connector, Proxy};
+use error::{Error, Result};
+
+#[derive(Debug, Clone, Deserialize, Default, PartialEq)]
+pub struct HeaderItem {
+    pub name: String,
+    pub value: String,
+}
+
+/// How long a request may take before it fails with `Error::Timeout`; `None` waits forever.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// Opening a connection, TLS handshake included. Connections are kept alive and shared
+    /// between requests, so this one is set per transport, see `HyperTransport::with_timeouts`.
+    pub connect: Option<Duration>,
+    /// Longest wait for the response headers or for the next chunk of the body.
+    pub read: Option<Duration>,
+    /// The whole call, from connecting to the last byte of the body. A `Client` enforces it
+    /// once around all attempts, so retries and the pauses between them count against it.
+    pub total: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    /// 30 seconds to connect, 2 minutes without data, no total limit.
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: Some(Duration::from_secs(30)),
+            read: Some(Duration::from_secs(120)),
+            total: None,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Waits forever, as the crate did before timeouts existed.
+    pub fn none() -> Timeouts {
+        Timeouts {
+            connect: None,
+            read: None,
+            total: None,
+        }
+    }
+}
+
+/// A request as produced by the crate, before it goes on the wire.
+#[derive(Debug, Clone)]
+pub struct TransportRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<HeaderItem>,
+    pub body: String,
+    /// Set by the `Client` sending the request, see `Client::with_timeouts`.
+    pub timeouts: Timeouts,
+}
+
+impl TransportRequest {
+    /// Value of the first header called `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.as_str())
+    }
+}
+
+/// The parts of a response the crate looks at.
+#[derive(Debug, Clone)]
+pub struct TransportResponse {
+    pub status: StatusCode,
+    pub headers: Vec<HeaderItem>,
+    /// Raw `Set-Cookie` values.
+    pub cookies: Vec<String>,
+    pub body: String,
+}
+
+impl TransportResponse {
+    /// Value of the first header called `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.as_str())
+    }
+}
+
+/// Sends requests on behalf of a `Client`.
+///
+/// `HyperTransport` is used by default; tests can plug in an in-memory implementation
+/// with `Client::with_transport` to check what the crate sends without Office 365.
+pub trait Transport {
+    /// Sends `request`, failing with `Error::Timeout` once its `timeouts` are exceeded.
+    fn send(&self, request: TransportRequest) -> SharePointFuture<TransportResponse>;
+
+    /// Completes after `duration`, for the pauses between retries. It must not block the thread,
+    /// which runs the event loop, so wait with a timer such as `tokio_core::reactor::Timeout`.
+    fn delay(&self, duration: Duration) -> SharePointFuture<()>;
+
+    /// A transport sending its requests, one after the other, over a connection of its own, for
+    /// handshakes such as NTLM that authenticate the connection rather than the request.
+    fn dedicated_connection(&self) -> Result<Rc<dyn Transport>>;
+}
+
+/// `work`, failing with `Error::Timeout` if it is not done within `timeout`.
+fn deadline<F>(work: F, timeout: Option<Duration>, handle: &Handle, url: &str) -> SharePointFuture<F::Item>
+where
+    F: Future<Error = Error> + 'static,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Box::new(work),
+    };
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    let url = url.to_owned();
+    Box::new(work.select2(timer).then(move |res| match res {
+        Ok(Either::A((item, _))) => Ok(item),
+        Ok(Either::B(_)) => Err(Error::Timeout { url: url, after: timeout }),
+        Err(Either::A((e, _))) => Err(e),
+        Err(Either::B((e, _))) => Err(e.into()),
+    }))
+}
+
+/// Body stream failing with `Error::Timeout` when no chunk arrives for `timeout`.
+struct ReadTimeout<S> {
+    body: S,
+    timer: Option<(Duration, Timeout)>,
+    url: String,
+}
+
+impl<S> Stream for ReadTimeout<S>
+where
+    S: Stream<Error = ::hyper::Error>,
+{
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Error> {
+        if let Async::Ready(chunk) = self.body.poll()? {
+            if let Some((timeout, ref mut timer)) = self.timer {
+                timer.reset(Instant::now() + timeout);
+            }
+            return Ok(Async::Ready(chunk));
+        }
+        if let Some((timeout, ref mut timer)) = self.timer {
+            if timer.poll()?.is_ready() {
+                return Err(Error::Timeout { url: self.url.to_owned(), after: timeout });
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// Default `Transport` sending requests over HTTPS with `hyper`, keeping connections alive.
+#[derive(Clone)]
+pub struct HyperTransport {
+    http: ::hyper::Client<Connector>,
+    connector: Connector,
+    handle: Handle,
+    connect_timeout: Option<Duration>,
+    proxy: Option<Proxy>,
+}
+
+impl HyperTransport {
+    /// Creates a transport whose connections are driven by `handle`, with the default connect timeout.
+    pub fn new(handle: &Handle) -> Result<HyperTransport> {
+        HyperTransport::with_timeouts(handle, Timeouts::default())
+    }
+
+    /// Creates a transport giving up connecting after `timeouts.connect`; the other timeouts
+    /// come with each request.
+    pub fn with_timeouts(handle: &Handle, timeouts: Timeouts) -> Result<HyperTransport> {
+        HyperTransport::with_config(
+            handle,
+            ConnectionConfig {
+                timeouts: timeouts,
+                ..ConnectionConfig::default()
+            },
+        )
+    }
+
+    /// Creates a transport connecting through `config.proxy` with `config.tls`, failing with
+    /// `Error::Tls` if a certificate of `config.tls` cannot be read.
+    pub fn with_config(handle: &Handle, config: ConnectionConfig) -> Result<HyperTransport> {
+        let connector = Connector::new(handle, &config)?;
+        Ok(HyperTransport {
+            http: HyperTransport::pool(handle, connector.clone()),
+            connector: connector,
+            handle: handle.clone(),
+            connect_timeout: config.timeouts.connect,
+            proxy: config.proxy,
+        })
+    }
+
+    fn pool(handle: &Handle, connector: Connector) -> ::hyper::Client<Connector> {
+        ::hyper::Client::configure().connector(connector).keep_alive(true).build(handle)
+    }
+}
+
+impl Transport for HyperTransport {
+    fn send(&self, request: TransportRequest) -> SharePointFuture<TransportResponse> {
+        let uri: Uri = match request.url.parse() {
+            Ok(uri) => uri,
+            Err(_) => return Box::new(future::err(Error::Uri(request.url))),
+        };
+        let url = request.url;
+        let timeouts = request.timeouts;
+        let connect_timeout = self.connect_timeout;
+
+        let proxy = match self.proxy {
+            Some(ref proxy) if uri.scheme() == Some("http") && !proxy.bypasses(uri.host().unwrap_or("")) => Some(proxy),
+            _ => None,
+        };
+        let mut req = Request::new(request.method, uri);
+        for header in request.headers {
+            req.headers_mut().set_raw(header.name, header.value);
+        }
+        // https goes through a tunnel opened by the connector, plain http is sent to the proxy
+        if let Some(proxy) = proxy {
+            req.set_proxy(true);
+            if let Some(authorization) = proxy.authorization() {
+                req.headers_mut().set_raw("Proxy-Authorization", authorization);
+            }
+        }
+        req.set_body(request.body);
+
+        let head = {
+            let url = url.clone();
+            self.http.request(req).map_err(move |e| match e {
+                ::hyper::Error::Io(ref io) if io.kind() == io::ErrorKind::TimedOut => Error::Timeout {
+                    url: url,
+                    after: connect_timeout.unwrap_or_default(),
+                },
+                e => Error::from(e),
+            })
+        };
+        let handle = self.handle.clone();
+        let body_url = url.clone();
+        let post = deadline(head, timeouts.read, &self.handle, &url).and_then(move |res| {
+            let status = res.status();
+            let headers: Vec<HeaderItem> = res.headers()
+                .iter()
+                .map(|q| {
+                    HeaderItem {
+                        name: q.name().to_string(),
+                        value: q.value_string(),
+                    }
+                })
+                .collect();
+
+            let mut header_cookies: Vec<String> = Vec::new();
+            if let Some(&SetCookie(ref cookies)) = res.headers().get() {
+                for cookie in cookies.iter() {
+                    header_cookies.push(cookie.to_string());
+                }
+            }
+
+            let timer = match timeouts.read {
+                Some(timeout) => Some((timeout, Timeout::new(timeout, &handle)?)),
+                None => None,
+            };
+            let body = ReadTimeout {
+                body: res.body(),
+                timer: timer,
+                url: body_url,
+            };
+            Ok(body
+                .fold(Vec::new(), |mut v, chunk| {
+                    v.extend(&chunk[..]);
+                    future::ok::<_, Error>(v)
+                })
+                .map(move |chunks| (status, headers, header_cookies, chunks)))
+        }).flatten();
+
+        Box::new(deadline(post, timeouts.total, &self.handle, &url).and_then(|(status, headers, cookies, chunks)| {
+            Ok(TransportResponse {
+                status: status,
+                headers: headers,
+                cookies: cookies,
+                body: String::from_utf8(chunks)?,
+            })
+        }))
+    }
+
+    fn delay(&self, duration: Duration) -> SharePointFuture<()> {
+        match Timeout::new(duration, &self.handle) {
+            Ok(timeout) => Box::new(timeout.map_err(Error::from)),
+            Err(e) => Box::new(future::err(e.into())),
+        }
+    }
+
+    /// A pool of its own, which keeps the one connection opened by the first request alive
+    /// for the next ones.
+    fn dedicated_connection(&self) -> Result<Rc<dyn Transport>> {
+        Ok(Rc::new(HyperTransport {
+            http: HyperTransport::pool(&self.handle, self.connector.clone()),
+            ..self.clone()
+        }))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::io;
+    use std::collections::VecDeque;
+    use std::thread;
+
+    /// In-memory `Transport` recording every request and answering with queued responses.
+    #[derive(Clone, Default)]
+    pub struct FakeTransport {
+        pub requests: Rc<RefCell<Vec<TransportRequest>>>,
+        /// Retry delays asked for, which pass at once.
+        pub delays: Rc<RefCell<Vec<Duration>>>,
+        /// Connection of every request, 0 for the shared ones and then 1, 2, ... for the
+        /// transports handed out by `dedicated_connection`.
+        pub connections: Rc<RefCell<Vec<usize>>>,
+        responses: Rc<RefCell<VecDeque<TransportResponse>>>,
+        connection: usize,
+        opened: Rc<Cell<usize>>,
+    }
+
+    impl FakeTransport {
+        pub fn respond(&self, status: StatusCode, body: &str) -> &FakeTransport {
+            self.respond_with_cookies(status, body, Vec::new())
+        }
+
+        pub fn respond_with_cookies(&self, status: StatusCode, body: &str, cookies: Vec<&str>) -> &FakeTransport {
+            self.responses.borrow_mut().push_back(TransportResponse {
+                status: status,
+                headers: Vec::new(),
+                cookies: cookies.iter().map(|c| c.to_string()).collect(),
+                body: body.to_string(),
+            });
+            self
+        }
+
+        pub fn respond_with_headers(&self, status: StatusCode, body: &str, headers: Vec<(&str, &str)>) -> &FakeTransport {
+            self.responses.borrow_mut().push_back(TransportResponse {
+                status: status,
+                headers: headers
+                    .iter()
+                    .map(|&(name, value)| HeaderItem {
+                        name: name.to_string(),
+                        value: value.to_string(),
+                    })
+                    .collect(),
+                cookies: Vec::new(),
+                body: body.to_string(),
+            });
+            self
+        }
+
+        pub fn request(&self, i: usize) -> TransportRequest {
+            self.requests.borrow()[i].clone()
+        }
+    }
+
+    impl Transport for FakeTransport {
+        fn send(&self, request: TransportRequest) -> SharePointFuture<TransportResponse> {
+            self.requests.borrow_mut().push(request);
+            self.connections.borrow_mut().push(self.connection);
+            match self.responses.borrow_mut().pop_front() {
+                Some(response) => Box::new(future::ok(response)),
+                None => Box::new(future::err(Error::Io(io::Error::new(io::ErrorKind::Other, "no response queued")))),
+            }
+        }
+
+        fn delay(&self, duration: Duration) -> SharePointFuture<()> {
+            self.delays.borrow_mut().push(duration);
+            Box::new(future::ok(()))
+        }
+
+        fn dedicated_connection(&self) -> Result<Rc<dyn Transport>> {
+            self.opened.set(self.opened.get() + 1);
+            Ok(Rc::new(FakeTransport {
+                connection: self.opened.get(),
+                ..self.clone()
+            }))
+        }
+    }
+
+    /// Local server accepting connections and answering with `head`, then one body byte
+    /// every 50ms; an empty `head` never answers.
+    pub fn stalling_server(head: &'static str) -> String {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/sites/test/_api/web", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    if head.is_empty() {
+                        thread::sleep(Duration::from_secs(10));
+                        return;
+                    }
+                    let mut request = [0; 4096];
+                    let _ = stream.read(&mut request);
+                    let _ = stream.write_all(head.as_bytes());
+                    while stream.write_all(b"1\r\nx\r\n").is_ok() {
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    fn get(url: String, timeouts: Timeouts) -> Result<TransportResponse> {
+        let mut core = self::tokio_core::reactor::Core::new().unwrap();
+        let transport = HyperTransport::new(&core.handle()).unwrap();
+        core.run(transport.send(TransportRequest {
+            method: Method::Get,
+            url: url,
+            headers: Vec::new(),
+            body: String::new(),
+            timeouts: timeouts,
+        }))
+    }
+
+    #[test]
+    fn stalled_response_times_out() {
+        let url = stalling_server("");
+        let timeouts = Timeouts { read: Some(Duration::from_millis(200)), ..Timeouts::default() };
+        match get(url.clone(), timeouts) {
+            Err(Error::Timeout { url: timed_out, after }) => {
+                assert_eq!(timed_out, url);
+                assert_eq!(after, Duration::from_millis(200));
+            }
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn trickling_response_hits_the_total_timeout() {
+        let url = stalling_server("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+        let timeouts = Timeouts { read: Some(Duration::from_millis(200)), total: Some(Duration::from_millis(500)), ..Timeouts::default() };
+        match get(url, timeouts) {
+            Err(Error::Timeout { after, .. }) => assert_eq!(after, Duration::from_millis(500)),
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+    }
+}
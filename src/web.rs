@@ -0,0 +1,443 @@
+//! Chainable queries on the objects of a site, following PnP JS Core:
+//! `client.web(&login).lists().get_by_title("Tasks").items().filter("Id gt 10").get()`.
+//!
+//! Every object only composes its `_api` URL until `get`, `add`, `update` or `delete` sends
+//! the request with the session of the login, as the functions of `list` do.
+//!
+//! ```no_run
+//! # extern crate sharepoint;
+//! # extern crate serde_json;
+//! # use sharepoint::SharePointClient;
+//! # use sharepoint::odata::field;
+//! # use serde_json::Value;
+//! # fn main() {
+//! # let mut client = SharePointClient::new().unwrap();
+//! # let login = client.login("https://company.sharepoint.com/sites/test/".to_string(), "user".to_string(), "secret".to_string()).unwrap();
+//! let tasks = client.web(&login).lists().get_by_title("Tasks");
+//! let open: Vec<Value> = client.run(tasks.items().filter(field("Status").ne("Done")).select("Id,Title").top(50).get()).unwrap();
+//! client.run(tasks.items().get_by_id(3).delete()).unwrap();
+//! # }
+//! ```
+extern crate futures;
+extern crate serde;
+extern crate uuid;
+
+use self::futures::Future;
+use self::serde::de::DeserializeOwned;
+use self::serde::ser::Serialize;
+use self::uuid::Uuid;
+
+#[allow(unused_imports)]
+use super::*;
+
+use auth::LoginContext;
+use client::{Client, SharePointClient, SharePointFuture};
+use data::{delete_with_login, get_with_login, post_with_login};
+use list::ListItemsContainer;
+use odata::{path_literal, Filter, QueryOptions};
+
+/// URL of an object of the REST API with the OData query options to send along.
+#[derive(Clone)]
+struct Query {
+    client: Client,
+    login: LoginContext,
+    path: String,
+    options: QueryOptions,
+}
+
+impl Query {
+    /// The object at `segment` below this one, e.g. `lists`.
+    fn child(&self, segment: &str) -> Query {
+        Query {
+            client: self.client.clone(),
+            login: self.login.clone(),
+            path: format!("{}/{}", self.path, segment),
+            options: QueryOptions::default(),
+        }
+    }
+
+    /// The member `key` of this collection, e.g. `items(3)`.
+    fn member(&self, key: &str) -> Query {
+        Query {
+            path: format!("{}({})", self.path, key),
+            options: QueryOptions::default(),
+            ..self.clone()
+        }
+    }
+
+    fn with_options<F>(mut self, change: F) -> Query
+    where
+        F: FnOnce(QueryOptions) -> QueryOptions,
+    {
+        self.options = change(self.options);
+        self
+    }
+
+    fn url(&self) -> String {
+        self.options.append_to(&self.path)
+    }
+
+    fn get<T>(&self) -> SharePointFuture<T>
+    where
+        T: DeserializeOwned + 'static,
+    {
+        get_with_login(&self.client, self.url(), &self.login)
+    }
+
+    fn get_all<T>(&self) -> SharePointFuture<Vec<T>>
+    where
+        T: DeserializeOwned + Default + 'static,
+    {
+        let res: SharePointFuture<ListItemsContainer<T>> = self.get();
+        Box::new(res.map(|res| res.results))
+    }
+}
+
+/// The comma separated `fields`, e.g. `Id, Title`.
+fn field_list(fields: &str) -> Vec<&str> {
+    fields.split(',').map(|f| f.trim()).filter(|f| !f.is_empty()).collect()
+}
+
+/// Implements `get` and the query options of a collection whose items deserialize to `T`.
+macro_rules! collection {
+    ($name:ident) => {
+        impl $name {
+            /// Only the items matching `filter`, a `Filter` built with `odata::field` or a
+            /// hand-written expression such as `Title eq 'Done'`, and the filters set before.
+            pub fn filter<F: Into<Filter>>(self, filter: F) -> $name {
+                $name(self.0.with_options(|mut o| {
+                    let filter = match o.filter.take() {
+                        Some(before) => before.and(filter.into()),
+                        None => filter.into(),
+                    };
+                    o.filter(filter)
+                }))
+            }
+
+            /// Only the comma separated `fields` of every item, besides those selected before.
+            pub fn select(self, fields: &str) -> $name {
+                $name(self.0.with_options(|o| o.select(&field_list(fields))))
+            }
+
+            /// Returns the targets of the comma separated lookup `fields` too.
+            pub fn expand(self, fields: &str) -> $name {
+                $name(self.0.with_options(|o| o.expand(&field_list(fields))))
+            }
+
+            /// Sorts by `field` ascending, after the fields sorted by before.
+            pub fn orderby(self, field: &str) -> $name {
+                $name(self.0.with_options(|o| o.orderby(field)))
+            }
+
+            /// Sorts by `field` descending, after the fields sorted by before.
+            pub fn orderby_desc(self, field: &str) -> $name {
+                $name(self.0.with_options(|o| o.orderby_desc(field)))
+            }
+
+            /// At most `count` items.
+            pub fn top(self, count: u32) -> $name {
+                $name(self.0.with_options(|o| o.top(count)))
+            }
+
+            /// Skips the first `count` items.
+            pub fn skip(self, count: u32) -> $name {
+                $name(self.0.with_options(|o| o.skip(count)))
+            }
+
+            /// Sends `options` instead of those set before.
+            pub fn query(self, options: QueryOptions) -> $name {
+                $name(self.0.with_options(|_| options))
+            }
+
+            /// URL of the request `get` sends.
+            pub fn url(&self) -> String {
+                self.0.url()
+            }
+
+            /// Reads the items.
+            pub fn get<T>(&self) -> SharePointFuture<Vec<T>>
+            where
+                T: DeserializeOwned + Default + 'static,
+            {
+                self.0.get_all()
+            }
+        }
+    };
+}
+
+/// Implements `get` and the `$select` and `$expand` options of a single object.
+macro_rules! object {
+    ($name:ident) => {
+        impl $name {
+            /// Only the comma separated `fields` of the object, besides those selected before.
+            pub fn select(self, fields: &str) -> $name {
+                $name(self.0.with_options(|o| o.select(&field_list(fields))))
+            }
+
+            /// Returns the targets of the comma separated lookup `fields` too.
+            pub fn expand(self, fields: &str) -> $name {
+                $name(self.0.with_options(|o| o.expand(&field_list(fields))))
+            }
+
+            /// URL of the request `get` sends.
+            pub fn url(&self) -> String {
+                self.0.url()
+            }
+
+            /// Reads the object.
+            pub fn get<T>(&self) -> SharePointFuture<T>
+            where
+                T: DeserializeOwned + 'static,
+            {
+                self.0.get()
+            }
+        }
+    };
+}
+
+/// The site of a login, `_api/web`.
+#[derive(Clone)]
+pub struct Web(Query);
+
+/// Lists of a site, `_api/web/lists`.
+#[derive(Clone)]
+pub struct Lists(Query);
+
+/// One list, e.g. `_api/web/lists/GetByTitle('Tasks')`.
+#[derive(Clone)]
+pub struct List(Query);
+
+/// Items of a list.
+#[derive(Clone)]
+pub struct Items(Query);
+
+/// One item of a list, `items(3)`.
+#[derive(Clone)]
+pub struct Item(Query);
+
+/// Fields (columns) of a site or list.
+#[derive(Clone)]
+pub struct Fields(Query);
+
+/// Folders of a site or folder.
+#[derive(Clone)]
+pub struct Folders(Query);
+
+/// One folder.
+#[derive(Clone)]
+pub struct Folder(Query);
+
+/// Files of a folder.
+#[derive(Clone)]
+pub struct Files(Query);
+
+/// One file.
+#[derive(Clone)]
+pub struct File(Query);
+
+object!(Web);
+object!(List);
+object!(Item);
+object!(Folder);
+object!(File);
+collection!(Lists);
+collection!(Items);
+collection!(Fields);
+collection!(Folders);
+collection!(Files);
+
+impl Web {
+    /// The site of `login`, queried through `client`.
+    pub fn new(client: &Client, login: &LoginContext) -> Web {
+        Web(Query {
+            client: client.clone(),
+            login: login.clone(),
+            path: format!("{}/_api/web", login.site.url()),
+            options: QueryOptions::default(),
+        })
+    }
+
+    pub fn lists(&self) -> Lists {
+        Lists(self.0.child("lists"))
+    }
+
+    pub fn fields(&self) -> Fields {
+        Fields(self.0.child("fields"))
+    }
+
+    pub fn folders(&self) -> Folders {
+        Folders(self.0.child("folders"))
+    }
+
+    /// The folder at `url`, relative to the server, e.g. `/sites/test/Shared Documents`.
+    pub fn get_folder_by_server_relative_url(&self, url: &str) -> Folder {
+        Folder(self.0.child(&format!("GetFolderByServerRelativeUrl({})", path_literal(url))))
+    }
+
+    /// The file at `url`, relative to the server.
+    pub fn get_file_by_server_relative_url(&self, url: &str) -> File {
+        File(self.0.child(&format!("GetFileByServerRelativeUrl({})", path_literal(url))))
+    }
+}
+
+impl Lists {
+    pub fn get_by_title(&self, title: &str) -> List {
+        List(self.0.child(&format!("GetByTitle({})", path_literal(title))))
+    }
+
+    pub fn get_by_id(&self, id: Uuid) -> List {
+        List(self.0.child(&format!("GetById('{}')", id)))
+    }
+}
+
+impl List {
+    pub fn items(&self) -> Items {
+        Items(self.0.child("items"))
+    }
+
+    pub fn fields(&self) -> Fields {
+        Fields(self.0.child("fields"))
+    }
+
+    pub fn root_folder(&self) -> Folder {
+        Folder(self.0.child("RootFolder"))
+    }
+}
+
+impl Items {
+    pub fn get_by_id(&self, id: i32) -> Item {
+        Item(self.0.member(&id.to_string()))
+    }
+
+    /// Creates an item from `data` and reads it back.
+    pub fn add<T, U>(&self, data: U) -> SharePointFuture<T>
+    where
+        T: DeserializeOwned + Default + 'static,
+        U: Serialize,
+    {
+        post_with_login(&self.0.client, self.0.path.to_owned(), &self.0.login, data, false)
+    }
+}
+
+impl Item {
+    /// Overwrites the fields in `data`.
+    pub fn update<U>(&self, data: U) -> SharePointFuture<()>
+    where
+        U: Serialize,
+    {
+        post_with_login(&self.0.client, self.0.path.to_owned(), &self.0.login, data, true)
+    }
+
+    pub fn delete(&self) -> SharePointFuture<()> {
+        delete_with_login(&self.0.client, self.0.path.to_owned(), &self.0.login)
+    }
+}
+
+impl Folder {
+    pub fn folders(&self) -> Folders {
+        Folders(self.0.child("Folders"))
+    }
+
+    pub fn files(&self) -> Files {
+        Files(self.0.child("Files"))
+    }
+}
+
+impl Files {
+    pub fn get_by_name(&self, name: &str) -> File {
+        File(self.0.child(&format!("GetByUrl({})", path_literal(name))))
+    }
+}
+
+impl Client {
+    /// The site of `login`, to query with `Web`.
+    pub fn web(&self, login: &LoginContext) -> Web {
+        Web::new(self, login)
+    }
+}
+
+impl SharePointClient {
+    /// The site of `login`, to query with `Web`; run the futures with `run`.
+    pub fn web(&self, login: &LoginContext) -> Web {
+        self.client().web(login)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::tests::fake_login;
+    use hyper::{Method, StatusCode};
+    use odata::field;
+    use transport::tests::FakeTransport;
+
+    #[derive(Debug, Deserialize, Default)]
+    struct TaskItem {
+        #[serde(rename = "Id", default)]
+        id: i32,
+    }
+
+    #[test]
+    fn composes_api_urls() {
+        let transport = FakeTransport::default();
+        let login = fake_login(&transport);
+        let client = SharePointClient::with_transport(transport).unwrap();
+        let web = client.web(&login);
+
+        assert_eq!(web.url(), "https://company.sharepoint.com/sites/test/_api/web");
+        let list = web.lists().get_by_title("Bob's tasks");
+        assert_eq!(list.url(), "https://company.sharepoint.com/sites/test/_api/web/lists/GetByTitle('Bob''s%20tasks')");
+        assert_eq!(list.items().get_by_id(3).url(), format!("{}/items(3)", list.url()));
+        assert_eq!(
+            web.get_folder_by_server_relative_url("/sites/test/Shared Documents").files().get_by_name("a.txt").url(),
+            "https://company.sharepoint.com/sites/test/_api/web/GetFolderByServerRelativeUrl('/sites/test/Shared%20Documents')/Files/GetByUrl('a.txt')"
+        );
+        assert_eq!(
+            list.items().filter("Title eq 'A&B'").select("Id,Title").top(10).filter(field("Id").gt(2)).url(),
+            format!("{}/items?$select=Id,Title&$filter=(Title%20eq%20'A%26B')%20and%20Id%20gt%202&$top=10", list.url())
+        );
+        assert_eq!(
+            list.items().get_by_id(3).select("Title, Author/Title").expand("Author").url(),
+            format!("{}/items(3)?$select=Title,Author/Title&$expand=Author", list.url())
+        );
+        assert_eq!(list.clone().select("Id").url(), format!("{}?$select=Id", list.url()));
+        assert_eq!(
+            web.lists().get_by_id(Uuid::nil()).fields().url(),
+            "https://company.sharepoint.com/sites/test/_api/web/lists/GetById('00000000-0000-0000-0000-000000000000')/fields"
+        );
+    }
+
+    #[test]
+    fn items_are_read_and_written_through_the_login() {
+        let transport = FakeTransport::default();
+        let login = fake_login(&transport);
+        let mut client = SharePointClient::with_transport(transport.clone()).unwrap();
+        let items = client.web(&login).lists().get_by_title("Tasks").items();
+
+        transport.respond(StatusCode::Ok, r#"{"value":[{"Id":1},{"Id":2}]}"#);
+        let read: Vec<TaskItem> = client.run(items.clone().top(2).get()).unwrap();
+        assert_eq!(read.len(), 2);
+        let get = transport.request(4);
+        assert_eq!(get.method, Method::Get);
+        assert!(get.url.ends_with("/_api/web/lists/GetByTitle('Tasks')/items?$top=2"));
+        assert_eq!(get.header("Cookie"), Some("rtFa=rt-fa-cookie; FedAuth=fed-auth-cookie"));
+
+        transport.respond(StatusCode::Created, r#"{"Id":3}"#);
+        let created: TaskItem = client.run(items.add(json!({"Title": "New"}))).unwrap();
+        assert_eq!(created.id, 3);
+        assert_eq!(transport.request(5).body, r#"{"Title":"New"}"#);
+
+        transport.respond(StatusCode::NoContent, "");
+        client.run(items.get_by_id(3).update(json!({"Title": "Renamed"}))).unwrap();
+        assert_eq!(transport.request(6).header("X-Http-Method"), Some("PATCH"));
+
+        transport.respond(StatusCode::Ok, "");
+        client.run(items.get_by_id(3).delete()).unwrap();
+        let delete = transport.request(7);
+        assert_eq!(delete.method, Method::Post);
+        assert!(delete.url.ends_with("/items(3)"));
+        assert_eq!(delete.header("X-Http-Method"), Some("DELETE"));
+        assert_eq!(delete.header("If-Match"), Some("*"));
+        assert_eq!(delete.header("X-RequestDigest"), Some("0xDIGEST,01 Jan 2018 00:00:00 -0000"));
+    }
+}